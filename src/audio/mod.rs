@@ -7,16 +7,14 @@ use hound::{SampleFormat, WavSpec, WavWriter};
 #[cfg(feature = "mp3")]
 use libc::c_int;
 
-const DEFAULT_PEAK_TARGET: f32 = 0.97;
-#[cfg(feature = "mp3")]
-const DEFAULT_MP3_BITRATE: c_int = 192;
+const PITCH_ANALYSIS_RATE: u32 = 11025;
+const MIN_PITCH_HZ: f32 = 60.0;
+const MAX_PITCH_HZ: f32 = 500.0;
+const VOICING_THRESHOLD: f32 = 0.5;
+const SILENCE_ENERGY: f32 = 1e-6;
 #[cfg(feature = "mp3")]
 const MP3_PADDING: usize = 7200;
 
-pub fn normalize_peak(samples: &mut [f32]) {
-    normalize_peak_to(samples, DEFAULT_PEAK_TARGET);
-}
-
 pub fn normalize_peak_to(samples: &mut [f32], target: f32) {
     if samples.is_empty() {
         return;
@@ -32,6 +30,196 @@ pub fn normalize_peak_to(samples: &mut [f32], target: f32) {
     }
 }
 
+/// Peak to normalise to for `target` raised or lowered by `gain_db`. Callers
+/// keep `gain_db` within [`headroom_db`]; the result is still capped at full
+/// scale against rounding.
+pub fn peak_with_gain_db(target: f32, gain_db: f32) -> f32 {
+    (target * 10f32.powf(gain_db / 20.0)).min(1.0)
+}
+
+/// Gain in dB that raises a peak of `target` to full scale.
+pub fn headroom_db(target: f32) -> f32 {
+    -20.0 * target.log10()
+}
+
+/// Shifts pitch by `semitones` using TD-PSOLA. Grains are taken one pitch period
+/// either side of each analysis mark and re-spaced, so duration and the spectral
+/// envelope (formants) are kept. Unvoiced regions are copied through unchanged.
+pub fn shift_pitch(samples: &[f32], sample_rate: u32, semitones: f32) -> Vec<f32> {
+    if samples.is_empty() || semitones.abs() < 1e-3 {
+        return samples.to_vec();
+    }
+    let marks = pitch_marks(samples, sample_rate);
+    if marks.is_empty() {
+        return samples.to_vec();
+    }
+
+    let factor = 2f32.powf(semitones / 12.0);
+    let mut output = vec![0.0_f32; samples.len()];
+    let mut weights = vec![0.0_f32; samples.len()];
+    let mut mark_idx = 0usize;
+    let mut position = marks[0].position as f32;
+
+    while (position as usize) < samples.len() {
+        let target = position as usize;
+        while mark_idx + 1 < marks.len()
+            && marks[mark_idx + 1].position.abs_diff(target)
+                <= marks[mark_idx].position.abs_diff(target)
+        {
+            mark_idx += 1;
+        }
+        let mark = marks[mark_idx];
+        overlap_add_grain(samples, mark, target, &mut output, &mut weights);
+        let step = if mark.voiced {
+            mark.period as f32 / factor
+        } else {
+            mark.period as f32
+        };
+        position += step.max(1.0);
+    }
+
+    for (sample, &weight) in output.iter_mut().zip(&weights) {
+        if weight > 1e-2 {
+            *sample /= weight;
+        }
+    }
+    output
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PitchMark {
+    position: usize,
+    period: usize,
+    voiced: bool,
+}
+
+fn pitch_marks(samples: &[f32], sample_rate: u32) -> Vec<PitchMark> {
+    let hop = (sample_rate / 100).max(2) as usize;
+    let periods = estimate_periods(samples, sample_rate, hop);
+    let unvoiced_period = hop / 2;
+
+    let mut marks = Vec::new();
+    let mut position = 0usize;
+    while position < samples.len() {
+        let (period, voiced) = match periods.get(position / hop).copied().flatten() {
+            Some(period) => (period, true),
+            None => (unvoiced_period, false),
+        };
+        marks.push(PitchMark {
+            position,
+            period,
+            voiced,
+        });
+        position += period;
+    }
+    marks
+}
+
+fn overlap_add_grain(
+    samples: &[f32],
+    mark: PitchMark,
+    target: usize,
+    output: &mut [f32],
+    weights: &mut [f32],
+) {
+    let half = mark.period;
+    let len = 2 * half;
+    for i in 0..len {
+        let (Some(src), Some(dst)) = (
+            (mark.position + i).checked_sub(half),
+            (target + i).checked_sub(half),
+        ) else {
+            continue;
+        };
+        if src >= samples.len() || dst >= output.len() {
+            break;
+        }
+        let window = 0.5 - 0.5 * (std::f32::consts::TAU * i as f32 / len as f32).cos();
+        output[dst] += samples[src] * window;
+        weights[dst] += window;
+    }
+}
+
+/// Estimates one pitch period (in samples) per `hop`, or `None` where the frame
+/// is silent or unvoiced. The coarse search runs on a decimated copy and is then
+/// refined at full resolution.
+fn estimate_periods(samples: &[f32], sample_rate: u32, hop: usize) -> Vec<Option<usize>> {
+    let decimation = (sample_rate / PITCH_ANALYSIS_RATE).max(1) as usize;
+    let reduced: Vec<f32> = samples
+        .chunks(decimation)
+        .map(|chunk| chunk.iter().sum::<f32>() / chunk.len() as f32)
+        .collect();
+    let reduced_rate = sample_rate as f32 / decimation as f32;
+    let min_lag = ((reduced_rate / MAX_PITCH_HZ) as usize).max(1);
+    let max_lag = (reduced_rate / MIN_PITCH_HZ).ceil() as usize;
+
+    (0..samples.len().div_ceil(hop))
+        .map(|frame| {
+            let coarse = best_lag(
+                &reduced,
+                frame * hop / decimation,
+                max_lag,
+                min_lag,
+                max_lag,
+            )?;
+            let centre = coarse * decimation;
+            let window = max_lag * decimation;
+            let start = frame * hop;
+            let refined = (centre.saturating_sub(decimation).max(1)..=centre + decimation)
+                .filter(|lag| start + window + lag <= samples.len())
+                .max_by(|&a, &b| {
+                    correlation(samples, start, window, a)
+                        .total_cmp(&correlation(samples, start, window, b))
+                });
+            Some(refined.unwrap_or(centre))
+        })
+        .collect()
+}
+
+fn best_lag(
+    signal: &[f32],
+    start: usize,
+    window: usize,
+    min_lag: usize,
+    max_lag: usize,
+) -> Option<usize> {
+    if start + window + max_lag > signal.len() {
+        return None;
+    }
+    let energy: f32 = signal[start..start + window].iter().map(|v| v * v).sum();
+    if energy / (window as f32) < SILENCE_ENERGY {
+        return None;
+    }
+
+    let scores: Vec<f32> = (min_lag..=max_lag)
+        .map(|lag| correlation(signal, start, window, lag))
+        .collect();
+    let peak = scores.iter().copied().fold(f32::MIN, f32::max);
+    if peak < VOICING_THRESHOLD {
+        return None;
+    }
+    // Prefer the shortest lag close to the global peak to avoid octave errors.
+    (1..scores.len().saturating_sub(1))
+        .find(|&idx| {
+            scores[idx] >= 0.9 * peak
+                && scores[idx] >= scores[idx - 1]
+                && scores[idx] >= scores[idx + 1]
+        })
+        .map(|idx| idx + min_lag)
+}
+
+fn correlation(signal: &[f32], start: usize, window: usize, lag: usize) -> f32 {
+    let frame = &signal[start..start + window];
+    let shifted = &signal[start + lag..start + lag + window];
+    let (dot, frame_energy, shifted_energy) = frame
+        .iter()
+        .zip(shifted)
+        .fold((0.0_f32, 0.0_f32, 0.0_f32), |(dot, fe, se), (a, b)| {
+            (dot + a * b, fe + a * a, se + b * b)
+        });
+    dot / (frame_energy * shifted_energy).sqrt().max(f32::EPSILON)
+}
+
 pub fn pcm_to_wav(samples: &[f32], sample_rate: u32) -> Result<Vec<u8>> {
     let payload_bytes = samples.len().saturating_mul(2);
     let mut cursor = Cursor::new(Vec::with_capacity(payload_bytes.saturating_add(128)));
@@ -56,13 +244,13 @@ pub fn pcm_to_wav(samples: &[f32], sample_rate: u32) -> Result<Vec<u8>> {
 }
 
 #[cfg(feature = "mp3")]
-pub fn pcm_to_mp3(samples: &[f32], sample_rate: u32) -> Result<Vec<u8>> {
-    let mut encoder = LameEncoder::new(sample_rate, 1)?;
+pub fn pcm_to_mp3(samples: &[f32], sample_rate: u32, bitrate_kbps: u32) -> Result<Vec<u8>> {
+    let mut encoder = LameEncoder::new(sample_rate, 1, bitrate_kbps)?;
     encoder.encode(samples)
 }
 
 #[cfg(not(feature = "mp3"))]
-pub fn pcm_to_mp3(_samples: &[f32], _sample_rate: u32) -> Result<Vec<u8>> {
+pub fn pcm_to_mp3(_samples: &[f32], _sample_rate: u32, _bitrate_kbps: u32) -> Result<Vec<u8>> {
     bail!("MP3 output is disabled (rebuild with `--features mp3` and install libmp3lame)");
 }
 
@@ -73,23 +261,26 @@ struct LameEncoder {
 
 #[cfg(feature = "mp3")]
 impl LameEncoder {
-    fn new(sample_rate: u32, channels: c_int) -> Result<Self> {
+    fn new(sample_rate: u32, channels: c_int, bitrate_kbps: u32) -> Result<Self> {
         unsafe {
             let handle = lame_init();
             if handle.is_null() {
                 bail!("failed to initialise libmp3lame encoder");
             }
             let mut encoder = Self { inner: handle };
-            encoder.configure(sample_rate, channels)?;
+            encoder.configure(sample_rate, channels, bitrate_kbps)?;
             Ok(encoder)
         }
     }
 
-    fn configure(&mut self, sample_rate: u32, channels: c_int) -> Result<()> {
+    fn configure(&mut self, sample_rate: u32, channels: c_int, bitrate_kbps: u32) -> Result<()> {
         unsafe {
             let sr = sample_rate
                 .try_into()
                 .map_err(|_| anyhow!("sample rate {sample_rate} too large"))?;
+            let brate = bitrate_kbps
+                .try_into()
+                .map_err(|_| anyhow!("MP3 bitrate {bitrate_kbps} too large"))?;
             ensure_success(
                 lame_set_in_samplerate(self.inner, sr),
                 "lame_set_in_samplerate",
@@ -102,10 +293,7 @@ impl LameEncoder {
                 lame_set_num_channels(self.inner, channels),
                 "lame_set_num_channels",
             )?;
-            ensure_success(
-                lame_set_brate(self.inner, DEFAULT_MP3_BITRATE),
-                "lame_set_brate",
-            )?;
+            ensure_success(lame_set_brate(self.inner, brate), "lame_set_brate")?;
             ensure_success(lame_set_quality(self.inner, 2), "lame_set_quality")?;
             ensure_success(lame_init_params(self.inner), "lame_init_params")
         }
@@ -220,11 +408,51 @@ mod tests {
         assert_eq!(reader.spec().sample_rate, 22050);
     }
 
+    #[test]
+    fn gain_is_folded_into_the_peak_target() {
+        assert!((peak_with_gain_db(0.5, -6.0206) - 0.25).abs() < 1e-4);
+        assert_eq!(peak_with_gain_db(0.5, 0.0), 0.5);
+        assert!((headroom_db(0.5) - 6.0206).abs() < 1e-4);
+        assert_eq!(headroom_db(1.0), 0.0);
+
+        let mut samples = vec![0.2_f32, -0.4_f32];
+        normalize_peak_to(&mut samples, peak_with_gain_db(0.9, headroom_db(0.9)));
+        assert!((samples[0] - 0.5).abs() < 1e-6);
+        assert!((samples[1] + 1.0).abs() < 1e-6);
+    }
+
+    fn pulse_train(sample_rate: u32, period: usize, seconds: f32) -> Vec<f32> {
+        let len = (sample_rate as f32 * seconds) as usize;
+        (0..len)
+            .map(|i| {
+                let t = (i % period) as f32 / sample_rate as f32;
+                (-t * 400.0).exp() * (std::f32::consts::TAU * 900.0 * t).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn shift_pitch_keeps_duration() {
+        let samples = pulse_train(24000, 160, 0.5);
+        let shifted = shift_pitch(&samples, 24000, 4.0);
+        assert_eq!(shifted.len(), samples.len());
+        assert_eq!(shift_pitch(&samples, 24000, 0.0), samples);
+    }
+
+    #[test]
+    fn shift_pitch_raises_period_by_an_octave() {
+        let samples = pulse_train(24000, 160, 0.5);
+        let shifted = shift_pitch(&samples, 24000, 12.0);
+        let periods = estimate_periods(&shifted, 24000, 240);
+        let middle = periods[periods.len() / 2].expect("voiced frame");
+        assert!((75..=85).contains(&middle), "unexpected period {middle}");
+    }
+
     #[cfg(feature = "mp3")]
     #[test]
     fn pcm_to_mp3_produces_bytes() {
         let samples = vec![0.0_f32; 22050];
-        let mp3 = pcm_to_mp3(&samples, 22050).expect("mp3 encoding");
+        let mp3 = pcm_to_mp3(&samples, 22050, 192).expect("mp3 encoding");
         let frame_sync = mp3
             .get(0)
             .copied()
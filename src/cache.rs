@@ -0,0 +1,535 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+const DISK_EXTENSION: &str = "json";
+
+/// Content-addressed store for encoded synthesis responses. Entries live in an
+/// LRU bounded by count and total bytes, and are optionally mirrored to disk
+/// under the same bounds. Disk reads and writes run on the blocking pool.
+pub struct SynthesisCache {
+    memory: Mutex<Lru<Arc<Vec<u8>>>>,
+    disk: Option<DiskMirror>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl SynthesisCache {
+    pub fn new(max_entries: usize, max_bytes: usize, disk_dir: Option<PathBuf>) -> Result<Self> {
+        let disk = disk_dir
+            .map(|dir| DiskMirror::open(dir, max_entries, max_bytes))
+            .transpose()?;
+        Ok(Self {
+            memory: Mutex::new(Lru::new(max_entries, max_bytes)),
+            disk,
+        })
+    }
+
+    /// Hashes the canonical JSON form of `parts` into a hex SHA-256 key.
+    pub fn key(parts: &impl Serialize) -> Result<String> {
+        let canonical = serde_json::to_vec(parts).context("failed to serialise cache key")?;
+        Ok(format!("{:x}", Sha256::digest(&canonical)))
+    }
+
+    pub async fn get(self: &Arc<Self>, key: &str) -> Option<Arc<Vec<u8>>> {
+        if let Some(value) = self.lock().get(key) {
+            return Some(value.clone());
+        }
+        self.disk.as_ref()?;
+        let this = self.clone();
+        let key = key.to_string();
+        tokio::task::spawn_blocking(move || {
+            let bytes = this.disk.as_ref()?.read(&key)?;
+            let value = Arc::new(bytes);
+            this.lock().insert(key, value.clone(), value.len());
+            Some(value)
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    /// Stores the entry in memory and, in the background, on disk.
+    pub fn insert(self: &Arc<Self>, key: String, value: Vec<u8>) {
+        // Read before the memory insert, so a purge in between also drops
+        // the disk write.
+        let generation = self.disk.as_ref().map(DiskMirror::generation);
+        let value = Arc::new(value);
+        self.lock().insert(key.clone(), value.clone(), value.len());
+        if let Some(generation) = generation {
+            let this = self.clone();
+            tokio::task::spawn_blocking(move || {
+                if let Some(disk) = &this.disk {
+                    disk.write(&key, &value, generation);
+                }
+            });
+        }
+    }
+
+    /// Drops every entry from memory and disk, returning how many distinct
+    /// entries were removed.
+    pub fn purge(&self) -> usize {
+        let mut removed: HashSet<String> = {
+            let mut memory = self.lock();
+            let keys = memory.keys().map(str::to_string).collect();
+            memory.clear();
+            keys
+        };
+        if let Some(disk) = &self.disk {
+            disk.purge(&mut removed);
+        }
+        removed.len()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let memory = self.lock();
+        CacheStats {
+            entries: memory.len(),
+            bytes: memory.weight(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lru<Arc<Vec<u8>>>> {
+        self.memory.lock().expect("synthesis cache mutex poisoned")
+    }
+}
+
+/// The on-disk copy of the cache. Files are tracked in recency order and the
+/// oldest are deleted once the mirror exceeds the memory cache's bounds.
+struct DiskMirror {
+    dir: PathBuf,
+    /// File sizes by key; bounded by [`Self::trim`] rather than by the LRU,
+    /// which would not say what it evicted.
+    index: Mutex<Lru<()>>,
+    /// Bumped by [`Self::purge`]; writes queued before it are dropped.
+    generation: AtomicU64,
+    max_entries: usize,
+    max_bytes: usize,
+}
+
+impl DiskMirror {
+    /// Indexes the files left by earlier runs, oldest first, and trims them
+    /// to the bounds.
+    fn open(dir: PathBuf, max_entries: usize, max_bytes: usize) -> Result<Self> {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create cache dir {}", dir.display()))?;
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&dir)
+            .with_context(|| format!("failed to read cache dir {}", dir.display()))?
+            .flatten()
+        {
+            let path = entry.path();
+            let Some(key) = disk_key(&path) else {
+                continue;
+            };
+            if let Ok(meta) = entry.metadata() {
+                let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                files.push((modified, key.to_string(), meta.len() as usize));
+            }
+        }
+        files.sort();
+        let mut index = Lru::new(usize::MAX, usize::MAX);
+        for (_, key, len) in files {
+            index.insert(key, (), len);
+        }
+        let disk = Self {
+            dir,
+            index: Mutex::new(index),
+            generation: AtomicU64::new(0),
+            max_entries,
+            max_bytes,
+        };
+        disk.trim();
+        Ok(disk)
+    }
+
+    fn read(&self, key: &str) -> Option<Vec<u8>> {
+        let bytes = fs::read(self.path(key)).ok()?;
+        self.lock().insert(key.to_string(), (), bytes.len());
+        Some(bytes)
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Writes the file through a temporary one, unless the mirror has been
+    /// purged since `generation` was read.
+    fn write(&self, key: &str, bytes: &[u8], generation: u64) {
+        if bytes.len() > self.max_bytes {
+            return;
+        }
+        let path = self.path(key);
+        let tmp = temp_path(&path);
+        let written = fs::write(&tmp, bytes).and_then(|()| {
+            // Renaming under the index lock orders the write against purge:
+            // the file either lands before purge deletes everything, or is
+            // dropped here.
+            let mut index = self.lock();
+            if self.generation() != generation {
+                return Ok(false);
+            }
+            fs::rename(&tmp, &path)?;
+            index.insert(key.to_string(), (), bytes.len());
+            Ok(true)
+        });
+        match written {
+            Ok(true) => self.trim(),
+            Ok(false) => {
+                let _ = fs::remove_file(&tmp);
+            }
+            Err(err) => {
+                warn!("failed to persist cache entry {key}: {err}");
+                let _ = fs::remove_file(&tmp);
+            }
+        }
+    }
+
+    /// Deletes the oldest files until the mirror is within its bounds.
+    fn trim(&self) {
+        let evicted = {
+            let mut index = self.lock();
+            let mut evicted = Vec::new();
+            while index.len() > self.max_entries || index.weight() > self.max_bytes {
+                let Some((key, ())) = index.pop_oldest() else {
+                    break;
+                };
+                evicted.push(key);
+            }
+            evicted
+        };
+        for key in evicted {
+            let path = self.path(&key);
+            if let Err(err) = fs::remove_file(&path)
+                && err.kind() != io::ErrorKind::NotFound
+            {
+                warn!("failed to evict cache file {}: {err}", path.display());
+            }
+        }
+    }
+
+    /// Deletes every cache file, adding their keys to `removed`. Writes
+    /// still pending are dropped.
+    fn purge(&self, removed: &mut HashSet<String>) {
+        let mut index = self.lock();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        index.clear();
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if let Some(key) = disk_key(&path)
+                && fs::remove_file(&path).is_ok()
+            {
+                removed.insert(key.to_string());
+            }
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.{DISK_EXTENSION}"))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lru<()>> {
+        self.index.lock().expect("synthesis cache mutex poisoned")
+    }
+}
+
+/// The key of a cache file, or `None` for anything else in the directory.
+fn disk_key(path: &Path) -> Option<&str> {
+    if path.extension()? != DISK_EXTENSION {
+        return None;
+    }
+    path.file_stem()?.to_str()
+}
+
+/// A temporary file next to `path`, unique across concurrent writes and
+/// processes sharing the directory.
+fn temp_path(path: &Path) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    path.with_extension(format!("{}.{n}.tmp", process::id()))
+}
+
+/// Least-recently-used map bounded by entry count and by the total weight
+/// callers assign to entries. Entries live in a slab linked from the newest
+/// to the oldest, so lookups, inserts and evictions are O(1). A map with
+/// room for no entries stores nothing.
+pub struct Lru<V> {
+    index: HashMap<String, usize>,
+    slots: Vec<Option<Slot<V>>>,
+    free: Vec<usize>,
+    newest: Option<usize>,
+    oldest: Option<usize>,
+    weight: usize,
+    max_entries: usize,
+    max_weight: usize,
+}
+
+struct Slot<V> {
+    key: String,
+    value: V,
+    weight: usize,
+    newer: Option<usize>,
+    older: Option<usize>,
+}
+
+impl<V> Lru<V> {
+    pub fn new(max_entries: usize, max_weight: usize) -> Self {
+        Self {
+            index: HashMap::new(),
+            slots: Vec::new(),
+            free: Vec::new(),
+            newest: None,
+            oldest: None,
+            weight: 0,
+            max_entries,
+            max_weight,
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<&V> {
+        let slot = *self.index.get(key)?;
+        self.unlink(slot);
+        self.link_newest(slot);
+        Some(&self.slot(slot).value)
+    }
+
+    /// Inserts or replaces `key`, evicting the oldest entries as needed.
+    /// Returns `false`, leaving the map unchanged, when `weight` alone
+    /// exceeds the weight budget or the map holds no entries.
+    pub fn insert(&mut self, key: String, value: V, weight: usize) -> bool {
+        if weight > self.max_weight || self.max_entries == 0 {
+            return false;
+        }
+        if let Some(&slot) = self.index.get(&key) {
+            self.remove_slot(slot);
+        }
+        let entry = Slot {
+            key: key.clone(),
+            value,
+            weight,
+            newer: None,
+            older: None,
+        };
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.slots[slot] = Some(entry);
+                slot
+            }
+            None => {
+                self.slots.push(Some(entry));
+                self.slots.len() - 1
+            }
+        };
+        self.index.insert(key, slot);
+        self.link_newest(slot);
+        self.weight += weight;
+        while self.index.len() > self.max_entries || self.weight > self.max_weight {
+            let Some(oldest) = self.oldest else {
+                break;
+            };
+            self.remove_slot(oldest);
+        }
+        true
+    }
+
+    /// Removes and returns the least recently used entry.
+    pub fn pop_oldest(&mut self) -> Option<(String, V)> {
+        let slot = self.oldest?;
+        let entry = self.remove_slot(slot);
+        Some((entry.key, entry.value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.index.keys().map(String::as_str)
+    }
+
+    /// Drops every entry, returning how many there were.
+    pub fn clear(&mut self) -> usize {
+        let removed = self.index.len();
+        self.index.clear();
+        self.slots.clear();
+        self.free.clear();
+        self.newest = None;
+        self.oldest = None;
+        self.weight = 0;
+        removed
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.max_entries
+    }
+
+    fn remove_slot(&mut self, slot: usize) -> Slot<V> {
+        self.unlink(slot);
+        let entry = self.slots[slot].take().expect("LRU slot is empty");
+        self.index.remove(&entry.key);
+        self.weight -= entry.weight;
+        self.free.push(slot);
+        entry
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let (newer, older) = {
+            let entry = self.slot(slot);
+            (entry.newer, entry.older)
+        };
+        match newer {
+            Some(newer) => self.slot_mut(newer).older = older,
+            None => self.newest = older,
+        }
+        match older {
+            Some(older) => self.slot_mut(older).newer = newer,
+            None => self.oldest = newer,
+        }
+    }
+
+    fn link_newest(&mut self, slot: usize) {
+        let previous = self.newest.replace(slot);
+        {
+            let entry = self.slot_mut(slot);
+            entry.newer = None;
+            entry.older = previous;
+        }
+        match previous {
+            Some(previous) => self.slot_mut(previous).newer = Some(slot),
+            None => self.oldest = Some(slot),
+        }
+    }
+
+    fn slot(&self, slot: usize) -> &Slot<V> {
+        self.slots[slot].as_ref().expect("LRU slot is empty")
+    }
+
+    fn slot_mut(&mut self, slot: usize) -> &mut Slot<V> {
+        self.slots[slot].as_mut().expect("LRU slot is empty")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[tokio::test]
+    async fn synthesis_cache_evicts_by_entries_and_bytes() {
+        let cache = Arc::new(SynthesisCache::new(3, 10, None).unwrap());
+        cache.insert("a".into(), vec![0; 4]);
+        cache.insert("b".into(), vec![0; 4]);
+        cache.get("a").await;
+        cache.insert("c".into(), vec![0; 4]);
+
+        assert!(cache.get("a").await.is_some());
+        assert!(cache.get("b").await.is_none());
+        assert!(cache.get("c").await.is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes), (2, 8));
+        assert_eq!(cache.purge(), 2);
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[test]
+    fn disk_mirror_is_bounded_and_purged_once_per_key() {
+        let dir = TempDir::new("cache");
+        let disk = DiskMirror::open(dir.to_path_buf(), 2, 10).unwrap();
+        disk.write("a", &[0; 4], 0);
+        disk.write("b", &[0; 4], 0);
+        disk.read("a");
+        disk.write("c", &[0; 4], 0);
+        assert!(disk.read("b").is_none(), "oldest file deleted");
+        assert!(disk.path("a").exists() && disk.path("c").exists());
+
+        // Reopening indexes the surviving files; a smaller bound trims them.
+        let disk = DiskMirror::open(dir.to_path_buf(), 1, 10).unwrap();
+        assert_eq!(disk.lock().len(), 1);
+
+        let cache = SynthesisCache {
+            memory: Mutex::new(Lru::new(2, 10)),
+            disk: Some(disk),
+        };
+        cache.lock().insert("c".into(), Arc::new(vec![0; 4]), 4);
+        cache.lock().insert("d".into(), Arc::new(vec![0; 4]), 4);
+        // "c" is in memory and on disk but counts once.
+        assert_eq!(cache.purge(), 2);
+    }
+
+    #[test]
+    fn writes_queued_before_a_purge_are_dropped() {
+        let dir = TempDir::new("cache-purge");
+        let disk = DiskMirror::open(dir.to_path_buf(), 2, 10).unwrap();
+        let generation = disk.generation();
+        disk.purge(&mut HashSet::new());
+        disk.write("late", &[0; 4], generation);
+        assert!(!disk.path("late").exists());
+        assert_eq!(disk.lock().len(), 0);
+        assert_eq!(fs::read_dir(&*dir).unwrap().count(), 0, "temp file left");
+
+        disk.write("fresh", &[0; 4], disk.generation());
+        assert!(disk.path("fresh").exists());
+        assert_ne!(temp_path(&disk.path("a")), temp_path(&disk.path("a")));
+    }
+
+    #[test]
+    fn generic_lru_keeps_recency_order_in_constant_time() {
+        let mut lru = Lru::new(3, 10);
+        for key in ["a", "b", "c"] {
+            assert!(lru.insert(key.into(), key, 3));
+        }
+        assert_eq!(lru.get("a"), Some(&"a"));
+        lru.insert("d".into(), "d", 1);
+        assert!(lru.get("b").is_none(), "least recently used entry evicted");
+
+        // Weight 3 + 3 + 1 + 4 > 10 evicts the oldest, "c".
+        lru.insert("e".into(), "e", 4);
+        assert!(lru.get("c").is_none());
+        assert_eq!((lru.len(), lru.weight()), (3, 8));
+
+        // Replacing keeps one entry and re-weighs it; slots are reused.
+        lru.insert("a".into(), "A", 1);
+        assert_eq!(lru.get("a"), Some(&"A"));
+        assert_eq!((lru.len(), lru.weight()), (3, 6));
+        assert_eq!(lru.slots.len(), 4);
+
+        assert!(!lru.insert("huge".into(), "huge", 11));
+        assert_eq!(lru.len(), 3);
+
+        let mut disabled = Lru::new(0, 10);
+        assert!(!disabled.insert("a".into(), "a", 1));
+        assert!(disabled.get("a").is_none());
+    }
+
+    #[test]
+    fn key_depends_on_every_field() {
+        let first = SynthesisCache::key(&("text", 0.6_f32)).unwrap();
+        let second = SynthesisCache::key(&("text", 0.7_f32)).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(first.len(), 64);
+    }
+}
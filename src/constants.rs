@@ -47,3 +47,5 @@ pub const DEFAULT_NOISE: f32 = 0.6;
 pub const DEFAULT_NOISEW: f32 = 0.8;
 pub const DEFAULT_LENGTH: f32 = 1.0;
 pub const DEFAULT_ASSIST_TEXT_WEIGHT: f32 = 1.0;
+/// Longest input, in characters, accepted for a single synthesis.
+pub const MAX_INPUT_CHARS: usize = 2000;
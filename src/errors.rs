@@ -12,8 +12,100 @@ pub enum TtsError {
     Serde(#[from] serde_json::Error),
     #[error("unsupported language")]
     UnsupportedLanguage,
+    #[error("{message}")]
+    InvalidParameter {
+        param: &'static str,
+        message: String,
+    },
+    #[error("{0}")]
+    InvalidInput(String),
+    #[error("speaker '{0}' is not available")]
+    UnknownSpeaker(String),
+    #[error("style '{0}' is not available")]
+    UnknownStyle(String),
+    #[error("model '{name}' not found; available models: {available}")]
+    UnknownModel { name: String, available: String },
+    #[error("input has {len} characters, more than the limit of {max}")]
+    InputTooLong { len: usize, max: usize },
+    #[error("{0}")]
+    Overloaded(String),
+    #[error("model '{0}' is already being reloaded")]
+    ReloadInProgress(String),
     #[error("{0}")]
     Other(String),
 }
 
 pub type Result<T> = std::result::Result<T, TtsError>;
+
+impl TtsError {
+    pub fn invalid(param: &'static str, message: impl Into<String>) -> Self {
+        Self::InvalidParameter {
+            param,
+            message: message.into(),
+        }
+    }
+
+    /// HTTP status code the error should be reported with.
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::InvalidParameter { .. } | Self::InvalidInput(_) | Self::UnsupportedLanguage => {
+                400
+            }
+            Self::UnknownSpeaker(_) | Self::UnknownStyle(_) | Self::UnknownModel { .. } => 404,
+            Self::InputTooLong { .. } => 413,
+            Self::ReloadInProgress(_) => 409,
+            Self::Overloaded(_) => 503,
+            Self::Config(_) | Self::Io(_) | Self::Ort(_) | Self::Serde(_) | Self::Other(_) => 500,
+        }
+    }
+
+    /// Request parameter the error refers to, if any.
+    pub fn param(&self) -> Option<&'static str> {
+        match self {
+            Self::InvalidParameter { param, .. } => Some(param),
+            Self::InvalidInput(_) | Self::InputTooLong { .. } => Some("input"),
+            Self::UnknownSpeaker(_) => Some("voice"),
+            Self::UnknownStyle(_) => Some("style"),
+            Self::UnknownModel { .. } => Some("model"),
+            _ => None,
+        }
+    }
+
+    /// Stable machine-readable error code.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Config(_) => "config_error",
+            Self::Io(_) => "io_error",
+            Self::Ort(_) => "inference_error",
+            Self::Serde(_) => "serialization_error",
+            Self::UnsupportedLanguage => "unsupported_language",
+            Self::InvalidParameter { .. } => "invalid_parameter",
+            Self::InvalidInput(_) => "invalid_input",
+            Self::UnknownSpeaker(_) => "voice_not_found",
+            Self::UnknownStyle(_) => "style_not_found",
+            Self::UnknownModel { .. } => "model_not_found",
+            Self::InputTooLong { .. } => "input_too_long",
+            Self::Overloaded(_) => "overloaded",
+            Self::ReloadInProgress(_) => "reload_in_progress",
+            Self::Other(_) => "internal_error",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_errors_survive_anyhow_context() {
+        let err = anyhow::Error::from(TtsError::UnknownSpeaker("nobody".into()))
+            .context("failed to run TTS inference");
+        let typed = err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<TtsError>())
+            .unwrap();
+        assert_eq!(typed.status(), 404);
+        assert_eq!(typed.param(), Some("voice"));
+        assert_eq!(typed.code(), "voice_not_found");
+    }
+}
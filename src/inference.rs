@@ -1,17 +1,45 @@
-use std::{sync::Arc, time::Instant};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::Instant,
+};
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result};
 use base64::Engine;
 use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
 
 use crate::{
     audio,
+    constants::MAX_INPUT_CHARS,
+    errors::TtsError,
     model::{InferenceRequest, TtsProject},
+    settings::Preset,
+    timestamps::Alignment,
 };
 
+const MAX_PITCH_SEMITONES: f32 = 12.0;
+const MIN_VOLUME_DB: f32 = -40.0;
+const MAX_VOLUME_DB: f32 = 12.0;
+
+/// Cheap to clone handle to the active project. The project can be swapped at
+/// runtime; requests hold their own `Arc` so the previous project stays alive
+/// until they finish.
 #[derive(Clone)]
 pub struct ChineseSynthesizer {
-    project: Arc<TtsProject>,
+    project: Arc<RwLock<Arc<TtsProject>>>,
+    defaults: SynthesisDefaults,
+}
+
+/// Values used for the parameters a request leaves unset.
+#[derive(Debug, Clone, Copy)]
+pub struct SynthesisDefaults {
+    pub style_weight: f32,
+    pub sdp_ratio: f32,
+    pub noise: f32,
+    pub noise_w: f32,
+    pub length_scale: f32,
+    pub assist_weight: f32,
+    pub peak_target: f32,
 }
 
 pub struct ChineseSynthesisInput {
@@ -19,12 +47,21 @@ pub struct ChineseSynthesisInput {
     pub speaker: Option<String>,
     pub style: Option<String>,
     pub style_weight: Option<f32>,
+    pub style_mix: Option<BTreeMap<String, f32>>,
+    pub style_vector: Option<Vec<f32>>,
     pub sdp_ratio: Option<f32>,
     pub noise: Option<f32>,
     pub noise_w: Option<f32>,
     pub length_scale: Option<f32>,
     pub assist_text: Option<String>,
+    /// Name of a configured assist prompt, used as the assist text.
+    pub assist_prompt: Option<String>,
     pub assist_weight: Option<f32>,
+    pub pitch: Option<f32>,
+    pub volume: Option<f32>,
+    pub seed: Option<u64>,
+    /// Supplies the parameters the request leaves unset.
+    pub preset: Option<Arc<Preset>>,
 }
 
 impl ChineseSynthesisInput {
@@ -34,14 +71,75 @@ impl ChineseSynthesisInput {
             speaker: None,
             style: None,
             style_weight: None,
+            style_mix: None,
+            style_vector: None,
             sdp_ratio: None,
             noise: None,
             noise_w: None,
             length_scale: None,
             assist_text: None,
+            assist_prompt: None,
             assist_weight: None,
+            pitch: None,
+            volume: None,
+            seed: None,
+            preset: None,
+        }
+    }
+
+    // The parameters a preset can supply, as the request sets them or else as
+    // the preset does.
+
+    pub fn speaker(&self) -> Option<&str> {
+        self.speaker
+            .as_deref()
+            .or_else(|| self.preset.as_deref()?.voice.as_deref())
+    }
+
+    pub fn style(&self) -> Option<&str> {
+        self.style
+            .as_deref()
+            .or_else(|| self.preset.as_deref()?.style.as_deref())
+    }
+
+    pub fn style_weight(&self) -> Option<f32> {
+        self.style_weight
+            .or_else(|| self.preset.as_deref()?.style_weight)
+    }
+
+    pub fn sdp_ratio(&self) -> Option<f32> {
+        self.sdp_ratio.or_else(|| self.preset.as_deref()?.sdp_ratio)
+    }
+
+    pub fn noise(&self) -> Option<f32> {
+        self.noise.or_else(|| self.preset.as_deref()?.noise)
+    }
+
+    pub fn noise_w(&self) -> Option<f32> {
+        self.noise_w.or_else(|| self.preset.as_deref()?.noise_w)
+    }
+
+    pub fn length_scale(&self) -> Option<f32> {
+        self.length_scale
+            .or_else(|| Some(1.0 / self.preset.as_deref()?.speed?))
+    }
+
+    /// Assist text and prompt name. The preset's pair only applies when the
+    /// request sets neither.
+    pub fn assist(&self) -> (Option<&str>, Option<&str>) {
+        match (&self.preset, &self.assist_text, &self.assist_prompt) {
+            (Some(preset), None, None) => (
+                preset.assist_text.as_deref(),
+                preset.assist_prompt.as_deref(),
+            ),
+            (_, text, prompt) => (text.as_deref(), prompt.as_deref()),
         }
     }
+
+    pub fn assist_weight(&self) -> Option<f32> {
+        self.assist_weight
+            .or_else(|| self.preset.as_deref()?.assist_weight)
+    }
 }
 
 pub struct SynthesisTimings {
@@ -52,6 +150,8 @@ pub struct SynthesisResult {
     pub pcm: Vec<f32>,
     pub sample_rate: u32,
     pub wav: Vec<u8>,
+    pub alignment: Alignment,
+    pub seed: Option<u64>,
     pub timings: SynthesisTimings,
 }
 
@@ -62,28 +162,62 @@ impl SynthesisResult {
 }
 
 impl ChineseSynthesizer {
-    pub fn new(project: Arc<TtsProject>) -> Self {
-        Self { project }
+    pub fn new(project: Arc<TtsProject>, defaults: SynthesisDefaults) -> Self {
+        Self {
+            project: Arc::new(RwLock::new(project)),
+            defaults,
+        }
+    }
+
+    pub fn defaults(&self) -> &SynthesisDefaults {
+        &self.defaults
     }
 
-    pub fn project(&self) -> &Arc<TtsProject> {
-        &self.project
+    pub fn project(&self) -> Arc<TtsProject> {
+        self.project
+            .read()
+            .expect("synthesizer project lock poisoned")
+            .clone()
+    }
+
+    /// Installs `project` for subsequent requests and returns the previous one.
+    pub fn swap(&self, project: Arc<TtsProject>) -> Arc<TtsProject> {
+        let mut current = self
+            .project
+            .write()
+            .expect("synthesizer project lock poisoned");
+        std::mem::replace(&mut *current, project)
     }
 
     pub fn synthesize(&self, input: &ChineseSynthesisInput) -> Result<SynthesisResult> {
         if input.text.trim().is_empty() {
-            bail!("text input must not be empty");
+            return Err(TtsError::InvalidInput("text input must not be empty".into()).into());
+        }
+        let len = input.text.chars().count();
+        if len > MAX_INPUT_CHARS {
+            return Err(TtsError::InputTooLong {
+                len,
+                max: MAX_INPUT_CHARS,
+            }
+            .into());
         }
 
-        let request = self.build_request(input)?;
+        let project = self.project();
+        let request = build_request(&project, &self.defaults, input)?;
         let start = Instant::now();
-        let mut result = self
-            .project
+        let mut result = project
             .infer_chinese(request)
             .context("failed to run TTS inference")?;
         let inference_elapsed = start.elapsed();
 
-        audio::normalize_peak(&mut result.audio);
+        if let Some(semitones) = input.pitch {
+            result.audio = audio::shift_pitch(&result.audio, result.sample_rate, semitones);
+        }
+        let peak = match input.volume {
+            Some(gain_db) => audio::peak_with_gain_db(self.defaults.peak_target, gain_db),
+            None => self.defaults.peak_target,
+        };
+        audio::normalize_peak_to(&mut result.audio, peak);
         let wav = audio::pcm_to_wav(&result.audio, result.sample_rate)
             .context("failed to encode WAV output")?;
 
@@ -91,69 +225,206 @@ impl ChineseSynthesizer {
             pcm: result.audio,
             sample_rate: result.sample_rate,
             wav,
+            alignment: result.alignment,
+            seed: result.seed,
             timings: SynthesisTimings {
                 total_ms: inference_elapsed.as_millis(),
             },
         })
     }
+}
 
-    fn build_request<'a>(
-        &'a self,
-        input: &'a ChineseSynthesisInput,
-    ) -> Result<InferenceRequest<'a>> {
-        let mut request = InferenceRequest::new(&input.text);
+fn build_request<'a>(
+    project: &'a TtsProject,
+    defaults: &SynthesisDefaults,
+    input: &'a ChineseSynthesisInput,
+) -> Result<InferenceRequest<'a>> {
+    let mut request = InferenceRequest::new(&input.text);
+    request.style_weight = defaults.style_weight;
+    request.sdp_ratio = defaults.sdp_ratio;
+    request.noise = defaults.noise;
+    request.noise_w = defaults.noise_w;
+    request.length_scale = defaults.length_scale;
+    request.assist_weight = defaults.assist_weight;
 
-        if let Some(ref speaker) = input.speaker {
-            if self.project.speaker_id(speaker).is_none() {
-                bail!("speaker '{}' is not available", speaker);
-            }
-            request.speaker = Some(speaker.as_str());
+    if let Some(speaker) = input.speaker() {
+        if project.speaker_id(speaker).is_none() {
+            return Err(TtsError::UnknownSpeaker(speaker.to_string()).into());
         }
+        request.speaker = Some(speaker);
+    }
 
-        if let Some(ref style) = input.style {
-            if self.project.style_id(style).is_none() {
-                bail!("style '{}' is not available", style);
-            }
-            request.style = Some(style.as_str());
+    if let Some(style) = input.style() {
+        if project.style_id(style).is_none() {
+            return Err(TtsError::UnknownStyle(style.to_string()).into());
         }
+        request.style = Some(style);
+    }
 
-        if let Some(weight) = input.style_weight {
-            if !(0.0..=1.0).contains(&weight) {
-                bail!("style_weight must be within [0.0, 1.0]");
-            }
-            request.style_weight = weight;
+    if let Some(weight) = input.style_weight() {
+        if !(0.0..=1.0).contains(&weight) {
+            return Err(TtsError::invalid(
+                "style_weight",
+                "style_weight must be within [0.0, 1.0]",
+            )
+            .into());
         }
+        request.style_weight = weight;
+    }
 
-        if let Some(sdp_ratio) = input.sdp_ratio {
-            request.sdp_ratio = sdp_ratio.clamp(0.0, 1.0);
+    if let Some(ref mix) = input.style_mix {
+        if mix.is_empty() {
+            return Err(
+                TtsError::invalid("style_mix", "style_mix must name at least one style").into(),
+            );
         }
-
-        if let Some(noise) = input.noise {
-            request.noise = noise.max(0.0);
+        for (name, weight) in mix {
+            if project.style_id(name).is_none() {
+                return Err(TtsError::UnknownStyle(name.clone()).into());
+            }
+            if !(0.0..=1.0).contains(weight) {
+                return Err(TtsError::invalid(
+                    "style_mix",
+                    format!("style_mix weight for '{name}' must be within [0.0, 1.0]"),
+                )
+                .into());
+            }
         }
+        request.style_mix = Some(mix);
+    }
 
-        if let Some(noise_w) = input.noise_w {
-            request.noise_w = noise_w.max(0.0);
+    if let Some(ref vector) = input.style_vector {
+        let expected = project.style_dim();
+        if vector.len() != expected {
+            return Err(TtsError::invalid(
+                "style_vector",
+                format!("style_vector must have {expected} dimensions"),
+            )
+            .into());
+        }
+        if vector.iter().any(|v| !v.is_finite()) {
+            return Err(TtsError::invalid(
+                "style_vector",
+                "style_vector must only contain finite values",
+            )
+            .into());
         }
+        request.style_vector = Some(vector.as_slice());
+    }
 
-        if let Some(length_scale) = input.length_scale {
-            if length_scale <= 0.0 {
-                bail!("length_scale must be positive");
-            }
-            request.length_scale = length_scale;
+    if let Some(sdp_ratio) = input.sdp_ratio() {
+        request.sdp_ratio = sdp_ratio.clamp(0.0, 1.0);
+    }
+
+    if let Some(noise) = input.noise() {
+        request.noise = noise.max(0.0);
+    }
+
+    if let Some(noise_w) = input.noise_w() {
+        request.noise_w = noise_w.max(0.0);
+    }
+
+    if let Some(length_scale) = input.length_scale() {
+        if length_scale <= 0.0 {
+            return Err(TtsError::invalid("length_scale", "length_scale must be positive").into());
         }
+        request.length_scale = length_scale;
+    }
 
-        if let Some(ref assist) = input.assist_text {
-            request.assist_text = Some(assist.as_str());
+    match input.assist() {
+        (Some(_), Some(_)) => {
+            return Err(TtsError::invalid(
+                "assist_prompt",
+                "assist_prompt cannot be combined with assist_text",
+            )
+            .into());
+        }
+        (Some(assist), None) => request.assist_text = Some(assist),
+        (None, Some(name)) => {
+            let text = project.bert().assist_prompt(name).ok_or_else(|| {
+                TtsError::invalid("assist_prompt", format!("unknown assist prompt '{name}'"))
+            })?;
+            request.assist_text = Some(text);
         }
+        (None, None) => {}
+    }
 
-        if let Some(weight) = input.assist_weight {
-            if !(0.0..=1.0).contains(&weight) {
-                bail!("assist_weight must be within [0.0, 1.0]");
-            }
-            request.assist_weight = weight;
+    if let Some(weight) = input.assist_weight() {
+        if !(0.0..=1.0).contains(&weight) {
+            return Err(TtsError::invalid(
+                "assist_weight",
+                "assist_weight must be within [0.0, 1.0]",
+            )
+            .into());
         }
+        request.assist_weight = weight;
+    }
+
+    request.seed = input.seed;
+
+    if let Some(pitch) = input.pitch
+        && !(-MAX_PITCH_SEMITONES..=MAX_PITCH_SEMITONES).contains(&pitch)
+    {
+        return Err(TtsError::invalid(
+            "pitch",
+            format!(
+                "pitch must be within [-{MAX_PITCH_SEMITONES}, {MAX_PITCH_SEMITONES}] semitones"
+            ),
+        )
+        .into());
+    }
+
+    // Gain is applied to the normalisation peak, so it can only go as far
+    // as the headroom `peak_target` leaves below full scale.
+    let max_volume = MAX_VOLUME_DB.min(audio::headroom_db(defaults.peak_target));
+    if let Some(volume) = input.volume
+        && !(MIN_VOLUME_DB..=max_volume).contains(&volume)
+    {
+        return Err(TtsError::invalid(
+            "volume",
+            format!(
+                "volume must be within [{MIN_VOLUME_DB}, {max_volume:.2}] dB; peak_target {} \
+                 leaves {max_volume:.2} dB of headroom",
+                defaults.peak_target
+            ),
+        )
+        .into());
+    }
+
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_preset(preset: Preset) -> ChineseSynthesisInput {
+        let mut input = ChineseSynthesisInput::new("你好".to_string());
+        input.preset = Some(Arc::new(preset));
+        input
+    }
+
+    #[test]
+    fn request_length_scale_beats_preset_speed() {
+        let mut input = with_preset(Preset {
+            speed: Some(2.0),
+            ..Default::default()
+        });
+        assert_eq!(input.length_scale(), Some(0.5));
+        input.length_scale = Some(1.2);
+        assert_eq!(input.length_scale(), Some(1.2));
+    }
 
-        Ok(request)
+    #[test]
+    fn request_assist_text_drops_the_preset_prompt() {
+        let mut input = with_preset(Preset {
+            assist_prompt: Some("calm".to_string()),
+            assist_weight: Some(0.5),
+            ..Default::default()
+        });
+        assert_eq!(input.assist(), (None, Some("calm")));
+        input.assist_text = Some("今天很开心".to_string());
+        assert_eq!(input.assist(), (Some("今天很开心"), None));
+        assert_eq!(input.assist_weight(), Some(0.5));
     }
 }
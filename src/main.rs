@@ -1,43 +1,117 @@
 mod audio;
+mod cache;
 mod config;
 mod constants;
 mod errors;
 mod inference;
 mod model;
 mod nlp;
+mod registry;
+mod reload;
 mod server;
+mod settings;
+#[cfg(test)]
+mod test_util;
+mod timestamps;
 
-use std::{net::SocketAddr, path::PathBuf};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::Context;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use tokio::runtime::Builder;
+use tracing::{info, warn};
 use tracing_subscriber::{EnvFilter, fmt};
 
-use crate::{model::TtsProject, server::serve};
+use crate::{
+    cache::SynthesisCache,
+    model::{ProjectPaths, bundle},
+    registry::ModelRegistry,
+    server::{ServeOptions, serve},
+    settings::{Precision, Settings, SettingsArgs},
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Path to Style-Bert-VITS2 ONNX model (.onnx)
-    #[arg(long)]
-    model: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    settings: SettingsArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compute a style vector from reference embeddings and register it by name
+    AddStyle(AddStyleArgs),
+    /// Write an int8 dynamically quantised copy of an fp32 ONNX model, by
+    /// default the BERT model, for use with `--bert-precision int8`
+    Quantize(QuantizeArgs),
+    /// Download the BERT tokenizer and model files and verify every file
+    /// against its SHA-256, so the server can later run with `--offline`
+    DownloadAssets(DownloadAssetsArgs),
+    /// Serve the BERT model over a Unix socket for servers started with
+    /// `--bert-socket`, so they share one copy of it
+    BertSidecar(BertSidecarArgs),
+}
 
-    /// Path to config.json for the ONNX model
+#[derive(clap::Args, Debug)]
+struct AddStyleArgs {
+    /// Path to config.json to update with the new style id
     #[arg(long)]
     config: PathBuf,
 
-    /// Path to style_vectors.npy
+    /// Path to style_vectors.npy to append the new vector to
     #[arg(long = "style-vectors")]
     style_vectors: PathBuf,
 
-    /// Root directory for ONNX BERT models (expects chinese-roberta-wwm-ext-large-onnx)
+    /// Name of the new style
+    #[arg(long)]
+    name: String,
+
+    /// Reference embedding (.npy, one vector or one per row); may be repeated
+    #[arg(long = "embedding", required = true)]
+    embeddings: Vec<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct QuantizeArgs {
+    /// Directory holding the BERT model, as passed to the server; its
+    /// model.onnx is downloaded if missing
+    #[arg(long = "bert-root", required_unless_present = "input")]
+    bert_root: Option<PathBuf>,
+
+    /// fp32 ONNX model to quantise instead of the BERT model
+    #[arg(long, conflicts_with = "bert_root")]
+    input: Option<PathBuf>,
+
+    /// Where to write the quantised model [default: NAME_int8.onnx next to
+    /// the input]
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct DownloadAssetsArgs {
+    /// Directory to download the BERT files into [default: model.bert_root]
     #[arg(long = "bert-root")]
-    bert_root: PathBuf,
+    bert_root: Option<PathBuf>,
 
-    /// Address to bind the HTTP server to
-    #[arg(long, default_value = "0.0.0.0:8080")]
-    listen: String,
+    /// Model precision to fetch; may be repeated [default: runtime.bert.precision,
+    /// else fp16]
+    #[arg(long, value_enum)]
+    precision: Vec<Precision>,
+}
+
+#[derive(clap::Args, Debug)]
+struct BertSidecarArgs {
+    /// Directory holding the BERT model [default: model.bert_root]
+    #[arg(long = "bert-root")]
+    bert_root: Option<PathBuf>,
+
+    /// Socket to listen on [default: runtime.bert_socket]
+    #[arg(long)]
+    socket: Option<PathBuf>,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -49,22 +123,196 @@ fn main() -> anyhow::Result<()> {
 
     fmt().with_env_filter(env_filter).init();
 
-    let project = TtsProject::load(
-        &args.model,
-        &args.config,
-        &args.style_vectors,
-        &args.bert_root,
-    )
-    .context("failed to initialise TTS project")?;
+    if let Some(Command::AddStyle(add)) = args.command {
+        let style_id =
+            model::style::append_style(&add.config, &add.style_vectors, &add.name, &add.embeddings)
+                .context("failed to add style")?;
+        info!("added style '{}' with id {style_id}", add.name);
+        return Ok(());
+    }
+
+    let settings = Settings::load(&args.settings)?;
+    if args.settings.print_config {
+        print!("{}", settings.to_toml()?);
+        return Ok(());
+    }
+    match args.command {
+        Some(Command::Quantize(quantize)) => return quantize_model(quantize, &settings),
+        Some(Command::DownloadAssets(download)) => return download_assets(download, &settings),
+        Some(Command::BertSidecar(sidecar)) => return bert_sidecar(sidecar, &settings),
+        _ => {}
+    }
+
+    let model = &settings.model;
+    // With a sidecar the BERT files are only needed by the sidecar.
+    let bert_root = match (&model.bert_root, &settings.runtime.bert_socket) {
+        (_, Some(_)) => model.bert_root.clone().unwrap_or_default(),
+        (Some(bert_root), None) => {
+            nlp::bert::ensure_bert_assets(
+                &model::resolve_bert_dir(bert_root),
+                settings.runtime.bert.precision,
+                &settings.assets,
+                false,
+            )?;
+            bert_root.clone()
+        }
+        (None, None) => anyhow::bail!("--bert-root (model.bert_root) is required"),
+    };
+    let models = match (
+        &model.model_dir,
+        &model.model,
+        &model.config,
+        &model.style_vectors,
+    ) {
+        (Some(dir), ..) => bundle::discover(dir, &bert_root, &settings.runtime)?,
+        (None, Some(model), Some(config), Some(style_vectors)) => {
+            let name = model
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "default".to_string());
+            let paths = ProjectPaths {
+                model: model.clone(),
+                config: config.clone(),
+                style_vectors: style_vectors.clone(),
+                bert_root,
+                runtime: settings.runtime.clone(),
+            };
+            vec![(name, paths)]
+        }
+        _ => anyhow::bail!(
+            "either --model-dir or --model, --config and --style-vectors are required"
+        ),
+    };
 
-    let listen: SocketAddr = args.listen.parse().context("invalid listen address")?;
+    let server = &settings.server;
+    let listen: SocketAddr = server.listen.parse().context("invalid listen address")?;
+
+    let cache = if server.cache_entries > 0 {
+        Some(Arc::new(SynthesisCache::new(
+            server.cache_entries,
+            server.cache_max_mb.saturating_mul(1024 * 1024),
+            server.cache_dir.clone(),
+        )?))
+    } else {
+        None
+    };
+
+    let models = ModelRegistry::load(models, settings.synthesis_defaults())
+        .context("failed to initialise TTS project")?;
 
     let runtime = Builder::new_multi_thread()
         .enable_all()
         .build()
         .context("failed to build tokio runtime")?;
 
-    runtime
-        .block_on(async { serve(listen, project).await })
-        .context("server terminated unexpectedly")
+    let options = ServeOptions {
+        drain_timeout: Duration::from_secs(server.shutdown_timeout),
+        warmup_texts: server.warmup_texts.clone(),
+        api_keys_file: server.api_keys.clone(),
+        mp3_bitrate: settings.audio.mp3_bitrate,
+        presets: settings.presets.clone(),
+    };
+    let result = runtime
+        .block_on(async { serve(listen, models, cache, options).await })
+        .context("server terminated unexpectedly");
+    // Draining already happened inside `serve`; don't wait again for
+    // inferences that outlived the deadline.
+    runtime.shutdown_background();
+    result
+}
+
+fn quantize_model(args: QuantizeArgs, settings: &Settings) -> anyhow::Result<()> {
+    let input = match (args.input, args.bert_root) {
+        (Some(input), _) => input,
+        (None, Some(root)) => {
+            let dir = model::resolve_bert_dir(&root);
+            nlp::bert::ensure_bert_assets(&dir, Some(Precision::Fp32), &settings.assets, false)?;
+            dir.join("model.onnx")
+        }
+        (None, None) => unreachable!("clap requires --bert-root or --input"),
+    };
+    let output = args
+        .output
+        .unwrap_or_else(|| Precision::Int8.variant_of(&input));
+    let summary = model::quantize::quantize_dynamic(&input, &output)
+        .with_context(|| format!("failed to quantise {}", input.display()))?;
+    info!(
+        "quantised {} MatMul nodes ({} left in fp32); wrote {} ({} MiB, from {} MiB)",
+        summary.quantized,
+        summary.skipped,
+        output.display(),
+        summary.output_bytes >> 20,
+        summary.input_bytes >> 20
+    );
+    Ok(())
+}
+
+fn download_assets(args: DownloadAssetsArgs, settings: &Settings) -> anyhow::Result<()> {
+    let bert_root = args
+        .bert_root
+        .or_else(|| settings.model.bert_root.clone())
+        .context("--bert-root (model.bert_root) is required")?;
+    let dir = model::resolve_bert_dir(&bert_root);
+    let precisions = if args.precision.is_empty() {
+        vec![settings.runtime.bert.precision.unwrap_or(Precision::Fp16)]
+    } else {
+        args.precision
+    };
+    let mut unverified = Vec::new();
+    for precision in precisions {
+        let files = nlp::bert::ensure_bert_assets(&dir, Some(precision), &settings.assets, true)
+            .with_context(|| format!("failed to fetch {} BERT assets", precision.as_str()))?;
+        unverified.extend(files);
+    }
+    unverified.sort();
+    unverified.dedup();
+    if unverified.is_empty() {
+        info!("BERT assets in {} are complete and verified", dir.display());
+    } else {
+        warn!(
+            "BERT assets in {} are complete; {} had no checksum and are unverified: {}",
+            dir.display(),
+            unverified.len(),
+            unverified.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn bert_sidecar(args: BertSidecarArgs, settings: &Settings) -> anyhow::Result<()> {
+    let bert_root = args
+        .bert_root
+        .or_else(|| settings.model.bert_root.clone())
+        .context("--bert-root (model.bert_root) is required")?;
+    let socket = args
+        .socket
+        .or_else(|| settings.runtime.bert_socket.clone())
+        .context("--socket (runtime.bert_socket) is required")?;
+    let dir = model::resolve_bert_dir(&bert_root);
+    nlp::bert::ensure_bert_assets(
+        &dir,
+        settings.runtime.bert.precision,
+        &settings.assets,
+        false,
+    )?;
+
+    // The sidecar loads the model itself rather than forwarding to a socket.
+    let runtime = settings::RuntimeSettings {
+        bert_socket: None,
+        ..settings.runtime.clone()
+    };
+    let env = ort::environment::Environment::builder()
+        .with_name("sbv2-bert")
+        .build()
+        .context("failed to initialize ONNX Runtime environment")?
+        .into_arc();
+    let bert = nlp::bert::BertExtractor::new(&env, &dir, &runtime)
+        .with_context(|| format!("failed to initialize BERT at {}", dir.display()))?;
+    nlp::sidecar::serve(&socket, Arc::new(bert))
+}
+
+#[cfg(not(unix))]
+fn bert_sidecar(_: BertSidecarArgs, _: &Settings) -> anyhow::Result<()> {
+    anyhow::bail!("the BERT sidecar needs Unix domain sockets")
 }
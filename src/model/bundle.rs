@@ -0,0 +1,221 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+
+use super::ProjectPaths;
+use crate::{
+    config::HyperParametersData,
+    settings::{Precision, RuntimeSettings},
+};
+
+const CONFIG_FILE: &str = "config.json";
+const STYLE_VECTORS_FILE: &str = "style_vectors.npy";
+
+/// Resolves `dir` into named model folders. `dir` is either a single
+/// Style-Bert-VITS2 model folder or a directory whose subfolders are models;
+/// each model is named after its folder.
+pub fn discover(
+    dir: &Path,
+    bert_root: &Path,
+    runtime: &RuntimeSettings,
+) -> Result<Vec<(String, ProjectPaths)>> {
+    if !dir.is_dir() {
+        bail!("model directory {} does not exist", dir.display());
+    }
+    if dir.join(CONFIG_FILE).exists() {
+        return Ok(vec![(
+            folder_name(dir),
+            model_folder(dir, bert_root, runtime)?,
+        )]);
+    }
+
+    let mut folders: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("failed to list {}", dir.display()))?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir() && path.join(CONFIG_FILE).exists())
+        .collect();
+    folders.sort();
+    if folders.is_empty() {
+        bail!(
+            "{} contains neither {CONFIG_FILE} nor model folders",
+            dir.display()
+        );
+    }
+    folders
+        .iter()
+        .map(|folder| {
+            Ok((
+                folder_name(folder),
+                model_folder(folder, bert_root, runtime)?,
+            ))
+        })
+        .collect()
+}
+
+fn model_folder(dir: &Path, bert_root: &Path, runtime: &RuntimeSettings) -> Result<ProjectPaths> {
+    let style_vectors = dir.join(STYLE_VECTORS_FILE);
+    if !style_vectors.exists() {
+        bail!("{} has no {STYLE_VECTORS_FILE}", dir.display());
+    }
+    Ok(ProjectPaths {
+        model: find_onnx_model(dir)?,
+        config: dir.join(CONFIG_FILE),
+        style_vectors,
+        bert_root: bert_root.to_path_buf(),
+        runtime: runtime.clone(),
+    })
+}
+
+fn find_onnx_model(dir: &Path) -> Result<PathBuf> {
+    let mut onnx = Vec::new();
+    let mut safetensors = false;
+    for entry in fs::read_dir(dir).with_context(|| format!("failed to list {}", dir.display()))? {
+        let path = entry?.path();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("onnx") => onnx.push(path),
+            Some("safetensors") => safetensors = true,
+            _ => {}
+        }
+    }
+    onnx.sort();
+    // Precision variants of one model count once, as the fp32 file when it
+    // exists; `runtime.vits.precision` picks the variant at load time.
+    let mut models: Vec<PathBuf> = Vec::new();
+    for path in onnx {
+        let base = Precision::Fp32.variant_of(&path);
+        if models
+            .iter()
+            .all(|model| Precision::Fp32.variant_of(model) != base)
+        {
+            models.push(if base.exists() { base } else { path });
+        }
+    }
+    let mut onnx = models;
+    match onnx.len() {
+        1 => Ok(onnx.remove(0)),
+        0 if safetensors => bail!(
+            "{} only contains .safetensors weights; export the model to ONNX first",
+            dir.display()
+        ),
+        0 => bail!("{} contains no .onnx model", dir.display()),
+        _ => bail!(
+            "{} contains several .onnx models ({}); pass --model explicitly",
+            dir.display(),
+            onnx.iter()
+                .filter_map(|path| path.file_name())
+                .map(|name| name.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+fn folder_name(dir: &Path) -> String {
+    dir.canonicalize()
+        .ok()
+        .as_deref()
+        .unwrap_or(dir)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "default".to_string())
+}
+
+/// Checks that the config's id tables fit the style vector matrix.
+pub fn check_id_tables(data: &HyperParametersData, num_style_vectors: usize) -> Result<()> {
+    if data.num_styles != num_style_vectors {
+        bail!(
+            "config.json declares {} styles but style_vectors.npy has {num_style_vectors} rows",
+            data.num_styles
+        );
+    }
+    if let Some((name, id)) = data
+        .style2id
+        .iter()
+        .find(|(_, id)| **id >= num_style_vectors)
+    {
+        bail!("style '{name}' maps to id {id}, beyond the {num_style_vectors} style vectors");
+    }
+    let num_speakers = data.spk2id.len();
+    if let Some((name, id)) = data.spk2id.iter().find(|(_, id)| **id >= num_speakers) {
+        bail!("speaker '{name}' maps to id {id}, but only {num_speakers} speakers are defined");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn data(
+        num_styles: usize,
+        styles: &[(&str, usize)],
+        speakers: &[(&str, usize)],
+    ) -> HyperParametersData {
+        let table = |entries: &[(&str, usize)]| -> BTreeMap<String, usize> {
+            entries
+                .iter()
+                .map(|(name, id)| (name.to_string(), *id))
+                .collect()
+        };
+        HyperParametersData {
+            use_jp_extra: false,
+            sampling_rate: 44100,
+            add_blank: true,
+            cleaned_text: true,
+            spk2id: table(speakers),
+            num_styles,
+            style2id: table(styles),
+        }
+    }
+
+    #[test]
+    fn id_tables_must_fit_style_vectors() {
+        let ok = data(2, &[("Neutral", 0), ("Happy", 1)], &[("a", 0)]);
+        assert!(check_id_tables(&ok, 2).is_ok());
+        assert!(check_id_tables(&ok, 3).is_err());
+
+        let bad_style = data(2, &[("Neutral", 0), ("Happy", 2)], &[("a", 0)]);
+        assert!(check_id_tables(&bad_style, 2).is_err());
+
+        let bad_speaker = data(2, &[("Neutral", 0)], &[("a", 1)]);
+        assert!(check_id_tables(&bad_speaker, 2).is_err());
+    }
+
+    #[test]
+    fn discovers_nested_model_folders() {
+        let root = TempDir::new("bundle");
+        for name in ["alpha", "beta"] {
+            let folder = root.join(name);
+            fs::create_dir_all(&folder).unwrap();
+            fs::write(folder.join(CONFIG_FILE), "{}").unwrap();
+            fs::write(folder.join(STYLE_VECTORS_FILE), "").unwrap();
+            fs::write(folder.join(format!("{name}.onnx")), "").unwrap();
+        }
+        fs::write(root.join("beta/beta_int8.onnx"), "").unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+
+        let models = discover(&root, Path::new("bert"), &RuntimeSettings::default()).unwrap();
+        let names: Vec<&str> = models.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["alpha", "beta"]);
+        assert!(models[1].1.model.ends_with("beta/beta.onnx"));
+        assert_eq!(
+            Precision::Int8.variant_of(&models[1].1.model),
+            root.join("beta/beta_int8.onnx")
+        );
+
+        let single = discover(
+            &root.join("alpha"),
+            Path::new("bert"),
+            &RuntimeSettings::default(),
+        )
+        .unwrap();
+        assert_eq!(single[0].0, "alpha");
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{cache::CacheStats, nlp::assist::AssistCacheStats};
+
+use super::{
+    pool::PoolStatus,
+    signature::{ModelVariant, TensorInfo},
+};
+
+/// Everything the admin API reports about a loaded project.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelDetails {
+    pub model_name: String,
+    pub version: String,
+    pub variant: ModelVariant,
+    pub use_jp_extra: bool,
+    pub add_blank: bool,
+    pub sample_rate: u32,
+    pub num_speakers: usize,
+    pub num_styles: usize,
+    pub style_dim: usize,
+    pub default_speaker: Option<String>,
+    pub default_style: Option<String>,
+    pub inputs: Vec<TensorInfo>,
+    pub outputs: Vec<TensorInfo>,
+    pub execution_provider: &'static str,
+    pub sessions: PoolStatus,
+    pub bert: BertDetails,
+    pub files: Vec<FileDigest>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BertDetails {
+    pub model_path: PathBuf,
+    pub execution_provider: String,
+    /// `None` while the sessions are released after being idle.
+    pub sessions: Option<PoolStatus>,
+    /// Sentence feature cache of this process; `None` when disabled.
+    pub feature_cache: Option<CacheStats>,
+    pub assist_cache: Option<AssistCacheStats>,
+    /// Socket of the sidecar process serving BERT, when one is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sidecar: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileDigest {
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+impl FileDigest {
+    pub fn compute(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let bytes = file.metadata()?.len();
+        let mut hasher = Sha256::new();
+        io::copy(&mut BufReader::new(file), &mut hasher)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            bytes,
+            sha256: format!("{:x}", hasher.finalize()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn digest_matches_known_sha256() {
+        let dir = TempDir::new("digest");
+        let path = dir.join("abc");
+        std::fs::write(&path, b"abc").unwrap();
+        let digest = FileDigest::compute(&path).unwrap();
+        assert_eq!(digest.bytes, 3);
+        assert_eq!(
+            digest.sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -1,20 +1,26 @@
+pub mod bundle;
+pub mod details;
+mod optimize;
+pub mod pool;
+pub mod quantize;
+pub(crate) mod session;
+pub mod signature;
+pub mod style;
+
 use std::{
-    collections::HashMap,
-    fs::File,
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{Context, Result, anyhow, bail};
 use ndarray::{Array1, Array2, Array3, Axis, CowArray, arr0};
 use ndarray_npy::ReadNpyExt;
-use ort::{
-    ExecutionProvider, GraphOptimizationLevel, SessionBuilder, environment::Environment,
-    session::Session, value::Value,
-};
-#[cfg(any(feature = "cuda", feature = "coreml", feature = "rocm"))]
-use tracing::info;
-use tracing::warn;
+use ort::{environment::Environment, value::Value};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
 
 use crate::{
     config::HyperParameters,
@@ -22,27 +28,79 @@ use crate::{
         DEFAULT_ASSIST_TEXT_WEIGHT, DEFAULT_LENGTH, DEFAULT_NOISE, DEFAULT_NOISEW,
         DEFAULT_SDP_RATIO, DEFAULT_STYLE,
     },
+    errors::TtsError,
     nlp::{
-        LANGUAGE_ID_MAP, LANGUAGE_TONE_START_MAP, SYMBOL_ID_MAP,
+        LANGUAGE_ID_MAP, LANGUAGE_TONE_START_MAP, PAD, SYMBOL_ID_MAP,
         bert::BertExtractor,
         chinese::{g2p, normalizer},
     },
+    settings::{Precision, RuntimeSettings},
+    timestamps::{self, Alignment},
 };
 
+use self::{
+    details::{FileDigest, ModelDetails},
+    pool::SessionPool,
+    session::{ModelSession, new_session},
+    signature::{ModelVariant, VitsInput, VitsSignature},
+};
+
+/// Locations of the assets a [`TtsProject`] is loaded from, and the session
+/// options to load them with, kept so the project can be reloaded the same way.
+#[derive(Debug, Clone)]
+pub struct ProjectPaths {
+    pub model: PathBuf,
+    pub config: PathBuf,
+    pub style_vectors: PathBuf,
+    pub bert_root: PathBuf,
+    pub runtime: RuntimeSettings,
+}
+
+impl ProjectPaths {
+    pub fn load(&self) -> Result<TtsProject> {
+        TtsProject::load(self, None)
+    }
+
+    /// Loads the project reusing an already initialised BERT extractor, so
+    /// several models can share one BERT session.
+    pub fn load_with_bert(&self, bert: Arc<BertExtractor>) -> Result<TtsProject> {
+        TtsProject::load(self, Some(bert))
+    }
+}
+
 pub struct TtsProject {
     hps: HyperParameters,
     style_vectors: Array2<f32>,
     style2id: HashMap<String, usize>,
     spk2id: HashMap<String, usize>,
-    onnx_session: Session,
-    bert: BertExtractor,
+    sessions: SessionPool<ModelSession>,
+    signature: VitsSignature,
+    execution_provider: &'static str,
+    bert: Arc<BertExtractor>,
     default_style_id: usize,
     default_speaker_id: usize,
+    paths: ProjectPaths,
+    file_digests: OnceLock<Vec<FileDigest>>,
+    fingerprint: String,
+}
+
+struct TextFeatures {
+    normalized: String,
+    phone_ids: Vec<i64>,
+    tone_ids: Vec<i64>,
+    lang_ids: Vec<i64>,
+    phone_names: Vec<String>,
+    word2ph: Vec<usize>,
+    bert: Array2<f32>,
 }
 
 pub struct InferenceResult {
     pub audio: Vec<f32>,
     pub sample_rate: u32,
+    pub alignment: Alignment,
+    /// The seed that reproduces this audio: the one given to a graph with a
+    /// seed input, else the request's.
+    pub seed: Option<u64>,
 }
 
 pub struct InferenceRequest<'a> {
@@ -50,12 +108,15 @@ pub struct InferenceRequest<'a> {
     pub speaker: Option<&'a str>,
     pub style: Option<&'a str>,
     pub style_weight: f32,
+    pub style_mix: Option<&'a BTreeMap<String, f32>>,
+    pub style_vector: Option<&'a [f32]>,
     pub sdp_ratio: f32,
     pub noise: f32,
     pub noise_w: f32,
     pub length_scale: f32,
     pub assist_text: Option<&'a str>,
     pub assist_weight: f32,
+    pub seed: Option<u64>,
 }
 
 impl<'a> InferenceRequest<'a> {
@@ -65,26 +126,37 @@ impl<'a> InferenceRequest<'a> {
             speaker: None,
             style: None,
             style_weight: 1.0,
+            style_mix: None,
+            style_vector: None,
             sdp_ratio: DEFAULT_SDP_RATIO,
             noise: DEFAULT_NOISE,
             noise_w: DEFAULT_NOISEW,
             length_scale: DEFAULT_LENGTH,
             assist_text: None,
             assist_weight: DEFAULT_ASSIST_TEXT_WEIGHT,
+            seed: None,
         }
     }
 }
 
 impl TtsProject {
-    #[allow(clippy::too_many_arguments)]
-    pub fn load(
-        model_path: &Path,
-        config_path: &Path,
-        style_vec_path: &Path,
-        bert_root: &Path,
-    ) -> Result<Self> {
+    pub fn load(paths: &ProjectPaths, shared_bert: Option<Arc<BertExtractor>>) -> Result<Self> {
+        let mut paths = paths.clone();
+        if let Some(precision) = paths.runtime.vits.precision {
+            paths.model = precision.variant_of(&paths.model);
+        }
+        let model_path = paths.model.as_path();
+        let config_path = paths.config.as_path();
+        let style_vec_path = paths.style_vectors.as_path();
         if !model_path.exists() {
-            bail!("TTS ONNX model not found at {}", model_path.display());
+            match paths.runtime.vits.precision {
+                Some(precision) => bail!(
+                    "{} TTS ONNX model not found at {}",
+                    precision.as_str(),
+                    model_path.display()
+                ),
+                None => bail!("TTS ONNX model not found at {}", model_path.display()),
+            }
         }
         if !config_path.exists() {
             bail!("config.json not found at {}", config_path.display());
@@ -96,6 +168,7 @@ impl TtsProject {
             );
         }
 
+        let fingerprint = fingerprint(&[model_path, config_path, style_vec_path])?;
         let hps = HyperParameters::load_from_file(config_path)?;
 
         let style_file = File::open(style_vec_path).with_context(|| {
@@ -110,16 +183,20 @@ impl TtsProject {
         if num_styles == 0 {
             bail!("style_vectors.npy is empty");
         }
+        bundle::check_id_tables(&hps.data, num_styles).with_context(|| {
+            format!(
+                "{} does not match {}",
+                config_path.display(),
+                style_vec_path.display()
+            )
+        })?;
 
-        let style2id: HashMap<String, usize> = if hps.data.style2id.is_empty() {
-            (0..num_styles).map(|idx| (idx.to_string(), idx)).collect()
-        } else {
-            hps.data
-                .style2id
-                .iter()
-                .map(|(k, v)| (k.clone(), (*v).min(num_styles - 1)))
-                .collect()
-        };
+        let style2id: HashMap<String, usize> = hps
+            .data
+            .style2id
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
         let spk2id: HashMap<String, usize> = hps
             .data
             .spk2id
@@ -140,59 +217,79 @@ impl TtsProject {
             .context("failed to initialize ONNX Runtime environment")?
             .into_arc();
 
-        let session = new_session(&env, model_path)?;
+        let runtime = &paths.runtime;
+        let mut execution_provider = "";
+        let sessions = SessionPool::build(
+            runtime.vits.replicas,
+            Duration::from_millis(runtime.checkout_timeout_ms),
+            || {
+                let (session, provider) =
+                    new_session(&env, model_path, runtime, &runtime.vits, "VITS")?;
+                execution_provider = provider;
+                Ok(session)
+            },
+        )?;
+        let signature = sessions
+            .with_first(|session| VitsSignature::inspect(session))
+            .with_context(|| {
+                format!(
+                    "{} does not match a supported Style-Bert-VITS2 export",
+                    model_path.display()
+                )
+            })?;
+        debug!(
+            "bound {:?} VITS model inputs {:?} and outputs {:?}",
+            signature.variant, signature.input_info, signature.output_info
+        );
+        if hps.data.use_jp_extra != (signature.variant == ModelVariant::JpExtra) {
+            warn!(
+                "config.json use_jp_extra={} disagrees with the {:?} model inputs; trusting the model",
+                hps.data.use_jp_extra, signature.variant
+            );
+        }
 
-        let bert_dir = resolve_bert_dir(bert_root);
-        let bert = BertExtractor::new(&env, &bert_dir)
-            .with_context(|| format!("failed to initialize BERT at {}", bert_dir.display()))?;
+        let bert = match shared_bert {
+            Some(bert) => bert,
+            None => {
+                let bert_dir = resolve_bert_dir(&paths.bert_root);
+                let bert =
+                    BertExtractor::new(&env, &bert_dir, &paths.runtime).with_context(|| {
+                        format!("failed to initialize BERT at {}", bert_dir.display())
+                    })?;
+                Arc::new(bert)
+            }
+        };
 
         Ok(Self {
             hps,
             style_vectors,
             style2id,
             spk2id,
-            onnx_session: session,
+            sessions,
+            signature,
+            execution_provider,
             bert,
             default_style_id,
             default_speaker_id,
+            paths,
+            file_digests: OnceLock::new(),
+            fingerprint,
         })
     }
 
+    /// Synthesises the request. A VITS session is only checked out for the
+    /// graph run itself, so requests busy with text analysis or waiting for
+    /// BERT do not keep a replica idle.
     pub fn infer_chinese(&self, request: InferenceRequest<'_>) -> Result<InferenceResult> {
-        let normalized = normalizer::normalize_text(request.text);
-        let (phones, tones, mut word2ph) = g2p::g2p(&normalized)?;
-
-        let language_id = *LANGUAGE_ID_MAP
-            .get("ZH")
-            .ok_or_else(|| anyhow!("language id for ZH not found"))?
-            as i64;
-        let tone_start = *LANGUAGE_TONE_START_MAP
-            .get("ZH")
-            .ok_or_else(|| anyhow!("tone start for ZH not found"))? as i32;
-
-        let (mut phone_ids, mut tone_ids) =
-            self.encode_phone_sequence(&phones, &tones, tone_start, &mut word2ph)?;
-        let mut lang_ids = vec![language_id; phone_ids.len()];
-
-        if self.hps.data.add_blank {
-            phone_ids = intersperse(&phone_ids, 0);
-            tone_ids = intersperse(&tone_ids, 0);
-            lang_ids = intersperse(&lang_ids, language_id);
-            for val in &mut word2ph {
-                *val *= 2;
-            }
-            if let Some(first) = word2ph.first_mut() {
-                *first += 1;
-            }
-        }
-
-        let bert_features = self.bert.extract(
-            &normalized,
-            &word2ph,
-            request
-                .assist_text
-                .map(|text| (text, request.assist_weight)),
-        )?;
+        let TextFeatures {
+            normalized,
+            phone_ids,
+            tone_ids,
+            lang_ids,
+            phone_names,
+            word2ph,
+            bert: bert_features,
+        } = self.text_features(&request)?;
         let bert_batch = bert_features.insert_axis(Axis(0)).to_owned();
 
         let hidden = bert_batch.shape()[1];
@@ -230,7 +327,11 @@ impl TtsProject {
         };
         let sid_tensor = CowArray::from(Array1::from_vec(vec![speaker_id as i64]).into_dyn());
 
-        let style_vector = self.make_style_vector(request.style, request.style_weight)?;
+        let style_vector = match (request.style_vector, request.style_mix) {
+            (Some(raw), _) => self.raw_style_vector(raw)?,
+            (None, Some(mix)) => self.mix_style_vectors(mix)?,
+            (None, None) => self.make_style_vector(request.style, request.style_weight)?,
+        };
         let style_tensor = CowArray::from(style_vector.insert_axis(Axis(0)).into_dyn());
 
         let bert_tensor = CowArray::from(bert_batch.into_dyn());
@@ -239,36 +340,139 @@ impl TtsProject {
 
         let length_scale = CowArray::from(arr0(request.length_scale).into_dyn());
         let sdp_ratio = CowArray::from(arr0(request.sdp_ratio).into_dyn());
-        let noise = CowArray::from(arr0(request.noise).into_dyn());
-        let noise_w = CowArray::from(arr0(request.noise_w).into_dyn());
-
-        let allocator = self.onnx_session.allocator();
-        let inputs = vec![
-            Value::from_array(allocator, &x_tst)?,
-            Value::from_array(allocator, &x_tst_lengths)?,
-            Value::from_array(allocator, &sid_tensor)?,
-            Value::from_array(allocator, &tones_arr)?,
-            Value::from_array(allocator, &lang_arr)?,
-            Value::from_array(allocator, &bert_tensor)?,
-            Value::from_array(allocator, &ja_tensor)?,
-            Value::from_array(allocator, &en_tensor)?,
-            Value::from_array(allocator, &style_tensor)?,
-            Value::from_array(allocator, &length_scale)?,
-            Value::from_array(allocator, &sdp_ratio)?,
-            Value::from_array(allocator, &noise)?,
-            Value::from_array(allocator, &noise_w)?,
-        ];
-
-        let outputs = self.onnx_session.run(inputs)?;
+        // Graphs without a seed input draw their own Gaussian noise, so a seeded
+        // request runs in deterministic mode with both noise scales at zero.
+        let seeded_graph = self.signature.has_input(VitsInput::Seed);
+        let (noise, noise_w) = match request.seed {
+            Some(_) if !seeded_graph => (0.0, 0.0),
+            _ => (request.noise, request.noise_w),
+        };
+        let noise = CowArray::from(arr0(noise).into_dyn());
+        let noise_w = CowArray::from(arr0(noise_w).into_dyn());
+        let used_seed = match request.seed {
+            None if seeded_graph => Some(clock_seed()),
+            seed => seed,
+        };
+        let seed = CowArray::from(arr0(used_seed.unwrap_or(0) as i64).into_dyn());
+
+        let session = self.sessions.checkout()?;
+        let allocator = session.allocator();
+        let mut inputs = Vec::with_capacity(self.signature.inputs.len());
+        for input in &self.signature.inputs {
+            let value = match input {
+                VitsInput::Phones => Value::from_array(allocator, &x_tst)?,
+                VitsInput::PhoneLengths => Value::from_array(allocator, &x_tst_lengths)?,
+                VitsInput::Speaker => Value::from_array(allocator, &sid_tensor)?,
+                VitsInput::Tones => Value::from_array(allocator, &tones_arr)?,
+                VitsInput::Language => Value::from_array(allocator, &lang_arr)?,
+                VitsInput::Bert => Value::from_array(allocator, &bert_tensor)?,
+                VitsInput::JaBert => Value::from_array(allocator, &ja_tensor)?,
+                VitsInput::EnBert => Value::from_array(allocator, &en_tensor)?,
+                VitsInput::Style => Value::from_array(allocator, &style_tensor)?,
+                VitsInput::LengthScale => Value::from_array(allocator, &length_scale)?,
+                VitsInput::SdpRatio => Value::from_array(allocator, &sdp_ratio)?,
+                VitsInput::NoiseScale => Value::from_array(allocator, &noise)?,
+                VitsInput::NoiseScaleW => Value::from_array(allocator, &noise_w)?,
+                VitsInput::Seed => Value::from_array(allocator, &seed)?,
+            };
+            inputs.push(value);
+        }
+
+        let outputs = session.run(inputs)?;
         let tensor = outputs[0].try_extract::<f32>()?;
         let waveform = tensor.view().iter().cloned().collect::<Vec<f32>>();
+        let frames = self
+            .signature
+            .duration_output
+            .and_then(|idx| outputs.get(idx))
+            .map(extract_durations)
+            .transpose()?;
+        drop(tensor);
+        drop(outputs);
+        drop(session);
+
+        let durations = match frames {
+            Some(frames) => {
+                if frames.len() != phone_names.len() {
+                    bail!(
+                        "duration output has {} entries for {} phones",
+                        frames.len(),
+                        phone_names.len()
+                    );
+                }
+                timestamps::scale_durations(&frames, waveform.len())
+            }
+            None => timestamps::estimate_durations(&phone_names, waveform.len()),
+        };
+        let alignment = Alignment::build(
+            &normalized,
+            &phone_names,
+            &word2ph,
+            &durations,
+            self.hps.data.sampling_rate,
+        );
 
         Ok(InferenceResult {
             audio: waveform,
             sample_rate: self.hps.data.sampling_rate,
+            alignment,
+            seed: used_seed,
+        })
+    }
+
+    /// Phone, tone and language ids of the request text and its BERT
+    /// features, aligned to the phones.
+    fn text_features(&self, request: &InferenceRequest<'_>) -> Result<TextFeatures> {
+        let normalized = normalizer::normalize_text(request.text);
+        let (phones, tones, mut word2ph) = g2p::g2p(&normalized)?;
+
+        let language_id = *LANGUAGE_ID_MAP
+            .get("ZH")
+            .ok_or_else(|| anyhow!("language id for ZH not found"))?
+            as i64;
+        let tone_start = *LANGUAGE_TONE_START_MAP
+            .get("ZH")
+            .ok_or_else(|| anyhow!("tone start for ZH not found"))? as i32;
+
+        let (mut phone_ids, mut tone_ids, mut phone_names) =
+            self.encode_phone_sequence(&phones, &tones, tone_start, &mut word2ph)?;
+        let mut lang_ids = vec![language_id; phone_ids.len()];
+
+        if self.hps.data.add_blank {
+            phone_ids = intersperse(&phone_ids, 0);
+            tone_ids = intersperse(&tone_ids, 0);
+            lang_ids = intersperse(&lang_ids, language_id);
+            phone_names = intersperse(&phone_names, PAD.to_string());
+            for val in &mut word2ph {
+                *val *= 2;
+            }
+            if let Some(first) = word2ph.first_mut() {
+                *first += 1;
+            }
+        }
+
+        let bert = self.bert.extract(
+            &normalized,
+            &word2ph,
+            request
+                .assist_text
+                .map(|text| (text, request.assist_weight)),
+        )?;
+        Ok(TextFeatures {
+            normalized,
+            phone_ids,
+            tone_ids,
+            lang_ids,
+            phone_names,
+            word2ph,
+            bert,
         })
     }
 
+    pub fn model_name(&self) -> &str {
+        &self.hps.model_name
+    }
+
     pub fn sample_rate(&self) -> u32 {
         self.hps.data.sampling_rate
     }
@@ -321,6 +525,66 @@ impl TtsProject {
         self.spk2id.get(name).copied()
     }
 
+    pub fn bert(&self) -> &Arc<BertExtractor> {
+        &self.bert
+    }
+
+    pub fn style_dim(&self) -> usize {
+        self.style_vectors.ncols()
+    }
+
+    /// Whether every VITS replica is checked out.
+    pub fn is_busy(&self) -> bool {
+        self.sessions.status().idle == 0
+    }
+
+    /// Changes whenever one of the model files does, so results made with
+    /// other files, before a reload or a restart, are not mistaken for this
+    /// project's.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Describes the loaded model. File hashes are computed on the first call
+    /// and reused afterwards, so callers should run this off the async runtime.
+    pub fn details(&self) -> Result<ModelDetails> {
+        let files = match self.file_digests.get() {
+            Some(files) => files.clone(),
+            None => {
+                let files = [
+                    self.paths.model.as_path(),
+                    self.paths.config.as_path(),
+                    self.paths.style_vectors.as_path(),
+                ]
+                .into_iter()
+                .chain(self.bert.local_files())
+                .map(FileDigest::compute)
+                .collect::<Result<Vec<_>>>()?;
+                self.file_digests.get_or_init(|| files).clone()
+            }
+        };
+
+        Ok(ModelDetails {
+            model_name: self.model_name().to_string(),
+            version: self.hps.version.clone(),
+            variant: self.signature.variant,
+            use_jp_extra: self.hps.data.use_jp_extra,
+            add_blank: self.hps.data.add_blank,
+            sample_rate: self.hps.data.sampling_rate,
+            num_speakers: self.spk2id.len(),
+            num_styles: self.style_vectors.nrows(),
+            style_dim: self.style_dim(),
+            default_speaker: self.default_speaker_name().map(str::to_string),
+            default_style: self.default_style_name().map(str::to_string),
+            inputs: self.signature.input_info.clone(),
+            outputs: self.signature.output_info.clone(),
+            execution_provider: self.execution_provider,
+            sessions: self.sessions.status(),
+            bert: self.bert.details()?,
+            files,
+        })
+    }
+
     pub fn default_style_id(&self) -> usize {
         self.default_style_id
     }
@@ -346,13 +610,40 @@ impl TtsProject {
         Ok(vec.to_owned())
     }
 
+    /// Blends several styles around the mean (row 0): each entry moves the
+    /// vector towards its style by the given weight.
+    fn mix_style_vectors(&self, mix: &BTreeMap<String, f32>) -> Result<Array1<f32>> {
+        let mean = self.style_vectors.row(0);
+        let mut vec = mean.to_owned();
+        for (name, &weight) in mix {
+            let style_id = *self
+                .style2id
+                .get(name)
+                .ok_or_else(|| anyhow!("style '{name}' not found"))?;
+            let target = self.style_vectors.row(style_id);
+            vec += &((&target - &mean) * weight);
+        }
+        Ok(vec)
+    }
+
+    fn raw_style_vector(&self, raw: &[f32]) -> Result<Array1<f32>> {
+        if raw.len() != self.style_dim() {
+            bail!(
+                "style_vector has {} dimensions, expected {}",
+                raw.len(),
+                self.style_dim()
+            );
+        }
+        Ok(Array1::from_vec(raw.to_vec()))
+    }
+
     fn encode_phone_sequence(
         &self,
         phones: &[String],
         tones: &[i32],
         tone_start: i32,
         word2ph: &mut [usize],
-    ) -> Result<(Vec<i64>, Vec<i64>)> {
+    ) -> Result<(Vec<i64>, Vec<i64>, Vec<String>)> {
         if phones.len() != tones.len() {
             bail!(
                 "phones/tones length mismatch: {} vs {}",
@@ -363,6 +654,7 @@ impl TtsProject {
 
         let mut phone_ids = Vec::with_capacity(phones.len());
         let mut tone_ids = Vec::with_capacity(phones.len());
+        let mut kept_phones = Vec::with_capacity(phones.len());
         let mut phone_idx = 0usize;
 
         for count in word2ph.iter_mut() {
@@ -377,6 +669,7 @@ impl TtsProject {
                 if let Some(&symbol_id) = SYMBOL_ID_MAP.get(phone.as_str()) {
                     phone_ids.push(symbol_id as i64);
                     tone_ids.push((tone_start + tone) as i64);
+                    kept_phones.push(phone.clone());
                     kept += 1;
                 } else {
                     warn!("skipping unknown phone symbol '{phone}'");
@@ -386,57 +679,71 @@ impl TtsProject {
         }
 
         if phone_ids.is_empty() {
-            bail!("no recognizable phone symbols after filtering");
+            return Err(TtsError::InvalidInput(
+                "input has no pronounceable text after normalization".into(),
+            )
+            .into());
         }
 
-        Ok((phone_ids, tone_ids))
+        Ok((phone_ids, tone_ids, kept_phones))
     }
 }
 
-fn new_session(env: &Arc<Environment>, model_path: &Path) -> Result<Session> {
-    let mut session_builder = SessionBuilder::new(env)?
-        .with_optimization_level(GraphOptimizationLevel::Level3)?
-        .with_parallel_execution(true)?;
+/// Seed for graphs with a seed input when the request does not fix one.
+fn clock_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+}
 
-    #[cfg(any(feature = "cuda", feature = "coreml", feature = "rocm"))]
-    let mut providers: Vec<ExecutionProvider> = Vec::new();
-    #[cfg(not(any(feature = "cuda", feature = "coreml", feature = "rocm")))]
-    let providers: Vec<ExecutionProvider> = Vec::new();
-    #[cfg(feature = "cuda")]
-    {
-        info!("Using CUDA execution provider");
-        providers.push(ExecutionProvider::CUDA(Default::default()));
+/// Hash of the path, size and modification time of each file; reading the
+/// files themselves would make every load as slow as `/admin/model`.
+fn fingerprint(files: &[&Path]) -> Result<String> {
+    let mut hasher = Sha256::new();
+    for file in files {
+        let meta =
+            fs::metadata(file).with_context(|| format!("failed to stat {}", file.display()))?;
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |elapsed| elapsed.as_nanos());
+        hasher.update(file.as_os_str().as_encoded_bytes());
+        hasher.update([0]);
+        hasher.update(meta.len().to_le_bytes());
+        hasher.update(modified.to_le_bytes());
     }
-    #[cfg(feature = "coreml")]
-    {
-        info!("Using CoreML execution provider");
-        providers.push(ExecutionProvider::CoreML(Default::default()));
-    }
-    #[cfg(feature = "rocm")]
-    {
-        info!("Using ROCm execution provider");
-        providers.push(ExecutionProvider::ROCm(Default::default()));
-    }
-
-    session_builder = session_builder.with_execution_providers(providers)?;
-
-    session_builder
-        .with_model_from_file(model_path)
-        .with_context(|| format!("failed to load ONNX model from {}", model_path.display()))
+    Ok(format!("{:x}", hasher.finalize()))
 }
 
-fn intersperse(values: &[i64], blank: i64) -> Vec<i64> {
+fn intersperse<T: Clone>(values: &[T], blank: T) -> Vec<T> {
     let mut result = Vec::with_capacity(values.len() * 2 + 1);
     for value in values {
-        result.push(blank);
-        result.push(*value);
+        result.push(blank.clone());
+        result.push(value.clone());
     }
     result.push(blank);
     result
 }
 
-fn resolve_bert_dir(root: &Path) -> PathBuf {
-    if root.join("model_fp16.onnx").exists() {
+fn extract_durations(value: &Value<'_>) -> Result<Vec<f32>> {
+    if let Ok(tensor) = value.try_extract::<f32>() {
+        return Ok(tensor.view().iter().copied().collect());
+    }
+    let tensor = value
+        .try_extract::<i64>()
+        .context("duration output is neither float32 nor int64")?;
+    Ok(tensor.view().iter().map(|&frames| frames as f32).collect())
+}
+
+/// `root` itself when it holds a BERT model file, otherwise the standard
+/// subfolder the model is downloaded into.
+pub(crate) fn resolve_bert_dir(root: &Path) -> PathBuf {
+    let bert_model = Path::new("model.onnx");
+    if [Precision::Fp32, Precision::Fp16, Precision::Int8]
+        .iter()
+        .any(|precision| root.join(precision.variant_of(bert_model)).exists())
+    {
         root.to_path_buf()
     } else {
         root.join("chinese-roberta-wwm-ext-large-onnx")
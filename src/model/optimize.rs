@@ -0,0 +1,208 @@
+use std::{
+    fs, iter,
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use anyhow::{Context, Result, bail};
+use ort::{GraphOptimizationLevel, environment::Environment, sys};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+
+use super::session::{CPU_PROVIDER, SessionOptions, check, function};
+use crate::settings::OptimizationLevel;
+
+/// Returns the optimised copy of `model_path` in `dir` for the execution
+/// provider named `provider`, creating it first if it is missing or older
+/// than the source model. The copy is keyed by the source path, the provider
+/// and the optimisation level, so several models and providers can share
+/// `dir`.
+pub(crate) fn optimized_model(
+    env: &Environment,
+    model_path: &Path,
+    dir: &Path,
+    level: OptimizationLevel,
+    provider: &str,
+) -> Result<PathBuf> {
+    let level = saved_level(level, provider);
+    let target = dir.join(cache_name(model_path, level, provider)?);
+    if is_fresh(&target, model_path) {
+        debug!("using optimised graph {}", target.display());
+        return Ok(target);
+    }
+
+    fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let partial = target.with_extension("onnx.partial");
+    save_optimized(env, model_path, &partial, level)
+        .with_context(|| format!("failed to save optimised graph of {}", model_path.display()))?;
+    fs::rename(&partial, &target)
+        .with_context(|| format!("failed to move {} into place", partial.display()))?;
+    info!(
+        "saved {} optimised graph of {} to {}",
+        level.as_str(),
+        model_path.display(),
+        target.display()
+    );
+    Ok(target)
+}
+
+/// The graph is optimised without the accelerator registered, and `all`
+/// adds layout changes only the CPU kernels understand, so graphs saved for
+/// an accelerator stop at `extended`.
+fn saved_level(level: OptimizationLevel, provider: &str) -> OptimizationLevel {
+    if provider != CPU_PROVIDER && level == OptimizationLevel::All {
+        OptimizationLevel::Extended
+    } else {
+        level
+    }
+}
+
+fn cache_name(model_path: &Path, level: OptimizationLevel, provider: &str) -> Result<String> {
+    let source = model_path
+        .canonicalize()
+        .with_context(|| format!("failed to resolve {}", model_path.display()))?;
+    let digest = format!("{:x}", Sha256::digest(source.to_string_lossy().as_bytes()));
+    let stem = model_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "model".to_string());
+    let provider = provider
+        .strip_suffix("ExecutionProvider")
+        .unwrap_or(provider)
+        .to_lowercase();
+    Ok(format!(
+        "{stem}-{}-{provider}-{}.onnx",
+        &digest[..12],
+        level.as_str()
+    ))
+}
+
+fn is_fresh(target: &Path, source: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified());
+    match (modified(target), modified(source)) {
+        (Ok(target), Ok(source)) => target >= source,
+        _ => false,
+    }
+}
+
+/// Builds a throwaway session with `SetOptimizedModelFilePath`, which makes
+/// ONNX Runtime write the optimised graph to `target`. The `ort` session
+/// builder does not expose this option, so it goes through the C API.
+fn save_optimized(
+    env: &Environment,
+    source: &Path,
+    target: &Path,
+    level: OptimizationLevel,
+) -> Result<()> {
+    let api = ort::ort();
+    let source = ort_path(source)?;
+    let target = ort_path(target)?;
+    let level = sys::GraphOptimizationLevel::from(GraphOptimizationLevel::from(level));
+
+    unsafe {
+        let options = SessionOptions::new(&api)?;
+        check(
+            &api,
+            function(
+                api.SetSessionGraphOptimizationLevel,
+                "SetSessionGraphOptimizationLevel",
+            )?(options.ptr, level),
+        )?;
+        check(
+            &api,
+            function(api.SetOptimizedModelFilePath, "SetOptimizedModelFilePath")?(
+                options.ptr,
+                target.as_ptr(),
+            ),
+        )?;
+        let mut session = ptr::null_mut();
+        check(
+            &api,
+            function(api.CreateSession, "CreateSession")?(
+                env.ptr(),
+                source.as_ptr(),
+                options.ptr,
+                &mut session,
+            ),
+        )?;
+        function(api.ReleaseSession, "ReleaseSession")?(session);
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn ort_path(path: &Path) -> Result<Vec<sys::ortchar>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let bytes = path.as_os_str().as_bytes();
+    if bytes.contains(&0) {
+        bail!("path {} contains a NUL byte", path.display());
+    }
+    Ok(bytes
+        .iter()
+        .map(|&byte| byte as sys::ortchar)
+        .chain(iter::once(0))
+        .collect())
+}
+
+#[cfg(windows)]
+fn ort_path(path: &Path) -> Result<Vec<sys::ortchar>> {
+    use std::os::windows::ffi::OsStrExt;
+
+    Ok(path
+        .as_os_str()
+        .encode_wide()
+        .chain(iter::once(0))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn cache_names_depend_on_source_provider_and_level() {
+        let dir = TempDir::new("optimize");
+        fs::create_dir_all(dir.join("a")).unwrap();
+        fs::create_dir_all(dir.join("b")).unwrap();
+        for sub in ["a", "b"] {
+            fs::write(dir.join(sub).join("model.onnx"), b"onnx").unwrap();
+        }
+        let a = dir.join("a/model.onnx");
+        let b = dir.join("b/model.onnx");
+
+        let cuda = "CUDAExecutionProvider";
+        let name = cache_name(&a, OptimizationLevel::Extended, CPU_PROVIDER).unwrap();
+        assert!(name.starts_with("model-") && name.ends_with("-cpu-extended.onnx"));
+        assert_ne!(
+            name,
+            cache_name(&b, OptimizationLevel::Extended, CPU_PROVIDER).unwrap()
+        );
+        assert_ne!(
+            name,
+            cache_name(&a, OptimizationLevel::All, CPU_PROVIDER).unwrap()
+        );
+        assert_ne!(
+            name,
+            cache_name(&a, OptimizationLevel::Extended, cuda).unwrap()
+        );
+
+        assert_eq!(
+            saved_level(OptimizationLevel::All, CPU_PROVIDER),
+            OptimizationLevel::All
+        );
+        assert_eq!(
+            saved_level(OptimizationLevel::All, cuda),
+            OptimizationLevel::Extended
+        );
+        assert_eq!(
+            saved_level(OptimizationLevel::Basic, cuda),
+            OptimizationLevel::Basic
+        );
+
+        assert!(!is_fresh(&dir.join("missing.onnx"), &a));
+        fs::write(dir.join(&name), b"optimised").unwrap();
+        assert!(is_fresh(&dir.join(&name), &a));
+    }
+}
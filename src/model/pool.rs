@@ -0,0 +1,330 @@
+use std::{
+    iter,
+    ops::Deref,
+    sync::{Arc, Condvar, Mutex, MutexGuard, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::errors::TtsError;
+
+/// Fixed set of interchangeable replicas, typically ONNX sessions, handed out
+/// one caller at a time. A caller that finds every replica busy waits up to
+/// the checkout timeout and is then turned away as overloaded.
+pub struct SessionPool<T> {
+    idle: Mutex<Vec<T>>,
+    returned: Condvar,
+    replicas: usize,
+    timeout: Duration,
+}
+
+/// A replica checked out of a [`SessionPool`]; returned when dropped.
+pub struct Checkout<'a, T> {
+    pool: &'a SessionPool<T>,
+    item: Option<T>,
+}
+
+/// A [`SessionPool`] that is released after going unused for the idle
+/// timeout and built again by the next caller, trading a reload for the
+/// memory the replicas hold in between.
+pub struct IdlePool<T> {
+    make: Box<dyn Fn() -> Result<SessionPool<T>> + Send + Sync>,
+    state: Mutex<IdleState<T>>,
+    /// Held while the pool is being built, so only one caller builds it and
+    /// the others wait for that build without holding `state`.
+    loading: Mutex<()>,
+    idle_timeout: Option<Duration>,
+    name: &'static str,
+}
+
+struct IdleState<T> {
+    pool: Option<Arc<SessionPool<T>>>,
+    last_used: Instant,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PoolStatus {
+    pub replicas: usize,
+    pub idle: usize,
+}
+
+impl<T> SessionPool<T> {
+    pub fn new(items: Vec<T>, timeout: Duration) -> Result<Self> {
+        if items.is_empty() {
+            bail!("a session pool needs at least one replica");
+        }
+        Ok(Self {
+            replicas: items.len(),
+            idle: Mutex::new(items),
+            returned: Condvar::new(),
+            timeout,
+        })
+    }
+
+    /// Builds `replicas` items with `make`, stopping at the first error.
+    pub fn build(
+        replicas: usize,
+        timeout: Duration,
+        make: impl FnMut() -> Result<T>,
+    ) -> Result<Self> {
+        let items = iter::repeat_with(make)
+            .take(replicas)
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(items, timeout)
+    }
+
+    pub fn checkout(&self) -> Result<Checkout<'_, T>> {
+        let idle = self.lock();
+        let (mut idle, wait) = self
+            .returned
+            .wait_timeout_while(idle, self.timeout, |idle| idle.is_empty())
+            .expect("session pool mutex poisoned");
+        match idle.pop() {
+            Some(item) => Ok(Checkout {
+                pool: self,
+                item: Some(item),
+            }),
+            None => {
+                debug_assert!(wait.timed_out());
+                Err(TtsError::Overloaded(format!(
+                    "all {} inference sessions stayed busy for {} ms",
+                    self.replicas,
+                    self.timeout.as_millis()
+                ))
+                .into())
+            }
+        }
+    }
+
+    /// The first replica, for metadata that is the same across all of them.
+    /// Blocks like [`Self::checkout`] but without a deadline.
+    pub fn with_first<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let idle = self
+            .returned
+            .wait_while(self.lock(), |idle| idle.is_empty())
+            .expect("session pool mutex poisoned");
+        f(&idle[0])
+    }
+
+    pub fn status(&self) -> PoolStatus {
+        PoolStatus {
+            replicas: self.replicas,
+            idle: self.lock().len(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<T>> {
+        self.idle.lock().expect("session pool mutex poisoned")
+    }
+}
+
+impl<T: Send + Sync + 'static> IdlePool<T> {
+    /// Starts out with `pool`; `make` builds it again after a release.
+    /// Without an idle timeout it is never released. `name` is used in logs.
+    pub fn new(
+        name: &'static str,
+        pool: SessionPool<T>,
+        idle_timeout: Option<Duration>,
+        make: impl Fn() -> Result<SessionPool<T>> + Send + Sync + 'static,
+    ) -> Result<Arc<Self>> {
+        let idle = Arc::new(Self {
+            make: Box::new(make),
+            state: Mutex::new(IdleState {
+                pool: Some(Arc::new(pool)),
+                last_used: Instant::now(),
+            }),
+            loading: Mutex::new(()),
+            idle_timeout,
+            name,
+        });
+        if let Some(timeout) = idle_timeout {
+            let weak = Arc::downgrade(&idle);
+            thread::Builder::new()
+                .name(format!("{name}-idle"))
+                .spawn(move || release_when_idle(weak, timeout))
+                .context("failed to spawn idle session reaper")?;
+        }
+        Ok(idle)
+    }
+
+    /// The session pool, loading it first if it was released. Holding the
+    /// returned pool keeps it from being released. The state lock is not
+    /// held while loading, so [`Self::status`] and the reaper do not wait
+    /// for it.
+    pub fn get(&self) -> Result<Arc<SessionPool<T>>> {
+        if let Some(pool) = self.current() {
+            return Ok(pool);
+        }
+        let _loading = self.loading.lock().expect("idle pool mutex poisoned");
+        // Another caller may have loaded it while this one waited.
+        if let Some(pool) = self.current() {
+            return Ok(pool);
+        }
+        info!("reloading idle {} sessions", self.name);
+        let pool = Arc::new((self.make)()?);
+        let mut state = self.lock();
+        state.last_used = Instant::now();
+        state.pool = Some(pool.clone());
+        Ok(pool)
+    }
+
+    /// The loaded pool, if any, marking it as used.
+    fn current(&self) -> Option<Arc<SessionPool<T>>> {
+        let mut state = self.lock();
+        state.last_used = Instant::now();
+        state.pool.clone()
+    }
+
+    /// `None` while the sessions are released.
+    pub fn status(&self) -> Option<PoolStatus> {
+        self.lock().pool.as_ref().map(|pool| pool.status())
+    }
+
+    /// Drops the sessions if nobody has asked for them within the idle
+    /// timeout and nobody still holds them. Returns whether it did.
+    fn release_if_idle(&self) -> bool {
+        let Some(timeout) = self.idle_timeout else {
+            return false;
+        };
+        let mut state = self.lock();
+        let unused = state
+            .pool
+            .as_ref()
+            .is_some_and(|pool| Arc::strong_count(pool) == 1);
+        if unused && state.last_used.elapsed() >= timeout {
+            state.pool = None;
+            info!(
+                "released {} sessions after {} s idle",
+                self.name,
+                timeout.as_secs()
+            );
+            return true;
+        }
+        false
+    }
+
+    fn lock(&self) -> MutexGuard<'_, IdleState<T>> {
+        self.state.lock().expect("idle pool mutex poisoned")
+    }
+}
+
+/// Runs until the pool is dropped, checking a few times per timeout.
+fn release_when_idle<T: Send + Sync + 'static>(pool: Weak<IdlePool<T>>, timeout: Duration) {
+    let interval = (timeout / 4).clamp(Duration::from_millis(100), Duration::from_secs(60));
+    loop {
+        thread::sleep(interval);
+        let Some(pool) = pool.upgrade() else {
+            return;
+        };
+        pool.release_if_idle();
+    }
+}
+
+impl<T> Deref for Checkout<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.item
+            .as_ref()
+            .expect("checked out item already returned")
+    }
+}
+
+impl<T> Drop for Checkout<'_, T> {
+    fn drop(&mut self) {
+        if let Some(item) = self.item.take() {
+            self.pool.lock().push(item);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkouts_are_exclusive_and_time_out_when_exhausted() {
+        let pool = SessionPool::new(vec![1, 2], Duration::from_millis(20)).unwrap();
+        let first = pool.checkout().unwrap();
+        let second = pool.checkout().unwrap();
+        assert_ne!(*first, *second);
+        assert_eq!(pool.status().idle, 0);
+
+        let err = pool.checkout().err().unwrap();
+        assert_eq!(err.downcast_ref::<TtsError>().unwrap().status(), 503);
+
+        drop(first);
+        assert_eq!(pool.status().idle, 1);
+        assert!(pool.checkout().is_ok());
+    }
+
+    #[test]
+    fn waiting_checkout_gets_returned_replica() {
+        let pool = SessionPool::new(vec![()], Duration::from_secs(5)).unwrap();
+        let held = pool.checkout().unwrap();
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| pool.checkout().map(|_| ()));
+            std::thread::sleep(Duration::from_millis(20));
+            drop(held);
+            waiter.join().unwrap().unwrap();
+        });
+    }
+
+    #[test]
+    fn idle_pool_is_released_and_rebuilt_on_demand() {
+        let builds = Arc::new(Mutex::new(0));
+        let counter = builds.clone();
+        let make = move || {
+            *counter.lock().unwrap() += 1;
+            SessionPool::new(vec![()], Duration::from_millis(10))
+        };
+        let pool = make().unwrap();
+        let idle = IdlePool::new("test", pool, Some(Duration::from_millis(10)), make).unwrap();
+
+        let held = idle.get().unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!idle.release_if_idle(), "released while checked out");
+        drop(held);
+        std::thread::sleep(Duration::from_millis(20));
+        idle.release_if_idle();
+        assert!(idle.status().is_none());
+
+        idle.get().unwrap().checkout().unwrap();
+        assert_eq!(*builds.lock().unwrap(), 2);
+        assert_eq!(idle.status().unwrap().replicas, 1);
+    }
+
+    #[test]
+    fn idle_pool_is_rebuilt_once_without_blocking_status() {
+        let builds = Arc::new(Mutex::new(0));
+        let counter = builds.clone();
+        let make = move || {
+            *counter.lock().unwrap() += 1;
+            std::thread::sleep(Duration::from_millis(100));
+            SessionPool::new(vec![()], Duration::from_millis(10))
+        };
+        let pool = SessionPool::new(vec![()], Duration::from_millis(10)).unwrap();
+        let idle = IdlePool::new("test", pool, None, make).unwrap();
+        idle.lock().pool = None;
+
+        std::thread::scope(|scope| {
+            let loaders: Vec<_> = (0..2)
+                .map(|_| scope.spawn(|| idle.get().map(|_| ())))
+                .collect();
+            std::thread::sleep(Duration::from_millis(20));
+            let started = Instant::now();
+            assert!(idle.status().is_none());
+            assert!(started.elapsed() < Duration::from_millis(50));
+            for loader in loaders {
+                loader.join().unwrap().unwrap();
+            }
+        });
+        assert_eq!(*builds.lock().unwrap(), 1);
+        assert!(idle.status().is_some());
+    }
+}
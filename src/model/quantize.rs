@@ -0,0 +1,564 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result, bail};
+use tracing::debug;
+
+// Field numbers from onnx.proto.
+const MODEL_GRAPH: u32 = 7;
+const MODEL_OPSET_IMPORT: u32 = 8;
+const OPSET_DOMAIN: u32 = 1;
+const OPSET_VERSION: u32 = 2;
+const GRAPH_NODE: u32 = 1;
+const GRAPH_INITIALIZER: u32 = 5;
+const GRAPH_INPUT: u32 = 11;
+const GRAPH_OUTPUT: u32 = 12;
+const NODE_INPUT: u32 = 1;
+const NODE_OUTPUT: u32 = 2;
+const NODE_NAME: u32 = 3;
+const NODE_OP_TYPE: u32 = 4;
+const NODE_DOMAIN: u32 = 7;
+const TENSOR_DIMS: u32 = 1;
+const TENSOR_DATA_TYPE: u32 = 2;
+const TENSOR_FLOAT_DATA: u32 = 4;
+const TENSOR_NAME: u32 = 8;
+const TENSOR_RAW_DATA: u32 = 9;
+const TENSOR_EXTERNAL_DATA: u32 = 13;
+const VALUE_INFO_NAME: u32 = 1;
+
+const FLOAT: u64 = 1;
+const INT8: u64 = 3;
+
+const MS_DOMAIN: &str = "com.microsoft";
+
+#[derive(Debug, Clone, Copy)]
+pub struct QuantizeSummary {
+    /// `MatMul` nodes rewritten to `DynamicQuantizeMatMul`.
+    pub quantized: usize,
+    /// `MatMul` nodes left in fp32 because their weight is not a constant
+    /// 2-D fp32 matrix stored in the model file.
+    pub skipped: usize,
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+}
+
+/// Writes an int8 dynamically quantised copy of the fp32 model at `input` to
+/// `output`, like ONNX Runtime's `quantize_dynamic`: every `MatMul` with a
+/// constant weight becomes a `com.microsoft` `DynamicQuantizeMatMul` whose
+/// weight is quantised symmetrically per output column, while activations
+/// are quantised at run time. Other nodes are copied unchanged.
+pub fn quantize_dynamic(input: &Path, output: &Path) -> Result<QuantizeSummary> {
+    let bytes = fs::read(input).with_context(|| format!("failed to read {}", input.display()))?;
+    let mut model = Message::decode(&bytes)
+        .with_context(|| format!("{} is not an ONNX model", input.display()))?;
+    let graph = model
+        .bytes(MODEL_GRAPH)
+        .with_context(|| format!("{} has no graph", input.display()))?;
+    let (graph, quantized, skipped) = quantize_graph(&Message::decode(graph)?)?;
+    if quantized == 0 {
+        bail!(
+            "{} has no MatMul with a constant fp32 weight to quantise; pass the fp32 model",
+            input.display()
+        );
+    }
+
+    let mut encoded = Vec::with_capacity(bytes.len() / 3);
+    graph.encode(&mut encoded)?;
+    model.replace(MODEL_GRAPH, Value::Bytes(Cow::Owned(encoded)));
+    if !model
+        .messages(MODEL_OPSET_IMPORT)?
+        .iter()
+        .any(|opset| opset.string(OPSET_DOMAIN) == Some(MS_DOMAIN))
+    {
+        let mut opset = Message::default();
+        opset.push_str(OPSET_DOMAIN, MS_DOMAIN);
+        opset.push(OPSET_VERSION, Value::Varint(1));
+        model.push_message(MODEL_OPSET_IMPORT, &opset)?;
+    }
+
+    let partial = output.with_extension("onnx.partial");
+    let file = File::create(&partial)
+        .with_context(|| format!("failed to create {}", partial.display()))?;
+    let mut writer = BufWriter::new(file);
+    model.encode(&mut writer)?;
+    writer
+        .into_inner()
+        .map_err(|err| err.into_error())
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("failed to write {}", partial.display()))?;
+    fs::rename(&partial, output)
+        .with_context(|| format!("failed to move {} into place", partial.display()))?;
+
+    Ok(QuantizeSummary {
+        quantized,
+        skipped,
+        input_bytes: bytes.len() as u64,
+        output_bytes: fs::metadata(output)?.len(),
+    })
+}
+
+fn quantize_graph<'a>(graph: &Message<'a>) -> Result<(Message<'a>, usize, usize)> {
+    let mut weights = HashMap::new();
+    for tensor in graph.messages(GRAPH_INITIALIZER)? {
+        if let Some(name) = tensor.string(TENSOR_NAME) {
+            weights.insert(name.to_string(), tensor);
+        }
+    }
+
+    let mut nodes = Vec::new();
+    let mut added = Vec::new();
+    let mut quantized_weights = HashSet::new();
+    let (mut quantized, mut skipped) = (0, 0);
+    for node in graph.messages(GRAPH_NODE)? {
+        let inputs = node.strings(NODE_INPUT);
+        let is_matmul = node.string(NODE_OP_TYPE) == Some("MatMul")
+            && node.string(NODE_DOMAIN).unwrap_or_default().is_empty()
+            && inputs.len() == 2;
+        if !is_matmul {
+            nodes.push(node);
+            continue;
+        }
+        let weight = inputs[1];
+        let Some(matrix) = weights.get(weight).map(float_matrix).transpose()?.flatten() else {
+            if weights.contains_key(weight) {
+                skipped += 1;
+            }
+            nodes.push(node);
+            continue;
+        };
+
+        let (q_name, scale_name, zero_name) = (
+            format!("{weight}_quantized"),
+            format!("{weight}_scale"),
+            format!("{weight}_zero_point"),
+        );
+        if quantized_weights.insert(weight.to_string()) {
+            let (values, scales) = matrix.quantize();
+            let cols = matrix.cols as i64;
+            added.push(tensor(&q_name, INT8, &[matrix.rows as i64, cols], values));
+            added.push(tensor(
+                &scale_name,
+                FLOAT,
+                &[cols],
+                scales
+                    .iter()
+                    .flat_map(|scale| scale.to_le_bytes())
+                    .collect(),
+            ));
+            added.push(tensor(&zero_name, INT8, &[cols], vec![0; matrix.cols]));
+        }
+
+        let mut rewritten = Message::default();
+        for name in [inputs[0], &q_name, &scale_name, &zero_name] {
+            rewritten.push_str(NODE_INPUT, name);
+        }
+        for name in node.strings(NODE_OUTPUT) {
+            rewritten.push_str(NODE_OUTPUT, name);
+        }
+        if let Some(name) = node.string(NODE_NAME) {
+            rewritten.push_str(NODE_NAME, &format!("{name}_quant"));
+        }
+        rewritten.push_str(NODE_OP_TYPE, "DynamicQuantizeMatMul");
+        rewritten.push_str(NODE_DOMAIN, MS_DOMAIN);
+        nodes.push(rewritten);
+        quantized += 1;
+    }
+
+    // The fp32 originals go unless something else still reads them.
+    let mut used: HashSet<&str> = nodes
+        .iter()
+        .flat_map(|node| node.strings(NODE_INPUT))
+        .collect();
+    let outputs = graph.messages(GRAPH_OUTPUT)?;
+    used.extend(
+        outputs
+            .iter()
+            .filter_map(|info| info.string(VALUE_INFO_NAME)),
+    );
+    let dropped: HashSet<&str> = quantized_weights
+        .iter()
+        .map(String::as_str)
+        .filter(|name| !used.contains(name))
+        .collect();
+    debug!(
+        "quantised {quantized} MatMul nodes, dropping {} fp32 weights",
+        dropped.len()
+    );
+
+    let mut result = Message::default();
+    let mut nodes = nodes.into_iter();
+    for (tag, value) in &graph.fields {
+        match *tag {
+            GRAPH_NODE => {
+                let node = nodes.next().expect("one node per node field");
+                result.push_message(GRAPH_NODE, &node)?;
+            }
+            GRAPH_INITIALIZER | GRAPH_INPUT => {
+                let name_tag = if *tag == GRAPH_INITIALIZER {
+                    TENSOR_NAME
+                } else {
+                    VALUE_INFO_NAME
+                };
+                let Value::Bytes(bytes) = value else {
+                    bail!("malformed graph field {tag}");
+                };
+                let name = Message::decode(bytes)?.string(name_tag).map(str::to_string);
+                if !name.is_some_and(|name| dropped.contains(name.as_str())) {
+                    result.push(*tag, value.clone());
+                }
+            }
+            _ => result.push(*tag, value.clone()),
+        }
+    }
+    for tensor in &added {
+        result.push_message(GRAPH_INITIALIZER, tensor)?;
+    }
+    Ok((result, quantized, skipped))
+}
+
+struct FloatMatrix {
+    rows: usize,
+    cols: usize,
+    data: Vec<f32>,
+}
+
+impl FloatMatrix {
+    /// Symmetric int8 quantisation with one scale per column.
+    fn quantize(&self) -> (Vec<u8>, Vec<f32>) {
+        let mut scales = vec![0.0f32; self.cols];
+        for row in self.data.chunks_exact(self.cols) {
+            for (scale, value) in scales.iter_mut().zip(row) {
+                *scale = scale.max(value.abs());
+            }
+        }
+        for scale in &mut scales {
+            *scale = if *scale > 0.0 { *scale / 127.0 } else { 1.0 };
+        }
+        let values = self
+            .data
+            .chunks_exact(self.cols)
+            .flat_map(|row| {
+                row.iter()
+                    .zip(&scales)
+                    .map(|(value, scale)| (value / scale).round().clamp(-127.0, 127.0) as i8 as u8)
+            })
+            .collect();
+        (values, scales)
+    }
+}
+
+/// Reads a constant 2-D fp32 tensor; `None` for anything else, including
+/// weights kept in external data files.
+fn float_matrix(tensor: &Message) -> Result<Option<FloatMatrix>> {
+    if tensor.varint(TENSOR_DATA_TYPE) != Some(FLOAT) || tensor.has(TENSOR_EXTERNAL_DATA) {
+        return Ok(None);
+    }
+    let dims = tensor.varints(TENSOR_DIMS)?;
+    let [rows, cols] = dims[..] else {
+        return Ok(None);
+    };
+    let (rows, cols) = (rows as usize, cols as usize);
+    let data: Vec<f32> = match tensor.bytes(TENSOR_RAW_DATA) {
+        Some(raw) => raw
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect(),
+        None => tensor.floats(TENSOR_FLOAT_DATA)?,
+    };
+    if rows == 0 || cols == 0 || data.len() != rows * cols {
+        return Ok(None);
+    }
+    Ok(Some(FloatMatrix { rows, cols, data }))
+}
+
+fn tensor(name: &str, data_type: u64, dims: &[i64], raw: Vec<u8>) -> Message<'static> {
+    let mut tensor = Message::default();
+    for &dim in dims {
+        tensor.push(TENSOR_DIMS, Value::Varint(dim as u64));
+    }
+    tensor.push(TENSOR_DATA_TYPE, Value::Varint(data_type));
+    tensor.push_str(TENSOR_NAME, name);
+    tensor.push(TENSOR_RAW_DATA, Value::Bytes(Cow::Owned(raw)));
+    tensor
+}
+
+/// Protobuf message kept as its raw fields, so fields this module does not
+/// know about survive a decode/encode round trip untouched.
+#[derive(Debug, Clone, Default)]
+struct Message<'a> {
+    fields: Vec<(u32, Value<'a>)>,
+}
+
+#[derive(Debug, Clone)]
+enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(Cow<'a, [u8]>),
+    Fixed32(u32),
+}
+
+impl<'a> Message<'a> {
+    fn decode(mut buf: &'a [u8]) -> Result<Self> {
+        let mut fields = Vec::new();
+        while !buf.is_empty() {
+            let key = read_varint(&mut buf)?;
+            let value = match key & 7 {
+                0 => Value::Varint(read_varint(&mut buf)?),
+                1 => Value::Fixed64(u64::from_le_bytes(take(&mut buf, 8)?.try_into()?)),
+                2 => {
+                    let len = read_varint(&mut buf)? as usize;
+                    Value::Bytes(Cow::Borrowed(take(&mut buf, len)?))
+                }
+                5 => Value::Fixed32(u32::from_le_bytes(take(&mut buf, 4)?.try_into()?)),
+                wire => bail!("unsupported protobuf wire type {wire}"),
+            };
+            fields.push(((key >> 3) as u32, value));
+        }
+        Ok(Self { fields })
+    }
+
+    fn encode(&self, out: &mut impl Write) -> Result<()> {
+        for (tag, value) in &self.fields {
+            let tag = u64::from(*tag) << 3;
+            match value {
+                Value::Varint(value) => {
+                    write_varint(out, tag)?;
+                    write_varint(out, *value)?;
+                }
+                Value::Fixed64(value) => {
+                    write_varint(out, tag | 1)?;
+                    out.write_all(&value.to_le_bytes())?;
+                }
+                Value::Bytes(bytes) => {
+                    write_varint(out, tag | 2)?;
+                    write_varint(out, bytes.len() as u64)?;
+                    out.write_all(bytes)?;
+                }
+                Value::Fixed32(value) => {
+                    write_varint(out, tag | 5)?;
+                    out.write_all(&value.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn has(&self, tag: u32) -> bool {
+        self.fields.iter().any(|(field, _)| *field == tag)
+    }
+
+    fn bytes(&self, tag: u32) -> Option<&[u8]> {
+        self.fields
+            .iter()
+            .rev()
+            .find_map(|(field, value)| match value {
+                Value::Bytes(bytes) if *field == tag => Some(bytes.as_ref()),
+                _ => None,
+            })
+    }
+
+    fn string(&self, tag: u32) -> Option<&str> {
+        self.bytes(tag)
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    fn strings(&self, tag: u32) -> Vec<&str> {
+        self.fields
+            .iter()
+            .filter_map(|(field, value)| match value {
+                Value::Bytes(bytes) if *field == tag => std::str::from_utf8(bytes).ok(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn varint(&self, tag: u32) -> Option<u64> {
+        self.fields
+            .iter()
+            .rev()
+            .find_map(|(field, value)| match value {
+                Value::Varint(value) if *field == tag => Some(*value),
+                _ => None,
+            })
+    }
+
+    /// Repeated integer field, packed or not.
+    fn varints(&self, tag: u32) -> Result<Vec<u64>> {
+        let mut values = Vec::new();
+        for (field, value) in &self.fields {
+            match value {
+                _ if *field != tag => {}
+                Value::Varint(value) => values.push(*value),
+                Value::Bytes(bytes) => {
+                    let mut buf = bytes.as_ref();
+                    while !buf.is_empty() {
+                        values.push(read_varint(&mut buf)?);
+                    }
+                }
+                _ => bail!("field {tag} is not an integer"),
+            }
+        }
+        Ok(values)
+    }
+
+    /// Repeated float field, packed or not.
+    fn floats(&self, tag: u32) -> Result<Vec<f32>> {
+        let mut values = Vec::new();
+        for (field, value) in &self.fields {
+            match value {
+                _ if *field != tag => {}
+                Value::Fixed32(bits) => values.push(f32::from_bits(*bits)),
+                Value::Bytes(bytes) => values.extend(
+                    bytes
+                        .chunks_exact(4)
+                        .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap())),
+                ),
+                _ => bail!("field {tag} is not a float"),
+            }
+        }
+        Ok(values)
+    }
+
+    fn messages(&self, tag: u32) -> Result<Vec<Message<'_>>> {
+        self.fields
+            .iter()
+            .filter(|(field, _)| *field == tag)
+            .map(|(_, value)| match value {
+                Value::Bytes(bytes) => Message::decode(bytes),
+                _ => bail!("field {tag} is not a message"),
+            })
+            .collect()
+    }
+
+    fn push(&mut self, tag: u32, value: Value<'a>) {
+        self.fields.push((tag, value));
+    }
+
+    fn push_str(&mut self, tag: u32, value: &str) {
+        self.push(tag, Value::Bytes(Cow::Owned(value.as_bytes().to_vec())));
+    }
+
+    fn push_message(&mut self, tag: u32, message: &Message) -> Result<()> {
+        let mut encoded = Vec::new();
+        message.encode(&mut encoded)?;
+        self.push(tag, Value::Bytes(Cow::Owned(encoded)));
+        Ok(())
+    }
+
+    fn replace(&mut self, tag: u32, value: Value<'a>) {
+        self.fields.retain(|(field, _)| *field != tag);
+        self.push(tag, value);
+    }
+}
+
+fn read_varint(buf: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let Some((&byte, rest)) = buf.split_first() else {
+            bail!("truncated protobuf varint");
+        };
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("protobuf varint is too long")
+}
+
+fn write_varint(out: &mut impl Write, mut value: u64) -> Result<()> {
+    while value >= 0x80 {
+        out.write_all(&[(value as u8) | 0x80])?;
+        value >>= 7;
+    }
+    out.write_all(&[value as u8])?;
+    Ok(())
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        bail!("truncated protobuf field");
+    }
+    let (head, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn rewrites_constant_matmuls_and_keeps_other_fields() {
+        let weight: Vec<f32> = vec![0.5, -1.0, 0.25, 2.0, -0.5, 0.0];
+        let mut graph = Message::default();
+        let mut node = Message::default();
+        for (tag, value) in [
+            (NODE_INPUT, "x"),
+            (NODE_INPUT, "w"),
+            (NODE_OUTPUT, "y"),
+            (NODE_NAME, "proj"),
+            (NODE_OP_TYPE, "MatMul"),
+        ] {
+            node.push_str(tag, value);
+        }
+        graph.push_message(GRAPH_NODE, &node).unwrap();
+        graph.push_str(2, "tiny");
+        let raw = weight.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let initializer = tensor("w", FLOAT, &[3, 2], raw);
+        graph.push_message(GRAPH_INITIALIZER, &initializer).unwrap();
+        let mut model = Message::default();
+        model.push(1, Value::Varint(8));
+        model.push_message(MODEL_GRAPH, &graph).unwrap();
+
+        let dir = TempDir::new("quantize");
+        let (input, output) = (dir.join("model.onnx"), dir.join("model_int8.onnx"));
+        let mut bytes = Vec::new();
+        model.encode(&mut bytes).unwrap();
+        fs::write(&input, bytes).unwrap();
+
+        let summary = quantize_dynamic(&input, &output).unwrap();
+        assert_eq!((summary.quantized, summary.skipped), (1, 0));
+
+        let bytes = fs::read(&output).unwrap();
+        let model = Message::decode(&bytes).unwrap();
+        assert_eq!(model.varint(1), Some(8));
+        let opsets = model.messages(MODEL_OPSET_IMPORT).unwrap();
+        assert_eq!(opsets[0].string(OPSET_DOMAIN), Some(MS_DOMAIN));
+        let graph = Message::decode(model.bytes(MODEL_GRAPH).unwrap()).unwrap();
+        assert_eq!(graph.string(2), Some("tiny"));
+        let node = &graph.messages(GRAPH_NODE).unwrap()[0];
+        assert_eq!(node.string(NODE_OP_TYPE), Some("DynamicQuantizeMatMul"));
+        assert_eq!(
+            node.strings(NODE_INPUT),
+            ["x", "w_quantized", "w_scale", "w_zero_point"]
+        );
+
+        let tensors = graph.messages(GRAPH_INITIALIZER).unwrap();
+        let names: Vec<_> = tensors
+            .iter()
+            .filter_map(|t| t.string(TENSOR_NAME))
+            .collect();
+        assert_eq!(names, ["w_quantized", "w_scale", "w_zero_point"]);
+        let values = tensors[0].bytes(TENSOR_RAW_DATA).unwrap();
+        let scales: Vec<f32> = tensors[1]
+            .bytes(TENSOR_RAW_DATA)
+            .unwrap()
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        for (index, (&q, &original)) in values.iter().zip(&weight).enumerate() {
+            let restored = f32::from(q as i8) * scales[index % 2];
+            assert!(
+                (restored - original).abs() < 0.01,
+                "{restored} vs {original}"
+            );
+        }
+    }
+}
@@ -0,0 +1,447 @@
+use std::{
+    ffi::{CStr, CString, c_int},
+    ops::Deref,
+    path::{Path, PathBuf},
+    ptr,
+    sync::Arc,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use ort::{
+    AllocatorType, ExecutionProvider, GraphOptimizationLevel, InMemorySession, SessionBuilder,
+    environment::Environment,
+    execution_providers::{
+        CUDAExecutionProviderOptions, CoreMLExecutionProviderOptions,
+        OpenVINOExecutionProviderOptions, ROCmExecutionProviderOptions,
+        TensorRTExecutionProviderOptions,
+    },
+    session::Session,
+    sys,
+};
+use tracing::{info, warn};
+
+use super::optimize;
+use crate::settings::{DeviceOptions, ExecutionMode, Provider, RuntimeSettings, SessionSettings};
+
+pub(super) const CPU_PROVIDER: &str = "CPUExecutionProvider";
+
+/// An ONNX Runtime session, together with the mapping of the model file it
+/// was created from when the model is memory-mapped. A mapped model is in
+/// ORT format, whose weights ONNX Runtime uses in place, so they stay in the
+/// page cache and are shared by every replica and process mapping the file.
+pub enum ModelSession {
+    Loaded(Session),
+    Mapped {
+        // Declared before the mapping so it is dropped first.
+        session: InMemorySession<'static>,
+        _model: MappedFile,
+    },
+}
+
+impl Deref for ModelSession {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        match self {
+            Self::Loaded(session) => session,
+            Self::Mapped { session, .. } => session,
+        }
+    }
+}
+
+/// Loads `model_path` with the first of `runtime.execution_providers` that
+/// initialises, falling back to the CPU. `role` names the model in logs.
+/// Returns the ONNX Runtime name of the provider the session is bound to.
+pub(crate) fn new_session(
+    env: &Arc<Environment>,
+    model_path: &Path,
+    runtime: &RuntimeSettings,
+    settings: &SessionSettings,
+    role: &str,
+) -> Result<(ModelSession, &'static str)> {
+    for &provider in &runtime.execution_providers {
+        if provider == Provider::Cpu {
+            break;
+        }
+        match accelerated_session(env, model_path, runtime, settings, provider) {
+            Ok(bound) => {
+                info!("{role} session bound to {}", bound.1);
+                return Ok(bound);
+            }
+            Err(err) => warn!(
+                "{role}: {} execution provider unavailable, trying the next one: {err:#}",
+                provider.as_str()
+            ),
+        }
+    }
+
+    let (builder, model_file) = session_builder(env, model_path, settings, CPU_PROVIDER)?;
+    let session = load(builder, &model_file, settings.memory_map)
+        .with_context(|| format!("failed to load ONNX model from {}", model_path.display()))?;
+    info!("{role} session bound to {CPU_PROVIDER}");
+    Ok((session, CPU_PROVIDER))
+}
+
+fn accelerated_session(
+    env: &Arc<Environment>,
+    model_path: &Path,
+    runtime: &RuntimeSettings,
+    settings: &SessionSettings,
+    provider: Provider,
+) -> Result<(ModelSession, &'static str)> {
+    let Some(execution_provider) = execution_provider(provider, runtime) else {
+        bail!("this build does not include {} support", provider.as_str());
+    };
+    let name = execution_provider.as_str();
+    if !execution_provider.is_available() {
+        bail!("the loaded ONNX Runtime library does not provide {name}");
+    }
+    // `ort` only logs a provider that fails to register and carries on with
+    // the CPU, so register it on throwaway options first to see the error.
+    probe(provider, runtime).with_context(|| format!("failed to initialise {name}"))?;
+
+    let (builder, model_file) = session_builder(env, model_path, settings, name)?;
+    let builder = builder.with_execution_providers([execution_provider])?;
+    let session = load(builder, &model_file, settings.memory_map).with_context(|| {
+        format!(
+            "failed to load ONNX model from {} with {name}",
+            model_path.display()
+        )
+    })?;
+    Ok((session, name))
+}
+
+fn load(builder: SessionBuilder, model_file: &Path, memory_map: bool) -> Result<ModelSession> {
+    if !memory_map {
+        return Ok(ModelSession::Loaded(
+            builder.with_model_from_file(model_file)?,
+        ));
+    }
+    let model = MappedFile::open(model_file)?;
+    if !is_ort_format(model.bytes()) {
+        bail!(
+            "memory_map needs a model in ORT format, but {} is not one; convert it with \
+             `python -m onnxruntime.tools.convert_onnx_models_to_ort` and load the .ort file",
+            model_file.display()
+        );
+    }
+    // SAFETY: the session borrows the mapping, which lives in the same
+    // `ModelSession` and is dropped after it.
+    let bytes: &'static [u8] = unsafe { &*ptr::from_ref(model.bytes()) };
+    let session = builder.with_model_from_memory(bytes)?;
+    Ok(ModelSession::Mapped {
+        session,
+        _model: model,
+    })
+}
+
+/// Whether `bytes` start like an ORT-format model: a flatbuffer whose file
+/// identifier is `ORTM`. ONNX Runtime only uses the bytes of those in place;
+/// `.onnx` protobufs are parsed into copies.
+fn is_ort_format(bytes: &[u8]) -> bool {
+    bytes.get(4..8) == Some(b"ORTM".as_slice())
+}
+
+/// A read-only, private mapping of a whole file, so the model is paged in
+/// from the page cache on demand instead of being copied into the heap.
+pub struct MappedFile {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only and only unmapped on drop.
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+    #[cfg(unix)]
+    fn open(path: &Path) -> Result<Self> {
+        use std::{fs::File, os::fd::AsRawFd};
+
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            bail!("{} is empty", path.display());
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("failed to memory-map {}", path.display()));
+        }
+        Ok(Self { ptr, len })
+    }
+
+    #[cfg(not(unix))]
+    fn open(path: &Path) -> Result<Self> {
+        bail!(
+            "memory-mapping {} is only supported on Unix",
+            path.display()
+        )
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.cast(), self.len) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+fn execution_provider(provider: Provider, runtime: &RuntimeSettings) -> Option<ExecutionProvider> {
+    if !provider.is_compiled() {
+        return None;
+    }
+    let memory_limit = |device: &DeviceOptions| {
+        device
+            .memory_limit_mb
+            .map_or(usize::MAX, |mb| mb.saturating_mul(1 << 20))
+    };
+    Some(match provider {
+        Provider::Cpu | Provider::Xnnpack => return None,
+        Provider::Cuda => ExecutionProvider::CUDA(CUDAExecutionProviderOptions {
+            device_id: runtime.cuda.device_id,
+            gpu_mem_limit: memory_limit(&runtime.cuda),
+            ..Default::default()
+        }),
+        Provider::Tensorrt => ExecutionProvider::TensorRT(TensorRTExecutionProviderOptions {
+            device_id: runtime.tensorrt.device_id,
+            max_workspace_size: memory_limit(&runtime.tensorrt).min(u32::MAX as usize) as u32,
+            ..Default::default()
+        }),
+        Provider::Rocm => ExecutionProvider::ROCm(ROCmExecutionProviderOptions {
+            device_id: runtime.rocm.device_id as i32,
+            gpu_mem_limit: memory_limit(&runtime.rocm),
+            do_copy_in_default_stream: true,
+            ..Default::default()
+        }),
+        Provider::Openvino => ExecutionProvider::OpenVINO(OpenVINOExecutionProviderOptions {
+            device_type: runtime.openvino.device_type.clone(),
+            ..Default::default()
+        }),
+        Provider::Coreml => ExecutionProvider::CoreML(CoreMLExecutionProviderOptions::default()),
+    })
+}
+
+/// Registers the provider for the configured device, with default options
+/// otherwise, on a session that is never created, which loads its shared
+/// library and surfaces missing drivers.
+/// CoreML is registered through a separately linked symbol and not probed.
+fn probe(provider: Provider, runtime: &RuntimeSettings) -> Result<()> {
+    let api = ort::ort();
+    unsafe {
+        let options = SessionOptions::new(&api)?;
+        match provider {
+            Provider::Cuda => {
+                let mut cuda = ptr::null_mut();
+                check(
+                    &api,
+                    function(api.CreateCUDAProviderOptions, "CreateCUDAProviderOptions")?(
+                        &mut cuda,
+                    ),
+                )?;
+                let device_id = CString::new(runtime.cuda.device_id.to_string())?;
+                let keys = [c"device_id".as_ptr()];
+                let values = [device_id.as_ptr()];
+                let mut status = function(
+                    api.UpdateCUDAProviderOptions,
+                    "UpdateCUDAProviderOptions",
+                )?(
+                    cuda, keys.as_ptr(), values.as_ptr(), keys.len()
+                );
+                if status.is_null() {
+                    status = function(
+                        api.SessionOptionsAppendExecutionProvider_CUDA_V2,
+                        "SessionOptionsAppendExecutionProvider_CUDA_V2",
+                    )?(options.ptr, cuda);
+                }
+                function(api.ReleaseCUDAProviderOptions, "ReleaseCUDAProviderOptions")?(cuda);
+                check(&api, status)?;
+            }
+            Provider::Tensorrt => {
+                let mut tensorrt = ptr::null_mut();
+                check(
+                    &api,
+                    function(
+                        api.CreateTensorRTProviderOptions,
+                        "CreateTensorRTProviderOptions",
+                    )?(&mut tensorrt),
+                )?;
+                let device_id = CString::new(runtime.tensorrt.device_id.to_string())?;
+                let keys = [c"device_id".as_ptr()];
+                let values = [device_id.as_ptr()];
+                let mut status =
+                    function(
+                        api.UpdateTensorRTProviderOptions,
+                        "UpdateTensorRTProviderOptions",
+                    )?(tensorrt, keys.as_ptr(), values.as_ptr(), keys.len());
+                if status.is_null() {
+                    status = function(
+                        api.SessionOptionsAppendExecutionProvider_TensorRT_V2,
+                        "SessionOptionsAppendExecutionProvider_TensorRT_V2",
+                    )?(options.ptr, tensorrt);
+                }
+                function(
+                    api.ReleaseTensorRTProviderOptions,
+                    "ReleaseTensorRTProviderOptions",
+                )?(tensorrt);
+                check(&api, status)?;
+            }
+            Provider::Rocm => {
+                let mut rocm: sys::OrtROCMProviderOptions = std::mem::zeroed();
+                rocm.device_id = runtime.rocm.device_id as c_int;
+                rocm.gpu_mem_limit = usize::MAX as _;
+                rocm.do_copy_in_default_stream = 1;
+                check(
+                    &api,
+                    function(
+                        api.SessionOptionsAppendExecutionProvider_ROCM,
+                        "SessionOptionsAppendExecutionProvider_ROCM",
+                    )?(options.ptr, &rocm),
+                )?;
+            }
+            Provider::Openvino => {
+                let openvino: sys::OrtOpenVINOProviderOptions = std::mem::zeroed();
+                check(
+                    &api,
+                    function(
+                        api.SessionOptionsAppendExecutionProvider_OpenVINO,
+                        "SessionOptionsAppendExecutionProvider_OpenVINO",
+                    )?(options.ptr, &openvino),
+                )?;
+            }
+            Provider::Cpu | Provider::Coreml | Provider::Xnnpack => {}
+        }
+    }
+    Ok(())
+}
+
+/// Applies `settings` to a new builder and picks the file to load: the saved
+/// optimised graph for `provider` when `optimized_model_dir` is set, which
+/// is then loaded without optimising again, or `model_path` itself.
+pub(crate) fn session_builder(
+    env: &Arc<Environment>,
+    model_path: &Path,
+    settings: &SessionSettings,
+    provider: &str,
+) -> Result<(SessionBuilder, PathBuf)> {
+    let (model_file, level) = match &settings.optimized_model_dir {
+        Some(dir) => (
+            optimize::optimized_model(env, model_path, dir, settings.optimization_level, provider)?,
+            GraphOptimizationLevel::Disable,
+        ),
+        None => (model_path.to_path_buf(), settings.optimization_level.into()),
+    };
+    let allocator = if settings.memory_arena {
+        AllocatorType::Arena
+    } else {
+        AllocatorType::Device
+    };
+    let mut builder = SessionBuilder::new(env)?
+        .with_optimization_level(level)?
+        .with_parallel_execution(settings.execution_mode == ExecutionMode::Parallel)?
+        .with_memory_pattern(settings.memory_pattern)?
+        .with_allocator(allocator)?;
+    if let Some(threads) = settings.intra_threads {
+        builder = builder.with_intra_threads(threads)?;
+    }
+    if let Some(threads) = settings.inter_threads {
+        builder = builder.with_inter_threads(threads)?;
+    }
+    Ok((builder, model_file))
+}
+
+/// Session options created through the C API, released on drop.
+pub(super) struct SessionOptions<'a> {
+    api: &'a sys::OrtApi,
+    pub(super) ptr: *mut sys::OrtSessionOptions,
+}
+
+impl<'a> SessionOptions<'a> {
+    pub(super) unsafe fn new(api: &'a sys::OrtApi) -> Result<Self> {
+        let mut ptr = ptr::null_mut();
+        unsafe {
+            check(
+                api,
+                function(api.CreateSessionOptions, "CreateSessionOptions")?(&mut ptr),
+            )?;
+        }
+        Ok(Self { api, ptr })
+    }
+}
+
+impl Drop for SessionOptions<'_> {
+    fn drop(&mut self) {
+        if let Some(release) = self.api.ReleaseSessionOptions {
+            unsafe { release(self.ptr) };
+        }
+    }
+}
+
+pub(super) fn function<F>(function: Option<F>, name: &str) -> Result<F> {
+    function.ok_or_else(|| anyhow!("ONNX Runtime does not provide {name}"))
+}
+
+pub(super) unsafe fn check(api: &sys::OrtApi, status: sys::OrtStatusPtr) -> Result<()> {
+    if status.is_null() {
+        return Ok(());
+    }
+    let message = match api.GetErrorMessage {
+        Some(get_message) => unsafe { CStr::from_ptr(get_message(status)) }
+            .to_string_lossy()
+            .into_owned(),
+        None => "unknown error".to_string(),
+    };
+    if let Some(release) = api.ReleaseStatus {
+        unsafe { release(status) };
+    }
+    bail!("ONNX Runtime error: {message}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn providers_missing_from_the_build_are_skipped() {
+        let runtime = RuntimeSettings::default();
+        for provider in [Provider::Cpu, Provider::Xnnpack] {
+            assert!(execution_provider(provider, &runtime).is_none());
+        }
+        assert_eq!(
+            execution_provider(Provider::Cuda, &runtime).is_some(),
+            cfg!(feature = "cuda")
+        );
+        assert_eq!(runtime.execution_providers.last(), Some(&Provider::Cpu));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mapped_file_exposes_the_file_contents() {
+        let dir = TempDir::new("mmap");
+        let path = dir.join("model.ort");
+        std::fs::write(&path, b"\x10\0\0\0ORTM").unwrap();
+        let mapped = MappedFile::open(&path).unwrap();
+        assert_eq!(mapped.bytes(), b"\x10\0\0\0ORTM");
+        assert!(is_ort_format(mapped.bytes()));
+
+        assert!(!is_ort_format(b"\x08\x07\x12\x07pytorch"));
+        assert!(!is_ort_format(b"ORTM"));
+    }
+}
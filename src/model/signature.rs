@@ -0,0 +1,268 @@
+use anyhow::{Result, bail};
+use ort::{session::Session, tensor::TensorElementDataType};
+use serde::Serialize;
+
+const DURATION_OUTPUT_NAMES: &[&str] = &["durations", "duration", "w_ceil"];
+
+/// A VITS graph input the server knows how to feed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VitsInput {
+    Phones,
+    PhoneLengths,
+    Speaker,
+    Tones,
+    Language,
+    Bert,
+    JaBert,
+    EnBert,
+    Style,
+    LengthScale,
+    SdpRatio,
+    NoiseScale,
+    NoiseScaleW,
+    Seed,
+}
+
+impl VitsInput {
+    /// Inputs every supported export must expose. Scalar controls may be baked
+    /// into the graph as constants.
+    const REQUIRED: [VitsInput; 7] = [
+        VitsInput::Phones,
+        VitsInput::PhoneLengths,
+        VitsInput::Speaker,
+        VitsInput::Tones,
+        VitsInput::Language,
+        VitsInput::Bert,
+        VitsInput::Style,
+    ];
+
+    fn from_name(name: &str) -> Option<Self> {
+        let input = match name {
+            "x_tst" | "x" | "phones" => VitsInput::Phones,
+            "x_tst_lengths" | "x_lengths" | "phone_lengths" => VitsInput::PhoneLengths,
+            "sid" | "speakers" | "speaker_id" => VitsInput::Speaker,
+            "tones" | "tone" => VitsInput::Tones,
+            "language" | "languages" | "lang_ids" => VitsInput::Language,
+            "bert" | "zh_bert" => VitsInput::Bert,
+            "ja_bert" => VitsInput::JaBert,
+            "en_bert" => VitsInput::EnBert,
+            "style_vec" | "style_vector" | "style" => VitsInput::Style,
+            "length_scale" => VitsInput::LengthScale,
+            "sdp_ratio" => VitsInput::SdpRatio,
+            "noise_scale" | "noise" => VitsInput::NoiseScale,
+            "noise_scale_w" | "noise_w" => VitsInput::NoiseScaleW,
+            "seed" => VitsInput::Seed,
+            _ => return None,
+        };
+        Some(input)
+    }
+
+    fn expected_type(self) -> TensorElementDataType {
+        match self {
+            VitsInput::Phones
+            | VitsInput::PhoneLengths
+            | VitsInput::Speaker
+            | VitsInput::Tones
+            | VitsInput::Language
+            | VitsInput::Seed => TensorElementDataType::Int64,
+            _ => TensorElementDataType::Float32,
+        }
+    }
+
+    fn expected_rank(self) -> usize {
+        match self {
+            VitsInput::Phones | VitsInput::Tones | VitsInput::Language | VitsInput::Style => 2,
+            VitsInput::PhoneLengths | VitsInput::Speaker => 1,
+            VitsInput::Bert | VitsInput::JaBert | VitsInput::EnBert => 3,
+            VitsInput::LengthScale
+            | VitsInput::SdpRatio
+            | VitsInput::NoiseScale
+            | VitsInput::NoiseScaleW
+            | VitsInput::Seed => 0,
+        }
+    }
+}
+
+/// Known Style-Bert-VITS2 export layouts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelVariant {
+    /// v2.x multilingual exports with `bert`, `ja_bert` and `en_bert`.
+    Multilingual,
+    /// JP-Extra exports with a single `bert` input.
+    JpExtra,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TensorInfo {
+    pub name: String,
+    #[serde(skip)]
+    pub element_type: TensorElementDataType,
+    pub dtype: String,
+    pub shape: Vec<Option<u32>>,
+}
+
+/// Input binding and output layout of a VITS session, resolved at load time.
+#[derive(Debug, Clone)]
+pub struct VitsSignature {
+    pub inputs: Vec<VitsInput>,
+    pub duration_output: Option<usize>,
+    pub variant: ModelVariant,
+    pub input_info: Vec<TensorInfo>,
+    pub output_info: Vec<TensorInfo>,
+}
+
+impl VitsSignature {
+    pub fn inspect(session: &Session) -> Result<Self> {
+        let inputs: Vec<TensorInfo> = session
+            .inputs
+            .iter()
+            .map(|input| TensorInfo::new(&input.name, input.input_type, &input.dimensions))
+            .collect();
+        let outputs: Vec<TensorInfo> = session
+            .outputs
+            .iter()
+            .map(|output| TensorInfo::new(&output.name, output.output_type, &output.dimensions))
+            .collect();
+        Self::resolve(inputs, outputs)
+    }
+
+    fn resolve(input_info: Vec<TensorInfo>, output_info: Vec<TensorInfo>) -> Result<Self> {
+        let mut inputs = Vec::with_capacity(input_info.len());
+        for info in &input_info {
+            let Some(input) = VitsInput::from_name(&info.name) else {
+                bail!(
+                    "unsupported VITS input '{}'; expected Style-Bert-VITS2 inputs such as \
+                     x_tst, x_tst_lengths, sid, tones, language, bert, style_vec",
+                    info.name
+                );
+            };
+            if inputs.contains(&input) {
+                bail!("VITS input '{}' is bound more than once", info.name);
+            }
+            if info.element_type != input.expected_type() {
+                bail!(
+                    "VITS input '{}' has type {}, expected {:?}",
+                    info.name,
+                    info.dtype,
+                    input.expected_type()
+                );
+            }
+            if info.shape.len() != input.expected_rank() {
+                bail!(
+                    "VITS input '{}' has rank {}, expected {}",
+                    info.name,
+                    info.shape.len(),
+                    input.expected_rank()
+                );
+            }
+            inputs.push(input);
+        }
+        for required in VitsInput::REQUIRED {
+            if !inputs.contains(&required) {
+                bail!("VITS model is missing the {required:?} input");
+            }
+        }
+
+        let variant = match (
+            inputs.contains(&VitsInput::JaBert),
+            inputs.contains(&VitsInput::EnBert),
+        ) {
+            (true, true) => ModelVariant::Multilingual,
+            (false, false) => ModelVariant::JpExtra,
+            _ => bail!("VITS model must expose both ja_bert and en_bert or neither"),
+        };
+
+        let Some(audio) = output_info.first() else {
+            bail!("VITS model has no outputs");
+        };
+        if audio.element_type != TensorElementDataType::Float32 {
+            bail!(
+                "VITS audio output '{}' has type {}, expected Float32",
+                audio.name,
+                audio.dtype
+            );
+        }
+        let duration_output = output_info
+            .iter()
+            .position(|output| DURATION_OUTPUT_NAMES.contains(&output.name.as_str()));
+
+        Ok(Self {
+            inputs,
+            duration_output,
+            variant,
+            input_info,
+            output_info,
+        })
+    }
+
+    pub fn has_input(&self, input: VitsInput) -> bool {
+        self.inputs.contains(&input)
+    }
+}
+
+impl TensorInfo {
+    fn new(name: &str, dtype: TensorElementDataType, shape: &[Option<u32>]) -> Self {
+        Self {
+            name: name.to_string(),
+            element_type: dtype,
+            dtype: format!("{dtype:?}"),
+            shape: shape.to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tensor(name: &str, dtype: TensorElementDataType, rank: usize) -> TensorInfo {
+        TensorInfo::new(name, dtype, &vec![None; rank])
+    }
+
+    fn jp_extra_inputs() -> Vec<TensorInfo> {
+        use TensorElementDataType::{Float32, Int64};
+        vec![
+            tensor("x_tst", Int64, 2),
+            tensor("x_tst_lengths", Int64, 1),
+            tensor("sid", Int64, 1),
+            tensor("tones", Int64, 2),
+            tensor("language", Int64, 2),
+            tensor("bert", Float32, 3),
+            tensor("style_vec", Float32, 2),
+            tensor("length_scale", Float32, 0),
+            tensor("sdp_ratio", Float32, 0),
+            tensor("noise_scale", Float32, 0),
+            tensor("noise_scale_w", Float32, 0),
+        ]
+    }
+
+    fn audio_output() -> Vec<TensorInfo> {
+        vec![tensor("output", TensorElementDataType::Float32, 3)]
+    }
+
+    #[test]
+    fn binds_jp_extra_inputs_by_name() {
+        let signature = VitsSignature::resolve(jp_extra_inputs(), audio_output()).unwrap();
+        assert_eq!(signature.variant, ModelVariant::JpExtra);
+        assert_eq!(signature.inputs[5], VitsInput::Bert);
+        assert!(!signature.has_input(VitsInput::JaBert));
+        assert_eq!(signature.duration_output, None);
+    }
+
+    #[test]
+    fn rejects_mismatched_signatures() {
+        let mut inputs = jp_extra_inputs();
+        inputs[0] = tensor("x_tst", TensorElementDataType::Float32, 2);
+        let err = VitsSignature::resolve(inputs, audio_output()).unwrap_err();
+        assert!(err.to_string().contains("x_tst"));
+
+        let mut inputs = jp_extra_inputs();
+        inputs.retain(|info| info.name != "style_vec");
+        assert!(VitsSignature::resolve(inputs, audio_output()).is_err());
+
+        let mut inputs = jp_extra_inputs();
+        inputs.push(tensor("emo", TensorElementDataType::Float32, 2));
+        assert!(VitsSignature::resolve(inputs, audio_output()).is_err());
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use ndarray::{Array1, Array2, ArrayD, Axis, Ix1, Ix2};
+use ndarray_npy::{ReadNpyExt, WriteNpyExt};
+use serde_json::Value as JsonValue;
+
+/// Computes a style vector as the mean of the reference embeddings, appends it
+/// to `style_vectors.npy` and registers `name` in the config's `style2id`.
+/// Returns the id assigned to the new style.
+pub fn append_style(
+    config_path: &Path,
+    style_vec_path: &Path,
+    name: &str,
+    embedding_paths: &[PathBuf],
+) -> Result<usize> {
+    let style_vectors: Array2<f32> =
+        Array2::read_npy(File::open(style_vec_path).with_context(|| {
+            format!(
+                "failed to open style vectors at {}",
+                style_vec_path.display()
+            )
+        })?)
+        .context("failed to read style_vectors.npy")?;
+
+    let buf = fs::read_to_string(config_path)
+        .with_context(|| format!("failed to read {}", config_path.display()))?;
+    let mut config: JsonValue = serde_json::from_str(&buf)
+        .with_context(|| format!("failed to parse {}", config_path.display()))?;
+
+    let data = config
+        .get_mut("data")
+        .and_then(JsonValue::as_object_mut)
+        .context("config.json has no 'data' section")?;
+    let style2id = data
+        .entry("style2id")
+        .or_insert_with(|| JsonValue::Object(Default::default()))
+        .as_object_mut()
+        .context("'data.style2id' is not an object")?;
+    if style2id.contains_key(name) {
+        bail!("style '{name}' already exists in {}", config_path.display());
+    }
+
+    let style = mean_embedding(embedding_paths, style_vectors.ncols())?;
+    let style_id = style_vectors.nrows();
+    let mut updated = style_vectors;
+    updated
+        .push_row(style.view())
+        .context("failed to append style vector")?;
+
+    style2id.insert(name.to_string(), JsonValue::from(style_id));
+    data.insert("num_styles".to_string(), JsonValue::from(updated.nrows()));
+
+    let tmp_npy = style_vec_path.with_extension("npy.tmp");
+    updated
+        .write_npy(
+            File::create(&tmp_npy)
+                .with_context(|| format!("failed to create {}", tmp_npy.display()))?,
+        )
+        .context("failed to write style vectors")?;
+    let tmp_config = config_path.with_extension("json.tmp");
+    let serialized =
+        serde_json::to_string_pretty(&config).context("failed to serialise config.json")?;
+    fs::write(&tmp_config, serialized)
+        .with_context(|| format!("failed to write {}", tmp_config.display()))?;
+
+    fs::rename(&tmp_npy, style_vec_path)
+        .with_context(|| format!("failed to replace {}", style_vec_path.display()))?;
+    fs::rename(&tmp_config, config_path)
+        .with_context(|| format!("failed to replace {}", config_path.display()))?;
+
+    Ok(style_id)
+}
+
+/// Averages every embedding row found in `paths`. Each file may hold a single
+/// vector or a matrix with one embedding per row.
+fn mean_embedding(paths: &[PathBuf], dim: usize) -> Result<Array1<f32>> {
+    let mut sum = Array1::<f32>::zeros(dim);
+    let mut count = 0usize;
+    for path in paths {
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let array = ArrayD::<f32>::read_npy(file)
+            .with_context(|| format!("failed to read embeddings from {}", path.display()))?;
+        let rows = match array.ndim() {
+            1 => array
+                .into_dimensionality::<Ix1>()?
+                .insert_axis(Axis(0))
+                .to_owned(),
+            2 => array.into_dimensionality::<Ix2>()?,
+            other => bail!("{} has {other} dimensions, expected 1 or 2", path.display()),
+        };
+        if rows.ncols() != dim {
+            bail!(
+                "{} has {}-dimensional embeddings, expected {dim}",
+                path.display(),
+                rows.ncols()
+            );
+        }
+        for row in rows.rows() {
+            sum += &row;
+            count += 1;
+        }
+    }
+    if count == 0 {
+        bail!("no reference embeddings provided");
+    }
+    Ok(sum / count as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::array;
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn append_style_registers_mean_vector() {
+        let dir = TempDir::new("style");
+        let config_path = dir.join("config.json");
+        let style_path = dir.join("style_vectors.npy");
+        let embedding_path = dir.join("ref.npy");
+        fs::write(
+            &config_path,
+            r#"{"data":{"style2id":{"Neutral":0},"num_styles":1}}"#,
+        )
+        .unwrap();
+        array![[0.0_f32, 0.0]]
+            .write_npy(File::create(&style_path).unwrap())
+            .unwrap();
+        array![[1.0_f32, 2.0], [3.0, 4.0]]
+            .write_npy(File::create(&embedding_path).unwrap())
+            .unwrap();
+
+        let id = append_style(&config_path, &style_path, "Calm", &[embedding_path]).unwrap();
+        assert_eq!(id, 1);
+
+        let vectors = Array2::<f32>::read_npy(File::open(&style_path).unwrap()).unwrap();
+        assert_eq!(vectors.row(1).to_vec(), vec![2.0, 3.0]);
+        let config: JsonValue =
+            serde_json::from_str(&fs::read_to_string(&config_path).unwrap()).unwrap();
+        assert_eq!(config["data"]["style2id"]["Calm"], 1);
+        assert_eq!(config["data"]["num_styles"], 2);
+
+        assert!(append_style(&config_path, &style_path, "Calm", &[]).is_err());
+    }
+}
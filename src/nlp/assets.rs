@@ -0,0 +1,342 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use reqwest::{
+    Proxy, StatusCode,
+    blocking::{Client, Response},
+    header::RANGE,
+};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{model::details::FileDigest, settings::AssetSettings};
+
+/// Digests of the files in an asset directory, in `sha256sum` format.
+const MANIFEST: &str = "SHA256SUMS";
+
+/// Makes sure every file in `files` exists in `dir`, downloading the missing
+/// ones from the configured mirror. Downloads go to `<file>.part`, resume from
+/// an earlier partial download and are only moved into place once their
+/// SHA-256 matches. With `verify`, files already present are hashed too and
+/// fetched again if they do not match. Returns the files whose digest was
+/// only recorded because there was none to check it against.
+pub fn ensure_assets(
+    dir: &Path,
+    files: &[String],
+    settings: &AssetSettings,
+    verify: bool,
+) -> Result<Vec<String>> {
+    let missing: Vec<&String> = files
+        .iter()
+        .filter(|file| !dir.join(file).exists())
+        .collect();
+    if missing.is_empty() && !verify {
+        return Ok(Vec::new());
+    }
+    if settings.offline && !missing.is_empty() {
+        bail!(
+            "BERT assets missing from {} in offline mode: {}. Run `download-assets` on a \
+             machine with access to {} and copy the directory over",
+            dir.display(),
+            missing
+                .iter()
+                .map(|file| file.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            settings.mirror
+        );
+    }
+
+    fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let mut manifest = read_manifest(dir)?;
+    let mut unverified = Vec::new();
+    let mut fetcher = (!settings.offline)
+        .then(|| Fetcher::new(settings))
+        .transpose()?;
+    for file in files {
+        let destination = dir.join(file);
+        let expected = match &mut fetcher {
+            Some(fetcher) => fetcher.expected(file, &manifest),
+            None => settings.sha256.get(file).or(manifest.get(file)).cloned(),
+        };
+        if destination.exists() {
+            if !verify {
+                continue;
+            }
+            let digest = FileDigest::compute(&destination)?.sha256;
+            match &expected {
+                Some(expected) if !digest.eq_ignore_ascii_case(expected) => {
+                    if fetcher.is_none() {
+                        bail!(
+                            "{} does not match its SHA-256 {expected}",
+                            destination.display()
+                        );
+                    }
+                    warn!(
+                        "{} does not match its SHA-256; downloading it again",
+                        destination.display()
+                    );
+                }
+                Some(_) => {
+                    info!("verified {}", destination.display());
+                    manifest.insert(file.clone(), digest);
+                    continue;
+                }
+                None => {
+                    warn!(
+                        "no known checksum for {}; recorded (unverified) SHA-256 {digest}",
+                        destination.display()
+                    );
+                    unverified.push(file.clone());
+                    manifest.insert(file.clone(), digest);
+                    continue;
+                }
+            }
+        }
+
+        let Some(fetcher) = &fetcher else {
+            continue;
+        };
+        let digest = fetcher.download(file, &destination, expected.as_deref())?;
+        if expected.is_none() {
+            warn!("no published checksum for {file}; recorded (unverified) SHA-256 {digest}");
+            unverified.push(file.clone());
+        }
+        manifest.insert(file.clone(), digest);
+        write_manifest(dir, &manifest)?;
+    }
+    if verify {
+        write_manifest(dir, &manifest)?;
+    }
+    Ok(unverified)
+}
+
+struct Fetcher<'a> {
+    settings: &'a AssetSettings,
+    client: Client,
+    /// Digests the mirror publishes for LFS files, fetched on first use.
+    published: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Deserialize)]
+struct RepoInfo {
+    siblings: Vec<Sibling>,
+}
+
+#[derive(Deserialize)]
+struct Sibling {
+    rfilename: String,
+    lfs: Option<LfsInfo>,
+}
+
+#[derive(Deserialize)]
+struct LfsInfo {
+    sha256: String,
+}
+
+impl<'a> Fetcher<'a> {
+    fn new(settings: &'a AssetSettings) -> Result<Self> {
+        let mut builder = Client::builder()
+            .user_agent("sbv2-onnx-server/0.1")
+            .connect_timeout(Duration::from_secs(30))
+            .timeout(None);
+        if let Some(proxy) = &settings.proxy {
+            builder = builder.proxy(Proxy::all(proxy).context("invalid download proxy")?);
+        }
+        Ok(Self {
+            settings,
+            client: builder.build().context("failed to build HTTP client")?,
+            published: None,
+        })
+    }
+
+    /// Pinned digest, then the one the mirror publishes, then the one
+    /// recorded when the file was first downloaded.
+    fn expected(&mut self, file: &str, manifest: &BTreeMap<String, String>) -> Option<String> {
+        if let Some(digest) = self.settings.sha256.get(file) {
+            return Some(digest.clone());
+        }
+        let published = self.published.get_or_insert_with(|| {
+            published_digests(&self.client, self.settings).unwrap_or_else(|err| {
+                warn!("could not fetch published checksums: {err:#}");
+                BTreeMap::new()
+            })
+        });
+        published.get(file).or_else(|| manifest.get(file)).cloned()
+    }
+
+    fn download(&self, file: &str, destination: &Path, expected: Option<&str>) -> Result<String> {
+        let settings = self.settings;
+        let url = format!(
+            "{}/{}/resolve/{}/{file}",
+            settings.mirror.trim_end_matches('/'),
+            settings.repo,
+            settings.revision
+        );
+        let partial = partial_path(destination);
+        let offset = fs::metadata(&partial).map_or(0, |meta| meta.len());
+        let mut response = self.get(&url, offset)?;
+        if offset > 0 && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            // The partial file is as long as, or longer than, the remote one.
+            response = self.get(&url, 0)?;
+        }
+        let response = response
+            .error_for_status()
+            .with_context(|| format!("request failed {url}"))?;
+        let resume = offset > 0 && response.status() == StatusCode::PARTIAL_CONTENT;
+        if resume {
+            info!("resuming {file} at {offset} bytes");
+        } else {
+            info!("downloading {url}");
+        }
+
+        let mut out = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resume)
+            .truncate(!resume)
+            .open(&partial)
+            .with_context(|| format!("failed to create {}", partial.display()))?;
+        copy_response(response, &mut out).with_context(|| format!("failed to download {url}"))?;
+        out.sync_all()?;
+        drop(out);
+
+        let digest = FileDigest::compute(&partial)?.sha256;
+        if let Some(expected) = expected
+            && !digest.eq_ignore_ascii_case(expected)
+        {
+            fs::remove_file(&partial)?;
+            bail!("checksum mismatch for {file}: expected SHA-256 {expected}, got {digest}");
+        }
+        fs::rename(&partial, destination)
+            .with_context(|| format!("failed to move {} into place", partial.display()))?;
+        Ok(digest)
+    }
+
+    fn get(&self, url: &str, offset: u64) -> Result<Response> {
+        let mut request = self.client.get(url);
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={offset}-"));
+        }
+        request
+            .send()
+            .with_context(|| format!("failed to download {url}"))
+    }
+}
+
+fn published_digests(
+    client: &Client,
+    settings: &AssetSettings,
+) -> Result<BTreeMap<String, String>> {
+    let url = format!(
+        "{}/api/models/{}/revision/{}?blobs=true",
+        settings.mirror.trim_end_matches('/'),
+        settings.repo,
+        settings.revision
+    );
+    let body = client
+        .get(&url)
+        .send()
+        .and_then(Response::error_for_status)
+        .and_then(Response::text)
+        .with_context(|| format!("request failed {url}"))?;
+    let info: RepoInfo =
+        serde_json::from_str(&body).with_context(|| format!("unexpected response from {url}"))?;
+    Ok(info
+        .siblings
+        .into_iter()
+        .filter_map(|sibling| Some((sibling.rfilename, sibling.lfs?.sha256)))
+        .collect())
+}
+
+/// Copies the body to `out`; an interrupted transfer keeps what arrived so
+/// the next attempt can resume.
+fn copy_response(mut response: Response, out: &mut impl Write) -> io::Result<u64> {
+    let copied = io::copy(&mut response, out);
+    out.flush()?;
+    copied
+}
+
+fn partial_path(destination: &Path) -> PathBuf {
+    let mut name = destination.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    destination.with_file_name(name)
+}
+
+fn read_manifest(dir: &Path) -> Result<BTreeMap<String, String>> {
+    let path = dir.join(MANIFEST);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to read {}", path.display()));
+        }
+    };
+    Ok(text
+        .lines()
+        .filter_map(|line| {
+            let (digest, file) = line.split_once(char::is_whitespace)?;
+            let file = file.trim_start().trim_start_matches('*');
+            Some((file.to_string(), digest.to_ascii_lowercase()))
+        })
+        .collect())
+}
+
+fn write_manifest(dir: &Path, manifest: &BTreeMap<String, String>) -> Result<()> {
+    let path = dir.join(MANIFEST);
+    let partial = partial_path(&path);
+    let text: String = manifest
+        .iter()
+        .map(|(file, digest)| format!("{digest}  {file}\n"))
+        .collect();
+    fs::write(&partial, text).with_context(|| format!("failed to write {}", partial.display()))?;
+    fs::rename(&partial, &path)
+        .with_context(|| format!("failed to move {} into place", partial.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn offline_mode_reports_missing_files_and_manifest_round_trips() {
+        let dir = TempDir::new("assets");
+        fs::write(dir.join("vocab.txt"), "[PAD]\n").unwrap();
+        let settings = AssetSettings {
+            offline: true,
+            ..Default::default()
+        };
+        let files = ["vocab.txt".to_string(), "model.onnx".to_string()];
+
+        let err = ensure_assets(&dir, &files, &settings, false).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("model.onnx") && !message.contains("vocab.txt"));
+        ensure_assets(&dir, &files[..1], &settings, false).unwrap();
+
+        // Without a known digest the file is only recorded, and later
+        // verification checks against that record.
+        assert_eq!(
+            ensure_assets(&dir, &files[..1], &settings, true).unwrap(),
+            vec!["vocab.txt".to_string()]
+        );
+        assert!(
+            ensure_assets(&dir, &files[..1], &settings, true)
+                .unwrap()
+                .is_empty()
+        );
+        let manifest = read_manifest(&dir).unwrap();
+        assert_eq!(
+            manifest["vocab.txt"],
+            FileDigest::compute(&dir.join("vocab.txt")).unwrap().sha256
+        );
+        fs::write(dir.join("vocab.txt"), "tampered\n").unwrap();
+        assert!(ensure_assets(&dir, &files[..1], &settings, true).is_err());
+    }
+}
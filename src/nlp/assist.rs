@@ -0,0 +1,219 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::BufWriter,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Context, Result};
+use ndarray::Array1;
+use ndarray_npy::{ReadNpyExt, WriteNpyExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::cache::Lru;
+
+/// Mean BERT features of assist texts. Recently used means are kept in an
+/// LRU; the means of named prompts are computed at startup and never
+/// evicted. With a cache directory every computed mean is also written to
+/// disk and read back on later misses, including after a restart.
+pub struct AssistCache {
+    recent: Mutex<Lru<Arc<Array1<f32>>>>,
+    /// Prompt name to text.
+    prompts: BTreeMap<String, String>,
+    /// Means of the prompts, by text.
+    pinned: HashMap<String, Arc<Array1<f32>>>,
+    dir: Option<PathBuf>,
+    /// Identifies the BERT model in disk cache keys, since means computed
+    /// with another model must not be reused.
+    model: String,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AssistCacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub prompts: Vec<String>,
+    /// Lookups answered without running BERT, from memory or disk.
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl AssistCache {
+    pub fn new(capacity: usize, dir: Option<PathBuf>, model: String) -> Result<Self> {
+        if let Some(dir) = &dir {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create assist cache dir {}", dir.display()))?;
+        }
+        Ok(Self {
+            recent: Mutex::new(Lru::new(capacity, usize::MAX)),
+            prompts: BTreeMap::new(),
+            pinned: HashMap::new(),
+            dir,
+            model,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    /// Computes, or loads from disk, the mean of each prompt and keeps it
+    /// for the lifetime of the cache.
+    pub fn pin(
+        &mut self,
+        prompts: &BTreeMap<String, String>,
+        compute: impl Fn(&str) -> Result<Array1<f32>>,
+    ) -> Result<()> {
+        for (name, text) in prompts {
+            let text = text.trim();
+            let mean = match self.load(text) {
+                Some(mean) => mean,
+                None => {
+                    let mean = compute(text)
+                        .with_context(|| format!("failed to compute assist prompt '{name}'"))?;
+                    self.store(text, &mean);
+                    mean
+                }
+            };
+            self.pinned.insert(text.to_string(), Arc::new(mean));
+            self.prompts.insert(name.clone(), text.to_string());
+        }
+        if !prompts.is_empty() {
+            info!("pinned {} assist prompts", prompts.len());
+        }
+        Ok(())
+    }
+
+    /// Text of the named prompt.
+    pub fn prompt(&self, name: &str) -> Option<&str> {
+        self.prompts.get(name).map(String::as_str)
+    }
+
+    pub fn prompt_names(&self) -> Vec<String> {
+        self.prompts.keys().cloned().collect()
+    }
+
+    pub fn get_or_compute(
+        &self,
+        text: &str,
+        compute: impl FnOnce(&str) -> Result<Array1<f32>>,
+    ) -> Result<Arc<Array1<f32>>> {
+        if let Some(mean) = self.pinned.get(text) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(mean.clone());
+        }
+        if let Some(mean) = self.lock().get(text) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(mean.clone());
+        }
+        let mean = match self.load(text) {
+            Some(mean) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Arc::new(mean)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                let mean = compute(text)?;
+                self.store(text, &mean);
+                Arc::new(mean)
+            }
+        };
+        self.lock().insert(text.to_string(), mean.clone(), 0);
+        Ok(mean)
+    }
+
+    pub fn stats(&self) -> AssistCacheStats {
+        let recent = self.lock();
+        AssistCacheStats {
+            entries: recent.len(),
+            capacity: recent.capacity(),
+            prompts: self.prompt_names(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn load(&self, text: &str) -> Option<Array1<f32>> {
+        let path = self.disk_path(text)?;
+        let file = File::open(&path).ok()?;
+        match Array1::<f32>::read_npy(file) {
+            Ok(mean) => Some(mean),
+            Err(err) => {
+                warn!(
+                    "ignoring unreadable assist cache entry {}: {err}",
+                    path.display()
+                );
+                None
+            }
+        }
+    }
+
+    fn store(&self, text: &str, mean: &Array1<f32>) {
+        let Some(path) = self.disk_path(text) else {
+            return;
+        };
+        let tmp = path.with_extension("tmp");
+        let written = File::create(&tmp)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| Ok(mean.write_npy(BufWriter::new(file))?))
+            .and_then(|()| Ok(fs::rename(&tmp, &path)?));
+        if let Err(err) = written {
+            warn!(
+                "failed to persist assist cache entry {}: {err:#}",
+                path.display()
+            );
+        }
+    }
+
+    fn disk_path(&self, text: &str) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        let mut hasher = Sha256::new();
+        hasher.update(self.model.as_bytes());
+        hasher.update([0]);
+        hasher.update(text.as_bytes());
+        Some(dir.join(format!("{:x}.npy", hasher.finalize())))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lru<Arc<Array1<f32>>>> {
+        self.recent.lock().expect("assist cache mutex poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn pinned_and_persisted_means_skip_bert() {
+        let dir = TempDir::new("assist");
+        let compute = |text: &str| Ok(Array1::from_elem(2, text.chars().count() as f32));
+        let prompts = BTreeMap::from([("calm".to_string(), "平静".to_string())]);
+
+        let mut cache = AssistCache::new(1, Some(dir.to_path_buf()), "model.onnx".into()).unwrap();
+        cache.pin(&prompts, compute).unwrap();
+        assert_eq!(cache.prompt("calm"), Some("平静"));
+        cache.get_or_compute("开心", compute).unwrap();
+        cache.get_or_compute("难过", compute).unwrap();
+        cache.get_or_compute("平静", |_| panic!("pinned")).unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (1, 1, 2));
+
+        // A new cache, as after a restart, reads the means back from disk.
+        let cache = AssistCache::new(1, Some(dir.to_path_buf()), "model.onnx".into()).unwrap();
+        let mean = cache
+            .get_or_compute("开心", |_| panic!("persisted"))
+            .unwrap();
+        assert_eq!(mean.as_slice(), Some(&[2.0, 2.0][..]));
+        let other_model =
+            AssistCache::new(1, Some(dir.to_path_buf()), "other.onnx".into()).unwrap();
+        other_model.get_or_compute("开心", compute).unwrap();
+        assert_eq!(other_model.stats().misses, 1);
+    }
+}
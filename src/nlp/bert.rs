@@ -1,25 +1,30 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    fs::{self, File},
-    io::copy,
     path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use anyhow::{Context, Result, anyhow, bail};
 use ndarray::{Array1, Array2, Array3, Axis, CowArray};
-use ort::{
-    ExecutionProvider, GraphOptimizationLevel, SessionBuilder, environment::Environment,
-    session::Session, tensor::OrtOwnedTensor, value::Value,
+use ort::{environment::Environment, tensor::OrtOwnedTensor, value::Value};
+use tokenizers::Tokenizer;
+
+use super::assist::AssistCache;
+#[cfg(unix)]
+use super::sidecar::SidecarClient;
+use crate::{
+    cache::{CacheStats, Lru},
+    model::{
+        details::BertDetails,
+        pool::{IdlePool, SessionPool},
+        session::{ModelSession, new_session},
+    },
+    nlp::assets,
+    settings::{AssetSettings, Precision, RuntimeSettings},
 };
-use reqwest::blocking::Client;
-use tokenizers::{Encoding, Tokenizer};
-#[cfg(any(feature = "cuda", feature = "coreml", feature = "rocm"))]
-use tracing::info;
 
-const CHINESE_BERT_REPO: &str = "tsukumijima/chinese-roberta-wwm-ext-large-onnx";
+const BERT_MODEL: &str = "model.onnx";
 const REQUIRED_FILES: &[&str] = &[
-    "model_fp16.onnx",
     "tokenizer.json",
     "tokenizer_config.json",
     "config.json",
@@ -27,36 +32,114 @@ const REQUIRED_FILES: &[&str] = &[
     "special_tokens_map.json",
     "added_tokens.json",
 ];
-const ASSIST_CACHE_CAPACITY: usize = 8;
+
+/// Per-token hidden states, one row per token, with each token's byte span
+/// in the text.
+pub(crate) type Features = (Array2<f32>, Vec<(usize, usize)>);
 
 pub struct BertExtractor {
-    session: Session,
+    backend: Backend,
+    assist_cache: AssistCache,
+    /// Features of recently seen sentences, weighed in bytes; `None` when
+    /// disabled.
+    feature_cache: Option<Mutex<Lru<Arc<Features>>>>,
+}
+
+enum Backend {
+    Local(Box<LocalBert>),
+    #[cfg(unix)]
+    Sidecar(SidecarClient),
+}
+
+struct LocalBert {
+    sessions: Arc<IdlePool<ModelSession>>,
+    model_path: PathBuf,
+    tokenizer_path: PathBuf,
+    execution_provider: &'static str,
     tokenizer: Tokenizer,
-    assist_cache: Mutex<AssistCache>,
 }
 
 impl BertExtractor {
-    pub fn new(env: &Arc<Environment>, model_dir: &Path) -> Result<Self> {
-        ensure_bert_assets(model_dir)?;
+    /// Loads BERT from `model_dir`, or connects to the sidecar process when
+    /// `runtime.bert_socket` is set.
+    pub fn new(
+        env: &Arc<Environment>,
+        model_dir: &Path,
+        runtime: &RuntimeSettings,
+    ) -> Result<Self> {
+        let backend = match &runtime.bert_socket {
+            #[cfg(unix)]
+            Some(socket) => Backend::Sidecar(SidecarClient::connect(
+                socket,
+                Duration::from_millis(runtime.bert_socket_timeout_ms),
+            )?),
+            #[cfg(not(unix))]
+            Some(_) => bail!("the BERT sidecar needs Unix domain sockets"),
+            None => Backend::Local(Box::new(LocalBert::new(env, model_dir, runtime)?)),
+        };
+        let model = match &backend {
+            Backend::Local(local) => local.model_path.display().to_string(),
+            #[cfg(unix)]
+            Backend::Sidecar(client) => client.details()?.model_path.display().to_string(),
+        };
+        let mut assist_cache = AssistCache::new(
+            runtime.assist_cache_entries,
+            runtime.assist_cache_dir.clone(),
+            model,
+        )?;
+        assist_cache.pin(&runtime.assist_prompts, |text| backend.style_mean(text))?;
+        Ok(Self {
+            backend,
+            assist_cache,
+            feature_cache: (runtime.bert_cache_entries > 0).then(|| {
+                Mutex::new(Lru::new(
+                    runtime.bert_cache_entries,
+                    runtime.bert_cache_max_mb.saturating_mul(1 << 20),
+                ))
+            }),
+        })
+    }
 
-        let tokenizer_path = model_dir.join("tokenizer.json");
-        let tokenizer = Tokenizer::from_file(&tokenizer_path).map_err(|e| {
-            anyhow!(
-                "failed to load tokenizer from {}: {e}",
-                tokenizer_path.display()
-            )
-        })?;
+    /// Text of a named assist prompt from `runtime.assist_prompts`.
+    pub fn assist_prompt(&self, name: &str) -> Option<&str> {
+        self.assist_cache.prompt(name)
+    }
 
-        let model_path = locate_model_file(model_dir)?;
-        let session = new_bert_session(env, &model_path).with_context(|| {
-            format!("failed to load ONNX BERT model at {}", model_path.display())
-        })?;
+    pub fn assist_prompt_names(&self) -> Vec<String> {
+        self.assist_cache.prompt_names()
+    }
 
-        Ok(Self {
-            session,
-            tokenizer,
-            assist_cache: Mutex::new(AssistCache::new(ASSIST_CACHE_CAPACITY)),
-        })
+    /// Files this process loaded BERT from; none when using a sidecar.
+    pub fn local_files(&self) -> Vec<&Path> {
+        match &self.backend {
+            Backend::Local(local) => vec![&local.model_path, &local.tokenizer_path],
+            #[cfg(unix)]
+            Backend::Sidecar(_) => Vec::new(),
+        }
+    }
+
+    pub fn details(&self) -> Result<BertDetails> {
+        let mut details = match &self.backend {
+            Backend::Local(local) => BertDetails {
+                model_path: local.model_path.clone(),
+                execution_provider: local.execution_provider.to_string(),
+                sessions: local.sessions.status(),
+                feature_cache: None,
+                assist_cache: None,
+                sidecar: None,
+            },
+            #[cfg(unix)]
+            Backend::Sidecar(client) => client.details()?,
+        };
+        details.feature_cache = self.feature_cache.as_ref().map(|cache| {
+            let cache = cache.lock().expect("feature cache mutex poisoned");
+            CacheStats {
+                entries: cache.len(),
+                bytes: cache.weight(),
+            }
+        });
+        details.assist_cache = Some(self.assist_cache.stats());
+        Ok(details)
     }
 
     pub fn extract(
@@ -65,8 +148,9 @@ impl BertExtractor {
         word2ph: &[usize],
         assist_text: Option<(&str, f32)>,
     ) -> Result<Array2<f32>> {
-        let (features, encoding) = self.forward(text)?;
-        let aligned_word2ph = align_word2ph(text, word2ph, &encoding)
+        let cached = self.features(text)?;
+        let (features, offsets) = &*cached;
+        let aligned_word2ph = align_word2ph(text, word2ph, offsets)
             .context("failed to align word2ph with BERT tokens")?;
         if features.shape()[0] != aligned_word2ph.len() {
             bail!(
@@ -112,34 +196,93 @@ impl BertExtractor {
     }
 
     fn cached_style_mean(&self, text: &str) -> Result<Arc<Array1<f32>>> {
-        {
-            let mut cache = self
-                .assist_cache
-                .lock()
-                .expect("assist cache mutex poisoned");
-            if let Some(mean) = cache.get(text) {
-                return Ok(mean);
-            }
+        self.assist_cache
+            .get_or_compute(text, |text| self.backend.style_mean(text))
+    }
+
+    /// Features of `text`, from the feature cache when it has them. Callers
+    /// pass normalised text, so sentences that only differed before
+    /// normalisation share an entry.
+    pub(crate) fn features(&self, text: &str) -> Result<Arc<Features>> {
+        cached_features(self.feature_cache.as_ref(), text, |text| {
+            self.backend.forward(text)
+        })
+    }
+}
+
+/// Looks `text` up in `cache`, running `forward` and storing its result on a
+/// miss.
+fn cached_features(
+    cache: Option<&Mutex<Lru<Arc<Features>>>>,
+    text: &str,
+    forward: impl FnOnce(&str) -> Result<Features>,
+) -> Result<Arc<Features>> {
+    let Some(cache) = cache else {
+        return forward(text).map(Arc::new);
+    };
+    if let Some(features) = cache
+        .lock()
+        .expect("feature cache mutex poisoned")
+        .get(text)
+    {
+        return Ok(features.clone());
+    }
+    let features = Arc::new(forward(text)?);
+    let (hidden, offsets) = &*features;
+    let bytes =
+        text.len() + hidden.len() * size_of::<f32>() + offsets.len() * size_of::<(usize, usize)>();
+    cache.lock().expect("feature cache mutex poisoned").insert(
+        text.to_string(),
+        features.clone(),
+        bytes,
+    );
+    Ok(features)
+}
+
+impl Backend {
+    fn forward(&self, text: &str) -> Result<Features> {
+        match self {
+            Backend::Local(local) => local.forward(text),
+            #[cfg(unix)]
+            Backend::Sidecar(client) => client.features(text),
         }
+    }
 
+    fn style_mean(&self, text: &str) -> Result<Array1<f32>> {
         let (features, _) = self.forward(text)?;
-        let mean = features
-            .mean_axis(Axis(0))
-            .context("empty assist feature")?;
-        let mean = Arc::new(mean);
-
-        let mut cache = self
-            .assist_cache
-            .lock()
-            .expect("assist cache mutex poisoned");
-        if let Some(existing) = cache.get(text) {
-            return Ok(existing);
-        }
-        cache.insert(text.to_string(), mean.clone());
-        Ok(mean)
+        features.mean_axis(Axis(0)).context("empty assist feature")
     }
+}
 
-    fn forward(&self, text: &str) -> Result<(Array2<f32>, Encoding)> {
+impl LocalBert {
+    fn new(env: &Arc<Environment>, model_dir: &Path, runtime: &RuntimeSettings) -> Result<Self> {
+        let tokenizer_path = model_dir.join("tokenizer.json");
+        let tokenizer = Tokenizer::from_file(&tokenizer_path).map_err(|e| {
+            anyhow!(
+                "failed to load tokenizer from {}: {e}",
+                tokenizer_path.display()
+            )
+        })?;
+
+        let model_path = locate_model_file(model_dir, runtime.bert.precision)?;
+        let (pool, execution_provider) = load_sessions(env, &model_path, runtime)?;
+        let idle_timeout = (runtime.bert_idle_timeout_secs > 0)
+            .then(|| Duration::from_secs(runtime.bert_idle_timeout_secs));
+        let (env, path, runtime) = (env.clone(), model_path.clone(), runtime.clone());
+        let sessions = IdlePool::new("BERT", pool, idle_timeout, move || {
+            load_sessions(&env, &path, &runtime).map(|(pool, _)| pool)
+        })?;
+
+        Ok(Self {
+            sessions,
+            model_path,
+            tokenizer_path,
+            execution_provider,
+            tokenizer,
+        })
+    }
+
+    fn forward(&self, text: &str) -> Result<Features> {
         let encoding = self
             .tokenizer
             .encode(text, true)
@@ -167,10 +310,12 @@ impl BertExtractor {
         let token_type_ids = CowArray::from(token_type_ids_array.view().into_dyn());
         let attention = CowArray::from(attention_array.view().into_dyn());
 
-        let allocator = self.session.allocator();
+        let sessions = self.sessions.get()?;
+        let session = sessions.checkout()?;
+        let allocator = session.allocator();
 
         let mut ordered_inputs = Vec::new();
-        for input in &self.session.inputs {
+        for input in &session.inputs {
             let value = match input.name.as_str() {
                 "input_ids" => Value::from_array(allocator, &input_ids)?,
                 "token_type_ids" | "token_type_id" | "segment_ids" => {
@@ -182,7 +327,7 @@ impl BertExtractor {
             ordered_inputs.push(value);
         }
 
-        let outputs = self.session.run(ordered_inputs)?;
+        let outputs = session.run(ordered_inputs)?;
         let tensor: OrtOwnedTensor<f32, _> = outputs[0].try_extract()?;
         let array = tensor.view();
         let dims = array.shape();
@@ -201,40 +346,55 @@ impl BertExtractor {
             }
             other => bail!("unexpected BERT output dimensions: {:?}", other),
         };
-        Ok((features, encoding))
+        Ok((features, encoding.get_offsets().to_vec()))
     }
 }
 
-fn new_bert_session(env: &Arc<Environment>, model_path: &Path) -> Result<Session> {
-    let mut session_builder = SessionBuilder::new(env)?
-        .with_optimization_level(GraphOptimizationLevel::Level3)?
-        .with_parallel_execution(true)?;
-
-    #[cfg(any(feature = "cuda", feature = "coreml", feature = "rocm"))]
-    let mut providers: Vec<ExecutionProvider> = Vec::new();
-    #[cfg(not(any(feature = "cuda", feature = "coreml", feature = "rocm")))]
-    let providers: Vec<ExecutionProvider> = Vec::new();
-    #[cfg(feature = "cuda")]
-    {
-        info!("Using CUDA for BERT");
-        providers.push(ExecutionProvider::CUDA(Default::default()));
-    }
-    #[cfg(feature = "coreml")]
-    {
-        info!("Using CoreML for BERT");
-        providers.push(ExecutionProvider::CoreML(Default::default()));
-    }
-    #[cfg(feature = "rocm")]
-    {
-        info!("Using ROCm for BERT");
-        providers.push(ExecutionProvider::ROCm(Default::default()));
-    }
-    session_builder = session_builder.with_execution_providers(providers)?;
-    Ok(session_builder.with_model_from_file(model_path)?)
+fn load_sessions(
+    env: &Arc<Environment>,
+    model_path: &Path,
+    runtime: &RuntimeSettings,
+) -> Result<(SessionPool<ModelSession>, &'static str)> {
+    let mut execution_provider = "";
+    let pool = SessionPool::build(
+        runtime.bert.replicas,
+        Duration::from_millis(runtime.checkout_timeout_ms),
+        || {
+            let (session, provider) = new_session(env, model_path, runtime, &runtime.bert, "BERT")
+                .with_context(|| {
+                    format!("failed to load ONNX BERT model at {}", model_path.display())
+                })?;
+            execution_provider = provider;
+            Ok(session)
+        },
+    )?;
+    Ok((pool, execution_provider))
 }
 
-fn locate_model_file(dir: &Path) -> Result<PathBuf> {
-    let candidates = ["model_fp16.onnx", "model.onnx", "encoder_model.onnx"];
+fn locate_model_file(dir: &Path, precision: Option<Precision>) -> Result<PathBuf> {
+    let bert_model = Path::new(BERT_MODEL);
+    if let Some(precision) = precision {
+        let path = dir.join(precision.variant_of(bert_model));
+        if path.exists() {
+            return Ok(path);
+        }
+        if precision == Precision::Int8 {
+            bail!(
+                "{} not found; create it from the fp32 model with the `quantize` subcommand",
+                path.display()
+            );
+        }
+        bail!(
+            "{} BERT model not found at {}",
+            precision.as_str(),
+            path.display()
+        );
+    }
+    let candidates = [
+        Precision::Fp16.variant_of(bert_model),
+        bert_model.to_path_buf(),
+        PathBuf::from("encoder_model.onnx"),
+    ];
     for candidate in candidates {
         let path = dir.join(candidate);
         if path.exists() {
@@ -248,95 +408,32 @@ fn to_i64_array(values: &[u32]) -> Vec<i64> {
     values.iter().map(|&v| v as i64).collect()
 }
 
-fn ensure_bert_assets(model_dir: &Path) -> Result<()> {
-    let model_present = REQUIRED_FILES
+/// Makes sure the tokenizer files and the model file for `precision` (fp16
+/// when unset) are in `model_dir`, downloading what is missing. int8 models
+/// are never downloaded; they are produced locally from the fp32 model.
+/// Returns the files that had no checksum to verify them against.
+pub(crate) fn ensure_bert_assets(
+    model_dir: &Path,
+    precision: Option<Precision>,
+    settings: &AssetSettings,
+    verify: bool,
+) -> Result<Vec<String>> {
+    let model_file = match precision.unwrap_or(Precision::Fp16) {
+        Precision::Int8 => None,
+        precision => Some(precision.variant_of(Path::new(BERT_MODEL))),
+    };
+    let files: Vec<String> = REQUIRED_FILES
         .iter()
-        .all(|name| model_dir.join(name).exists());
-    if model_present {
-        return Ok(());
-    }
-
-    fs::create_dir_all(model_dir)
-        .with_context(|| format!("failed to create {}", model_dir.display()))?;
-
-    let client = Client::builder()
-        .user_agent("sbv2-onnx-server/0.1")
-        .build()
-        .context("failed to build HTTP client")?;
-
-    for file in REQUIRED_FILES {
-        let destination = model_dir.join(file);
-        if destination.exists() {
-            continue;
-        }
-
-        let url = format!("https://huggingface.co/{CHINESE_BERT_REPO}/resolve/main/{file}");
-        let mut response = client
-            .get(&url)
-            .send()
-            .with_context(|| format!("failed to download {url}"))?
-            .error_for_status()
-            .with_context(|| format!("request failed {url}"))?;
-
-        let mut out = File::create(&destination)
-            .with_context(|| format!("failed to create {}", destination.display()))?;
-        copy(&mut response, &mut out)
-            .with_context(|| format!("failed to write {}", destination.display()))?;
-    }
-
-    Ok(())
+        .map(|name| name.to_string())
+        .chain(model_file.map(|file| file.to_string_lossy().into_owned()))
+        .collect();
+    assets::ensure_assets(model_dir, &files, settings, verify)
 }
 
-struct AssistCache {
-    entries: HashMap<String, Arc<Array1<f32>>>,
-    order: VecDeque<String>,
-    capacity: usize,
-}
-
-impl AssistCache {
-    fn new(capacity: usize) -> Self {
-        Self {
-            entries: HashMap::new(),
-            order: VecDeque::new(),
-            capacity: capacity.max(1),
-        }
-    }
-
-    fn get(&mut self, key: &str) -> Option<Arc<Array1<f32>>> {
-        let value = self.entries.get(key).cloned();
-        if value.is_some() {
-            self.touch(key);
-        }
-        value
-    }
-
-    fn insert(&mut self, key: String, value: Arc<Array1<f32>>) {
-        self.entries.insert(key.clone(), value);
-        self.touch(&key);
-        self.evict();
-    }
-
-    fn touch(&mut self, key: &str) {
-        self.order.retain(|existing| existing != key);
-        self.order.push_back(key.to_string());
-    }
-
-    fn evict(&mut self) {
-        while self.entries.len() > self.capacity {
-            if let Some(oldest) = self.order.pop_front() {
-                self.entries.remove(&oldest);
-            } else {
-                break;
-            }
-        }
-    }
-}
-
-fn align_word2ph(text: &str, word2ph: &[usize], encoding: &Encoding) -> Result<Vec<usize>> {
+fn align_word2ph(text: &str, word2ph: &[usize], offsets: &[(usize, usize)]) -> Result<Vec<usize>> {
     if word2ph.is_empty() {
         bail!("word2ph is empty");
     }
-    let offsets = encoding.get_offsets();
     if offsets.is_empty() {
         bail!("BERT encoding produced no offsets");
     }
@@ -400,23 +497,49 @@ fn align_word2ph(text: &str, word2ph: &[usize], encoding: &Encoding) -> Result<V
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ndarray::Array1;
-    use std::sync::Arc;
 
     #[test]
-    fn assist_cache_drops_least_recent_entry() {
-        let mut cache = AssistCache::new(2);
-        let first = Arc::new(Array1::from_vec(vec![0.0]));
-        let second = Arc::new(Array1::from_vec(vec![1.0]));
-        let third = Arc::new(Array1::from_vec(vec![2.0]));
-
-        cache.insert("first".into(), first);
-        cache.insert("second".into(), second);
-        cache.get("first");
-        cache.insert("third".into(), third);
-
-        assert!(cache.entries.contains_key("first"));
-        assert!(cache.entries.contains_key("third"));
-        assert!(!cache.entries.contains_key("second"));
+    fn word2ph_follows_token_spans() {
+        // [CLS] 你 好 [SEP], then one token covering both characters.
+        let word2ph = [1, 2, 3, 1];
+        let per_char = [(0, 0), (0, 3), (3, 6), (0, 0)];
+        assert_eq!(
+            align_word2ph("你好", &word2ph, &per_char).unwrap(),
+            vec![1, 2, 3, 1]
+        );
+        let merged = [(0, 0), (0, 6), (0, 0)];
+        assert_eq!(
+            align_word2ph("你好", &word2ph, &merged).unwrap(),
+            vec![1, 5, 1]
+        );
+    }
+
+    #[test]
+    fn features_are_cached_by_normalised_text() {
+        use std::cell::Cell;
+
+        use crate::nlp::chinese::normalizer::normalize_text;
+
+        let cache = Mutex::new(Lru::new(8, 1 << 20));
+        let forwards = Cell::new(0);
+        let forward = |text: &str| {
+            forwards.set(forwards.get() + 1);
+            Ok((Array2::zeros((text.chars().count() + 2, 4)), Vec::new()))
+        };
+
+        let first = cached_features(Some(&cache), &normalize_text("你好！"), forward).unwrap();
+        assert_eq!(forwards.get(), 1);
+        // Differs only before normalisation, so it hits the same entry.
+        let second = cached_features(Some(&cache), &normalize_text("你好!"), forward).unwrap();
+        assert_eq!(forwards.get(), 1);
+        assert!(Arc::ptr_eq(&first, &second));
+
+        cached_features(Some(&cache), &normalize_text("再见"), forward).unwrap();
+        assert_eq!(forwards.get(), 2);
+        assert_eq!(cache.lock().unwrap().len(), 2);
+
+        // Without a cache every call runs the model.
+        cached_features(None, &normalize_text("你好！"), forward).unwrap();
+        assert_eq!(forwards.get(), 3);
     }
 }
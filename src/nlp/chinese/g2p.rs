@@ -334,7 +334,7 @@ fn adjust_vowel_pinyin(pinyin: String) -> String {
     }
 }
 
-fn is_punctuation_char(ch: char) -> bool {
+pub(crate) fn is_punctuation_char(ch: char) -> bool {
     PUNCTUATIONS.iter().any(|p| p.chars().next() == Some(ch))
 }
 
@@ -1,6 +1,11 @@
+pub mod assets;
+pub mod assist;
 pub mod bert;
 pub mod chinese;
 pub mod english;
+#[cfg(unix)]
+pub mod sidecar;
+pub mod viseme;
 
 use std::collections::HashMap;
 
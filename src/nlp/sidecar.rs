@@ -0,0 +1,318 @@
+//! A BERT sidecar serves one [`BertExtractor`] over a Unix socket so several
+//! server processes on a host can share a single copy of the model.
+//!
+//! Every message is a one-byte tag, a little-endian `u32` payload length and
+//! the payload. A request tag is [`FEATURES`] with the UTF-8 text, or
+//! [`DETAILS`] with nothing. The reply tag is [`OK`], or [`OVERLOADED`] or
+//! [`FAILED`] with the error message. Features come back as the sequence
+//! length and hidden size, a `u32` byte span per token and the row-major
+//! `f32` hidden states; details come back as JSON. Connections are kept open
+//! and carry any number of requests in turn.
+
+use std::{
+    fs,
+    io::{self, BufReader, BufWriter, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use ndarray::Array2;
+use tracing::{info, warn};
+
+use super::bert::{BertExtractor, Features};
+use crate::{errors::TtsError, model::details::BertDetails};
+
+const FEATURES: u8 = 0;
+const DETAILS: u8 = 1;
+
+const OK: u8 = 0;
+const FAILED: u8 = 1;
+const OVERLOADED: u8 = 2;
+
+/// Frames larger than this are rejected rather than allocated.
+const MAX_FRAME: usize = 256 << 20;
+
+/// Accepts connections on `socket` until the process is stopped, serving
+/// each on its own thread. A stale socket file left by an earlier run is
+/// replaced; one with a live sidecar behind it is an error.
+pub fn serve(socket: &Path, bert: Arc<BertExtractor>) -> Result<()> {
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            bail!(
+                "a BERT sidecar is already listening on {}",
+                socket.display()
+            );
+        }
+        fs::remove_file(socket)
+            .with_context(|| format!("failed to remove stale socket {}", socket.display()))?;
+    }
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("failed to bind {}", socket.display()))?;
+    info!("BERT sidecar listening on {}", socket.display());
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("failed to accept BERT sidecar connection: {err}");
+                continue;
+            }
+        };
+        let bert = bert.clone();
+        thread::Builder::new()
+            .name("bert-sidecar".to_string())
+            .spawn(move || {
+                if let Err(err) = handle(stream, &bert) {
+                    warn!("BERT sidecar connection failed: {err:#}");
+                }
+            })
+            .context("failed to spawn BERT sidecar connection thread")?;
+    }
+    Ok(())
+}
+
+fn handle(stream: UnixStream, bert: &BertExtractor) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    while let Some((tag, payload)) = read_frame(&mut reader, true)? {
+        let reply = match tag {
+            FEATURES => String::from_utf8(payload)
+                .map_err(|_| anyhow!("text is not valid UTF-8"))
+                .and_then(|text| bert.features(&text))
+                .map(|features| encode_features(&features.0, &features.1)),
+            DETAILS => bert
+                .details()
+                .and_then(|details| Ok(serde_json::to_vec(&details)?)),
+            other => Err(anyhow!("unknown BERT sidecar request {other}")),
+        };
+        match reply {
+            Ok(payload) => write_frame(&mut writer, OK, &payload)?,
+            Err(err) => {
+                let tag = match err.downcast_ref::<TtsError>() {
+                    Some(TtsError::Overloaded(_)) => OVERLOADED,
+                    _ => FAILED,
+                };
+                write_frame(&mut writer, tag, format!("{err:#}").as_bytes())?;
+            }
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Client side of the sidecar protocol, keeping idle connections for reuse.
+pub struct SidecarClient {
+    socket: PathBuf,
+    /// Read and write timeout of every connection; `None` blocks
+    /// indefinitely.
+    timeout: Option<Duration>,
+    idle: Mutex<Vec<UnixStream>>,
+}
+
+impl SidecarClient {
+    /// Connects once to check the sidecar is up and logs what it serves. A
+    /// zero `timeout` lets socket reads and writes block indefinitely.
+    pub fn connect(socket: &Path, timeout: Duration) -> Result<Self> {
+        let client = Self {
+            socket: socket.to_path_buf(),
+            timeout: (!timeout.is_zero()).then_some(timeout),
+            idle: Mutex::new(Vec::new()),
+        };
+        let details = client
+            .details()
+            .with_context(|| format!("failed to reach BERT sidecar at {}", socket.display()))?;
+        info!(
+            "using BERT sidecar at {} ({} on {})",
+            socket.display(),
+            details.model_path.display(),
+            details.execution_provider
+        );
+        Ok(client)
+    }
+
+    pub fn features(&self, text: &str) -> Result<Features> {
+        let payload = self.call(FEATURES, text.as_bytes())?;
+        decode_features(&payload).context("malformed reply from BERT sidecar")
+    }
+
+    pub fn details(&self) -> Result<BertDetails> {
+        let payload = self.call(DETAILS, &[])?;
+        let mut details: BertDetails =
+            serde_json::from_slice(&payload).context("malformed reply from BERT sidecar")?;
+        details.sidecar = Some(self.socket.clone());
+        Ok(details)
+    }
+
+    /// Sends one request, on an idle connection if there is one. A reused
+    /// connection the sidecar has since closed is retried once on a new one.
+    /// A sidecar that does not answer in time is reported as overloaded, and
+    /// the connection is dropped since its reply may still arrive.
+    fn call(&self, tag: u8, payload: &[u8]) -> Result<Vec<u8>> {
+        let idle = self.idle.lock().expect("sidecar mutex poisoned").pop();
+        let (stream, (tag, reply)) = match idle {
+            Some(stream) => match exchange(&stream, tag, payload) {
+                Ok(reply) => (stream, reply),
+                Err(err) if is_timeout(&err) => return Err(self.exchange_error(err)),
+                Err(_) => self.exchange_fresh(tag, payload)?,
+            },
+            None => self.exchange_fresh(tag, payload)?,
+        };
+        self.idle
+            .lock()
+            .expect("sidecar mutex poisoned")
+            .push(stream);
+        match tag {
+            OK => Ok(reply),
+            OVERLOADED => {
+                Err(TtsError::Overloaded(String::from_utf8_lossy(&reply).into_owned()).into())
+            }
+            _ => bail!("BERT sidecar: {}", String::from_utf8_lossy(&reply)),
+        }
+    }
+
+    fn exchange_fresh(&self, tag: u8, payload: &[u8]) -> Result<(UnixStream, (u8, Vec<u8>))> {
+        let stream = UnixStream::connect(&self.socket)
+            .with_context(|| format!("failed to connect to {}", self.socket.display()))?;
+        stream.set_read_timeout(self.timeout)?;
+        stream.set_write_timeout(self.timeout)?;
+        let reply = exchange(&stream, tag, payload).map_err(|err| self.exchange_error(err))?;
+        Ok((stream, reply))
+    }
+
+    fn exchange_error(&self, err: io::Error) -> anyhow::Error {
+        if is_timeout(&err) {
+            return TtsError::Overloaded(format!(
+                "BERT sidecar at {} did not answer within {} ms",
+                self.socket.display(),
+                self.timeout.unwrap_or_default().as_millis()
+            ))
+            .into();
+        }
+        anyhow::Error::new(err).context(format!("BERT sidecar at {} failed", self.socket.display()))
+    }
+}
+
+/// Whether `err` is a socket read or write that hit its timeout.
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn exchange(mut stream: &UnixStream, tag: u8, payload: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+    let mut writer = BufWriter::new(stream);
+    write_frame(&mut writer, tag, payload)?;
+    writer.flush()?;
+    drop(writer);
+    read_frame(&mut stream, false)?.ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+}
+
+fn write_frame(writer: &mut impl Write, tag: u8, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&[tag])?;
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(payload)
+}
+
+/// `None` when the peer closed the connection between frames and
+/// `eof_ok` is set.
+fn read_frame(reader: &mut impl Read, eof_ok: bool) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut tag = [0u8];
+    match reader.read_exact(&mut tag) {
+        Err(err) if eof_ok && err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{len} byte frame exceeds the {MAX_FRAME} byte limit"),
+        ));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some((tag[0], payload)))
+}
+
+fn encode_features(features: &Array2<f32>, offsets: &[(usize, usize)]) -> Vec<u8> {
+    let (seq_len, hidden) = features.dim();
+    let mut out = Vec::with_capacity(8 + offsets.len() * 8 + features.len() * 4);
+    out.extend((seq_len as u32).to_le_bytes());
+    out.extend((hidden as u32).to_le_bytes());
+    for &(start, end) in offsets {
+        out.extend((start as u32).to_le_bytes());
+        out.extend((end as u32).to_le_bytes());
+    }
+    for value in features {
+        out.extend(value.to_le_bytes());
+    }
+    out
+}
+
+fn decode_features(payload: &[u8]) -> Result<Features> {
+    let mut words = payload
+        .chunks_exact(4)
+        .map(|word| <[u8; 4]>::try_from(word).unwrap());
+    let mut next = || words.next().context("reply is truncated");
+    let seq_len = u32::from_le_bytes(next()?) as usize;
+    let hidden = u32::from_le_bytes(next()?) as usize;
+    if payload.len() != 8 + seq_len * 8 + seq_len * hidden * 4 {
+        bail!("reply length does not match {seq_len}x{hidden} features");
+    }
+    let offsets = (0..seq_len)
+        .map(|_| {
+            let start = u32::from_le_bytes(next()?) as usize;
+            let end = u32::from_le_bytes(next()?) as usize;
+            Ok((start, end))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let data = (0..seq_len * hidden)
+        .map(|_| Ok(f32::from_le_bytes(next()?)))
+        .collect::<Result<Vec<_>>>()?;
+    Ok((Array2::from_shape_vec((seq_len, hidden), data)?, offsets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn features_survive_the_wire_format() {
+        let features = Array2::from_shape_fn((3, 2), |(row, col)| row as f32 - col as f32 * 0.5);
+        let offsets = vec![(0, 0), (0, 3), (0, 0)];
+
+        let mut wire = Vec::new();
+        write_frame(&mut wire, OK, &encode_features(&features, &offsets)).unwrap();
+        let (tag, payload) = read_frame(&mut wire.as_slice(), false).unwrap().unwrap();
+        assert_eq!(tag, OK);
+        assert_eq!(decode_features(&payload).unwrap(), (features, offsets));
+
+        assert!(decode_features(&payload[..payload.len() - 4]).is_err());
+        assert!(read_frame(&mut [].as_slice(), true).unwrap().is_none());
+    }
+
+    #[test]
+    fn a_silent_sidecar_is_reported_as_overloaded() {
+        let client = SidecarClient {
+            socket: PathBuf::from("bert.sock"),
+            timeout: Some(Duration::from_millis(50)),
+            idle: Mutex::new(Vec::new()),
+        };
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        stream.set_read_timeout(client.timeout).unwrap();
+
+        let err = exchange(&stream, FEATURES, "你好".as_bytes()).unwrap_err();
+        assert!(is_timeout(&err));
+        let err = client.exchange_error(err);
+        assert!(matches!(
+            err.downcast_ref::<TtsError>(),
+            Some(TtsError::Overloaded(_))
+        ));
+    }
+}
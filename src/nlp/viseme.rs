@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{nlp::PAD, timestamps::PhoneTimestamp};
+
+/// The Oculus/Meta 15-viseme set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Viseme {
+    #[serde(rename = "sil")]
+    Sil,
+    PP,
+    FF,
+    TH,
+    DD,
+    #[serde(rename = "kk")]
+    Kk,
+    CH,
+    SS,
+    #[serde(rename = "nn")]
+    Nn,
+    RR,
+    #[serde(rename = "aa")]
+    Aa,
+    E,
+    I,
+    O,
+    U,
+}
+
+impl Viseme {
+    pub fn id(&self) -> u8 {
+        *self as u8
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VisemeFrame {
+    pub viseme: Viseme,
+    pub id: u8,
+    pub start: f32,
+    pub end: f32,
+}
+
+/// Maps a ZH (opencpop) or EN (ARPAbet) phone symbol to a viseme. Compound
+/// finals map to the mouth shape of their main vowel.
+pub fn viseme_for_phone(phone: &str) -> Viseme {
+    match phone {
+        "b" | "p" | "m" => Viseme::PP,
+        "f" | "V" => Viseme::FF,
+        "th" | "dh" => Viseme::TH,
+        "d" | "t" => Viseme::DD,
+        "g" | "k" | "h" | "hh" | "ng" => Viseme::Kk,
+        "j" | "q" | "x" | "zh" | "ch" | "sh" | "jh" => Viseme::CH,
+        "z" | "c" | "s" => Viseme::SS,
+        "n" | "l" => Viseme::Nn,
+        "r" | "er" | "ir" => Viseme::RR,
+        "a" | "ai" | "an" | "ang" | "ao" | "ia" | "iang" | "iao" | "ua" | "uai" | "uan"
+        | "uang" | "AA" | "aa" | "ae" | "ah" | "aw" | "ay" => Viseme::Aa,
+        "e" | "ei" | "en" | "eng" | "ian" | "ie" | "ui" | "van" | "ve" | "E" | "En" | "EE"
+        | "eh" | "ey" => Viseme::E,
+        "i" | "i0" | "in" | "ing" | "vn" | "y" | "ih" | "iy" => Viseme::I,
+        "o" | "ou" | "uo" | "iong" | "OO" | "ow" | "oy" => Viseme::O,
+        "u" | "un" | "ong" | "iu" | "v" | "w" | "uh" | "uw" => Viseme::U,
+        _ => Viseme::Sil,
+    }
+}
+
+/// Converts timed phones into a viseme track, merging consecutive frames with
+/// the same shape. Blanks inserted between phones extend the previous shape so
+/// the mouth does not close between every phone.
+pub fn viseme_track(phones: &[PhoneTimestamp]) -> Vec<VisemeFrame> {
+    let mut track: Vec<VisemeFrame> = Vec::new();
+    for phone in phones {
+        if phone.end <= phone.start {
+            continue;
+        }
+        let viseme = match (phone.phone.as_str(), track.last()) {
+            (PAD, Some(last)) => last.viseme,
+            (symbol, _) => viseme_for_phone(symbol),
+        };
+        match track.last_mut() {
+            Some(last) if last.viseme == viseme => last.end = phone.end,
+            _ => track.push(VisemeFrame {
+                viseme,
+                id: viseme.id(),
+                start: phone.start,
+                end: phone.end,
+            }),
+        }
+    }
+    track
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timed(phone: &str, start: f32, end: f32) -> PhoneTimestamp {
+        PhoneTimestamp {
+            phone: phone.to_string(),
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn maps_chinese_and_english_phones() {
+        assert_eq!(viseme_for_phone("b"), Viseme::PP);
+        assert_eq!(viseme_for_phone("ang"), Viseme::Aa);
+        assert_eq!(viseme_for_phone("th"), Viseme::TH);
+        assert_eq!(viseme_for_phone("uw"), Viseme::U);
+        assert_eq!(viseme_for_phone("v"), Viseme::U);
+        assert_eq!(viseme_for_phone(","), Viseme::Sil);
+        assert_eq!(Viseme::U.id(), 14);
+    }
+
+    #[test]
+    fn track_merges_blanks_and_repeats() {
+        let phones = vec![
+            timed("_", 0.0, 0.1),
+            timed("m", 0.1, 0.2),
+            timed("_", 0.2, 0.25),
+            timed("b", 0.25, 0.3),
+            timed("a", 0.3, 0.5),
+            timed("_", 0.5, 0.6),
+        ];
+        let track = viseme_track(&phones);
+        let shapes: Vec<Viseme> = track.iter().map(|frame| frame.viseme).collect();
+        assert_eq!(shapes, vec![Viseme::Sil, Viseme::PP, Viseme::Aa]);
+        assert!((track[1].end - 0.3).abs() < 1e-6);
+        assert!((track[2].end - 0.6).abs() < 1e-6);
+    }
+}
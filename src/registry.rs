@@ -0,0 +1,101 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::{Context, Result, bail};
+use tracing::info;
+
+use crate::{
+    errors::TtsError,
+    inference::{ChineseSynthesizer, SynthesisDefaults},
+    model::ProjectPaths,
+    reload::ProjectReloader,
+};
+
+/// A served model together with the handle used to reload it.
+pub struct ModelEntry {
+    pub synthesizer: ChineseSynthesizer,
+    pub reloader: Arc<ProjectReloader>,
+}
+
+/// Every model the server was started with, keyed by name. All models share
+/// the BERT extractor of the first one.
+pub struct ModelRegistry {
+    models: BTreeMap<String, ModelEntry>,
+    default_model: String,
+}
+
+impl ModelRegistry {
+    pub fn load(models: Vec<(String, ProjectPaths)>, defaults: SynthesisDefaults) -> Result<Self> {
+        let Some(default_model) = models.first().map(|(name, _)| name.clone()) else {
+            bail!("no models to serve");
+        };
+
+        let mut entries = BTreeMap::new();
+        let mut shared_bert = None;
+        for (name, paths) in models {
+            if entries.contains_key(&name) {
+                bail!("model name '{name}' is used more than once");
+            }
+            let project = match shared_bert.clone() {
+                Some(bert) => paths.load_with_bert(bert),
+                None => paths.load(),
+            }
+            .with_context(|| format!("failed to load model '{name}'"))?;
+            shared_bert.get_or_insert_with(|| project.bert().clone());
+            info!("loaded model '{name}' from {}", paths.model.display());
+
+            let synthesizer = ChineseSynthesizer::new(Arc::new(project), defaults);
+            let reloader = Arc::new(ProjectReloader::new(
+                name.clone(),
+                paths,
+                synthesizer.clone(),
+            ));
+            entries.insert(
+                name,
+                ModelEntry {
+                    synthesizer,
+                    reloader,
+                },
+            );
+        }
+
+        Ok(Self {
+            models: entries,
+            default_model,
+        })
+    }
+
+    /// Looks up the model a request asked for. With a single model every name
+    /// resolves to it, so OpenAI clients sending e.g. `tts-1` keep working.
+    pub fn resolve(&self, requested: Option<&str>) -> Option<(&str, &ModelEntry)> {
+        let name = match requested {
+            Some(name) if self.models.contains_key(name) => name,
+            Some(_) if self.models.len() > 1 => return None,
+            _ => self.default_model.as_str(),
+        };
+        self.models
+            .get_key_value(name)
+            .map(|(name, entry)| (name.as_str(), entry))
+    }
+
+    /// The error for a request whose model [`Self::resolve`] did not find.
+    pub fn unknown(&self, requested: Option<&str>) -> TtsError {
+        TtsError::UnknownModel {
+            name: requested.unwrap_or_default().to_string(),
+            available: self.names().join(", "),
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.models.keys().cloned().collect()
+    }
+
+    pub fn default_model(&self) -> &str {
+        &self.default_model
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &ModelEntry)> {
+        self.models
+            .iter()
+            .map(|(name, entry)| (name.as_str(), entry))
+    }
+}
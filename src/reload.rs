@@ -0,0 +1,157 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
+};
+
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+use tracing::{error, info};
+
+use crate::{
+    errors::TtsError,
+    inference::{ChineseSynthesisInput, ChineseSynthesizer, SynthesisDefaults},
+    model::{ProjectPaths, TtsProject},
+    registry::ModelRegistry,
+};
+
+const VALIDATION_TEXT: &str = "你好。";
+
+/// Reloads the project from its original paths and swaps it into the running
+/// synthesizer once a test synthesis succeeds.
+pub struct ProjectReloader {
+    name: String,
+    paths: ProjectPaths,
+    synthesizer: ChineseSynthesizer,
+    in_progress: Arc<AtomicBool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReloadReport {
+    pub model: String,
+    pub elapsed_ms: u128,
+}
+
+impl ProjectReloader {
+    pub fn new(name: String, paths: ProjectPaths, synthesizer: ChineseSynthesizer) -> Self {
+        Self {
+            name,
+            paths,
+            synthesizer,
+            in_progress: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Fails with [`TtsError::ReloadInProgress`] while another reload of
+    /// this model is running.
+    pub async fn reload(self: &Arc<Self>) -> Result<ReloadReport> {
+        let guard = InProgress::acquire(&self.in_progress, &self.name)?;
+        // The guard lives in the blocking task, so the flag is cleared when
+        // the reload ends even if the caller stops waiting for it.
+        let reloader = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let _guard = guard;
+            reloader.reload_blocking()
+        })
+        .await
+        .context("reload task panicked")?
+    }
+
+    fn reload_blocking(&self) -> Result<ReloadReport> {
+        let start = Instant::now();
+        // BERT is shared between models and is not reloaded.
+        let bert = self.synthesizer.project().bert().clone();
+        let project = Arc::new(
+            self.paths
+                .load_with_bert(bert)
+                .context("failed to load replacement project")?,
+        );
+        validate(&project, *self.synthesizer.defaults())
+            .context("replacement project failed validation")?;
+
+        // In-flight requests keep their own handle, so the previous project is
+        // only dropped once the last of them completes. Cached responses need
+        // no purge: their keys include the project's fingerprint, which
+        // changed with the files.
+        drop(self.synthesizer.swap(project));
+
+        let report = ReloadReport {
+            model: self.name.clone(),
+            elapsed_ms: start.elapsed().as_millis(),
+        };
+        info!(
+            "reloaded model '{}' in {} ms",
+            report.model, report.elapsed_ms
+        );
+        Ok(report)
+    }
+}
+
+/// Marks a reload as running until dropped.
+struct InProgress(Arc<AtomicBool>);
+
+impl InProgress {
+    fn acquire(flag: &Arc<AtomicBool>, model: &str) -> Result<Self, TtsError> {
+        if flag.swap(true, Ordering::SeqCst) {
+            return Err(TtsError::ReloadInProgress(model.to_string()));
+        }
+        Ok(Self(flag.clone()))
+    }
+}
+
+impl Drop for InProgress {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+fn validate(project: &Arc<TtsProject>, defaults: SynthesisDefaults) -> Result<()> {
+    let result = ChineseSynthesizer::new(project.clone(), defaults)
+        .synthesize(&ChineseSynthesisInput::new(VALIDATION_TEXT))?;
+    if result.pcm.is_empty() || result.pcm.iter().any(|sample| !sample.is_finite()) {
+        bail!("test synthesis produced no usable audio");
+    }
+    Ok(())
+}
+
+/// Reloads every model each time the process receives SIGHUP.
+#[cfg(unix)]
+pub async fn reload_on_sighup(models: Arc<ModelRegistry>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(stream) => stream,
+        Err(err) => {
+            error!("failed to install SIGHUP handler: {err:?}");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("received SIGHUP, reloading models");
+        for (name, entry) in models.iter() {
+            if let Err(err) = entry.reloader.reload().await {
+                error!("reload of '{name}' failed, keeping current model: {err:?}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_concurrent_reload_is_a_conflict() {
+        let flag = Arc::new(AtomicBool::new(false));
+        let running = InProgress::acquire(&flag, "default").unwrap();
+
+        let err = InProgress::acquire(&flag, "default").err().unwrap();
+        assert_eq!(err.status(), 409);
+        assert_eq!(err.code(), "reload_in_progress");
+
+        drop(running);
+        assert!(InProgress::acquire(&flag, "default").is_ok());
+    }
+}
@@ -1,30 +1,89 @@
-use std::{net::SocketAddr, sync::Arc};
+mod auth;
+mod lifecycle;
+mod presets;
+mod probe;
+mod stream;
+
+use std::{
+    collections::BTreeMap, future::IntoFuture, net::SocketAddr, path::PathBuf, sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{Context, Result};
 use axum::{
-    Json, Router,
-    extract::State,
-    http::StatusCode,
+    Extension, Json, Router,
+    extract::{FromRequest, Query, Request, State, rejection::JsonRejection},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    middleware,
     response::{Html, IntoResponse},
     routing::{get, post},
 };
 use base64::{Engine, engine::general_purpose::STANDARD as BASE64_STANDARD};
 use serde::{Deserialize, Serialize};
 use tokio::net::TcpListener;
-use tracing::info;
+
+use self::{
+    auth::{ApiKeys, Caller},
+    lifecycle::Lifecycle,
+    presets::Presets,
+    probe::{Probe, Readiness},
+};
+use tracing::{info, warn};
 
 use crate::{
     audio,
-    inference::{ChineseSynthesisInput, ChineseSynthesizer},
-    model::TtsProject,
+    cache::{CacheStats, SynthesisCache},
+    errors::TtsError,
+    inference::ChineseSynthesisInput,
+    model::details::ModelDetails,
+    nlp::chinese::normalizer,
+    nlp::viseme::{self, VisemeFrame},
+    registry::{ModelEntry, ModelRegistry},
+    reload::{self, ReloadReport},
+    settings::Preset,
+    timestamps::CharTimestamp,
 };
 
 #[derive(Clone)]
 struct AppState {
-    synthesizer: ChineseSynthesizer,
+    models: Arc<ModelRegistry>,
+    cache: Option<Arc<SynthesisCache>>,
+    lifecycle: Arc<Lifecycle>,
+    probe: Arc<Probe>,
+    api_keys: Option<Arc<ApiKeys>>,
+    presets: Arc<Presets>,
+    mp3_bitrate: u32,
     index_html: &'static str,
 }
 
+/// Runtime behaviour of the HTTP server that is independent of the models.
+pub struct ServeOptions {
+    /// How long in-flight requests may run after a shutdown signal.
+    pub drain_timeout: Duration,
+    /// Texts synthesised with every model before reporting ready.
+    pub warmup_texts: Vec<String>,
+    /// JSON file with API keys and their quotas; see `auth::ApiKeys::load`.
+    pub api_keys_file: Option<PathBuf>,
+    /// Bitrate of MP3 responses in kbit/s.
+    pub mp3_bitrate: u32,
+    /// Presets available at startup, by name.
+    pub presets: BTreeMap<String, Preset>,
+}
+
+impl AppState {
+    fn model(&self, requested: Option<&str>) -> ApiResult<(&str, &ModelEntry)> {
+        self.models
+            .resolve(requested)
+            .ok_or_else(|| self.models.unknown(requested).into())
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ModelQuery {
+    #[serde(default)]
+    model: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 struct SpeechRequest {
     model: String,
@@ -32,10 +91,16 @@ struct SpeechRequest {
     #[serde(default)]
     voice: Option<String>,
     #[serde(default)]
+    preset: Option<String>,
+    #[serde(default)]
     style: Option<String>,
     #[serde(default)]
     style_weight: Option<f32>,
     #[serde(default)]
+    style_mix: Option<BTreeMap<String, f32>>,
+    #[serde(default)]
+    style_vector: Option<Vec<f32>>,
+    #[serde(default)]
     noise: Option<f32>,
     #[serde(default)]
     noise_w: Option<f32>,
@@ -52,7 +117,21 @@ struct SpeechRequest {
     #[serde(default)]
     assist_text: Option<String>,
     #[serde(default)]
+    assist_prompt: Option<String>,
+    #[serde(default)]
     assist_weight: Option<f32>,
+    #[serde(default)]
+    pitch: Option<f32>,
+    #[serde(default)]
+    volume: Option<f32>,
+    #[serde(default)]
+    timestamps: bool,
+    #[serde(default)]
+    subtitle_format: Option<SubtitleFormat>,
+    #[serde(default)]
+    visemes: bool,
+    #[serde(default)]
+    seed: Option<u64>,
 }
 
 #[derive(Debug, Deserialize, Default, Clone, Copy)]
@@ -72,6 +151,13 @@ impl AudioFormat {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum SubtitleFormat {
+    Vtt,
+    Srt,
+}
+
 #[derive(Debug, Deserialize, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
 enum ResponseFormat {
@@ -85,59 +171,190 @@ impl Default for ResponseFormat {
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 struct SpeechResponse {
     model: String,
     voice: Option<String>,
     style: Option<String>,
     audio_base64: String,
-    audio_format: &'static str,
+    audio_format: String,
     sample_rate: u32,
     duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamps: Option<Vec<CharTimestamp>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subtitles: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    visemes: Option<Vec<VisemeFrame>>,
 }
 
+/// Every input that influences the synthesised audio, hashed into the cache key.
 #[derive(Serialize)]
-struct ApiErrorBody {
-    message: String,
+struct SpeechCacheKey<'a> {
+    model: &'a str,
+    fingerprint: &'a str,
+    text: String,
+    voice: Option<&'a str>,
+    style: Option<&'a str>,
+    style_weight: f32,
+    style_mix: Option<&'a BTreeMap<String, f32>>,
+    style_vector: Option<&'a [f32]>,
+    noise: f32,
+    noise_w: f32,
+    sdp_ratio: f32,
+    length_scale: f32,
+    assist_text: Option<&'a str>,
+    assist_weight: f32,
+    pitch: f32,
+    volume: f32,
+    seed: Option<u64>,
+    audio_format: &'static str,
+    timestamps: bool,
+    subtitle_format: Option<SubtitleFormat>,
+    visemes: bool,
+}
+
+#[derive(Serialize)]
+struct CachePurgeResponse {
+    purged: usize,
 }
 
 type ApiResult<T> = std::result::Result<T, ApiError>;
 
 #[derive(Serialize)]
 struct MetadataResponse {
+    models: Vec<String>,
+    model: String,
     voices: Vec<String>,
     styles: Vec<String>,
+    presets: Vec<String>,
+    assist_prompts: Vec<String>,
     sample_rate: u32,
 }
 
-pub async fn serve(addr: SocketAddr, project: TtsProject) -> Result<()> {
-    let project = Arc::new(project);
-    let synthesizer = ChineseSynthesizer::new(project.clone());
+pub async fn serve(
+    addr: SocketAddr,
+    models: ModelRegistry,
+    cache: Option<Arc<SynthesisCache>>,
+    options: ServeOptions,
+) -> Result<()> {
+    let ServeOptions {
+        drain_timeout,
+        warmup_texts,
+        api_keys_file,
+        mp3_bitrate,
+        presets,
+    } = options;
+    let api_keys = ApiKeys::load(api_keys_file.as_deref())?.map(Arc::new);
+    match api_keys {
+        Some(ref keys) => info!("API key authentication enabled with {} keys", keys.len()),
+        None => warn!("no API keys configured; the API is open to anyone who can reach it"),
+    }
+    let models = Arc::new(models);
+    info!(
+        "serving models {:?} (default '{}')",
+        models.names(),
+        models.default_model()
+    );
+    #[cfg(unix)]
+    tokio::spawn(reload::reload_on_sighup(models.clone()));
+
     static INDEX_HTML: &str = include_str!("templates/index.html");
+    let lifecycle = Lifecycle::new();
+    let probe = Probe::new();
     let state = AppState {
-        synthesizer,
+        models: models.clone(),
+        cache,
+        lifecycle: lifecycle.clone(),
+        probe: probe.clone(),
+        api_keys,
+        presets: Arc::new(Presets::new(presets)),
+        mp3_bitrate,
         index_html: INDEX_HTML,
     };
 
+    let api = Router::new()
+        .route("/v1/metadata", get(metadata))
+        .route("/v1/audio/speech", post(create_speech))
+        .route("/v1/audio/speech/stream", get(stream::speech_stream))
+        .route("/admin/cache", get(cache_stats).delete(purge_cache))
+        .route("/admin/model", get(model_details))
+        .route("/admin/reload", post(reload_model))
+        .route("/admin/presets", get(presets::list_presets))
+        .route(
+            "/admin/presets/{name}",
+            get(presets::get_preset)
+                .put(presets::put_preset)
+                .delete(presets::delete_preset),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_key,
+        ));
     let app = Router::new()
         .route("/", get(index))
         .route("/healthz", get(health))
-        .route("/v1/metadata", get(metadata))
-        .route("/v1/audio/speech", post(create_speech))
+        .route("/readyz", get(ready))
+        .merge(api)
         .with_state(state);
 
     let listener = TcpListener::bind(addr)
         .await
         .with_context(|| format!("failed to bind HTTP listener on {addr}"))?;
     info!("listening on http://{}", listener.local_addr()?);
+    tokio::spawn(probe.warm_up(models, warmup_texts));
+
+    let server = axum::serve(listener, app.into_make_service())
+        .with_graceful_shutdown(lifecycle.clone().stop_on_signal())
+        .into_future();
+    tokio::pin!(server);
+    tokio::select! {
+        result = &mut server => return result.context("HTTP server terminated unexpectedly"),
+        _ = lifecycle.stopped() => {}
+    }
 
-    axum::serve(listener, app.into_make_service())
-        .await
-        .context("HTTP server terminated unexpectedly")
+    // No new connections are accepted from here on; give open requests and
+    // running inferences until the deadline to complete.
+    let drain = async {
+        let result = server.await;
+        lifecycle.idle().await;
+        result
+    };
+    match tokio::time::timeout(drain_timeout, drain).await {
+        Ok(result) => {
+            result.context("HTTP server terminated unexpectedly")?;
+            info!("all requests drained, shutting down");
+        }
+        Err(_) => warn!(
+            "drain deadline of {:?} elapsed with {} inferences still running",
+            drain_timeout,
+            lifecycle.in_flight()
+        ),
+    }
+    Ok(())
+}
+
+async fn health(State(state): State<AppState>) -> (StatusCode, String) {
+    if state.lifecycle.is_stopping() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "shutting down".to_string());
+    }
+    match state.probe.check(&state.models).await {
+        Ok(()) => (StatusCode::OK, "ok".to_string()),
+        Err(message) => (StatusCode::SERVICE_UNAVAILABLE, message),
+    }
 }
 
-async fn health() -> &'static str {
-    "ok"
+async fn ready(State(state): State<AppState>) -> (StatusCode, &'static str) {
+    if state.lifecycle.is_stopping() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "shutting down");
+    }
+    match state.probe.readiness() {
+        Readiness::Ready => (StatusCode::OK, "ready"),
+        Readiness::WarmingUp => (StatusCode::SERVICE_UNAVAILABLE, "warming up"),
+        Readiness::Failed => (StatusCode::SERVICE_UNAVAILABLE, "warm-up failed"),
+    }
 }
 
 async fn index(State(state): State<AppState>) -> Html<&'static str> {
@@ -146,14 +363,18 @@ async fn index(State(state): State<AppState>) -> Html<&'static str> {
 
 async fn create_speech(
     State(state): State<AppState>,
-    Json(payload): Json<SpeechRequest>,
-) -> ApiResult<Json<SpeechResponse>> {
+    caller: Option<Extension<Caller>>,
+    ApiJson(payload): ApiJson<SpeechRequest>,
+) -> ApiResult<(HeaderMap, Json<SpeechResponse>)> {
     let SpeechRequest {
         model,
         input,
-        voice,
+        mut voice,
+        preset,
         style,
         style_weight,
+        style_mix,
+        style_vector,
         noise,
         noise_w,
         sdp_ratio,
@@ -162,68 +383,153 @@ async fn create_speech(
         response_format,
         audio_format,
         assist_text,
+        assist_prompt,
         assist_weight,
+        pitch,
+        volume,
+        timestamps,
+        subtitle_format,
+        visemes,
+        seed,
     } = payload;
 
     let format = audio_format.unwrap_or_default();
 
     let response_format = response_format.unwrap_or_default();
     if !matches!(response_format, ResponseFormat::B64Json) {
-        return Err(ApiError::bad_request(
-            "only b64_json response_format is supported",
-        ));
+        return Err(
+            ApiError::bad_request("only b64_json response_format is supported")
+                .with_param("response_format"),
+        );
     }
 
     if input.trim().is_empty() {
-        return Err(ApiError::bad_request("input text must not be empty"));
+        return Err(TtsError::InvalidInput("input text must not be empty".into()).into());
     }
 
+    let (model_name, entry) = state.model(Some(&model))?;
+    let synthesizer = entry.synthesizer.clone();
+    let project = synthesizer.project();
+    let defaults = *synthesizer.defaults();
+    let preset = state
+        .presets
+        .select(preset.as_deref(), &mut voice, |name| {
+            project.speaker_id(name).is_some()
+        })?;
+
     let mut synth_input = ChineseSynthesisInput::new(input);
-    synth_input.speaker = voice.clone();
-    synth_input.style = style.clone();
+    synth_input.preset = preset;
+    synth_input.speaker = voice;
+    synth_input.style = style;
     synth_input.style_weight = style_weight;
+    synth_input.style_mix = style_mix;
+    synth_input.style_vector = style_vector;
     synth_input.noise = noise;
     synth_input.noise_w = noise_w;
     synth_input.sdp_ratio = sdp_ratio;
     synth_input.assist_text = assist_text;
+    synth_input.assist_prompt = assist_prompt;
     synth_input.assist_weight = assist_weight;
+    synth_input.pitch = pitch;
+    synth_input.volume = volume;
+    synth_input.seed = seed;
 
     if let Some(ls) = length_scale {
         synth_input.length_scale = Some(ls);
     } else if let Some(speed) = speed {
         if speed <= 0.0 {
-            return Err(ApiError::bad_request("speed must be greater than 0"));
+            return Err(TtsError::invalid("speed", "speed must be greater than 0").into());
         }
         synth_input.length_scale = Some(1.0 / speed);
     }
 
-    let synthesizer = state.synthesizer.clone();
-    let result = tokio::task::spawn_blocking(move || synthesizer.synthesize(&synth_input))
-        .await
-        .map_err(|err| ApiError::internal(format!("inference task panicked: {err}")))?
-        .map_err(|err| {
-            tracing::error!("TTS inference failed: {err:?}");
-            ApiError::from_anyhow(err)
-        })?;
+    let resolved_style = synth_input
+        .style()
+        .or_else(|| project.default_style_name())
+        .map(str::to_string);
+    let resolved_voice = synth_input
+        .speaker()
+        .or_else(|| project.default_speaker_name())
+        .map(str::to_string);
+    if let Some(Extension(caller)) = &caller {
+        caller.authorize(model_name, resolved_voice.as_deref())?;
+    }
 
-    let resolved_style = style.clone().or_else(|| {
-        state
-            .synthesizer
-            .project()
-            .default_style_name()
-            .map(str::to_string)
-    });
-    let resolved_voice = voice.clone().or_else(|| {
-        state
-            .synthesizer
-            .project()
-            .default_speaker_name()
-            .map(str::to_string)
-    });
+    let cache_key = match state.cache {
+        Some(_) => {
+            let key = SpeechCacheKey {
+                model: model_name,
+                fingerprint: project.fingerprint(),
+                text: normalizer::normalize_text(&synth_input.text),
+                voice: resolved_voice.as_deref(),
+                style: resolved_style.as_deref(),
+                style_weight: synth_input.style_weight().unwrap_or(defaults.style_weight),
+                style_mix: synth_input.style_mix.as_ref(),
+                style_vector: synth_input.style_vector.as_deref(),
+                noise: synth_input.noise().unwrap_or(defaults.noise),
+                noise_w: synth_input.noise_w().unwrap_or(defaults.noise_w),
+                sdp_ratio: synth_input.sdp_ratio().unwrap_or(defaults.sdp_ratio),
+                length_scale: synth_input.length_scale().unwrap_or(defaults.length_scale),
+                // A prompt is keyed by its text, which may change between
+                // restarts.
+                assist_text: match synth_input.assist() {
+                    (Some(text), _) => Some(text),
+                    (None, prompt) => prompt.and_then(|name| project.bert().assist_prompt(name)),
+                },
+                assist_weight: synth_input
+                    .assist_weight()
+                    .unwrap_or(defaults.assist_weight),
+                pitch: synth_input.pitch.unwrap_or(0.0),
+                volume: synth_input.volume.unwrap_or(0.0),
+                seed,
+                audio_format: format.as_str(),
+                timestamps,
+                subtitle_format,
+                visemes,
+            };
+            Some(SynthesisCache::key(&key).map_err(ApiError::from_anyhow)?)
+        }
+        None => None,
+    };
+
+    if let (Some(cache), Some(key)) = (&state.cache, &cache_key)
+        && let Some(bytes) = cache.get(key).await
+    {
+        match serde_json::from_slice::<SpeechResponse>(&bytes) {
+            Ok(mut cached) => {
+                cached.model = model;
+                cached.duration_ms = 0;
+                return Ok((cache_headers(key, true), Json(cached)));
+            }
+            Err(err) => warn!("discarding unreadable cache entry {key}: {err}"),
+        }
+    }
+
+    let reservation = caller
+        .map(|Extension(caller)| caller.reserve(synth_input.text.chars().count()))
+        .transpose()?;
+    let in_flight = state
+        .lifecycle
+        .begin()
+        .ok_or_else(|| TtsError::Overloaded("server is shutting down".into()))?;
+    let result = tokio::task::spawn_blocking(move || {
+        let _in_flight = in_flight;
+        synthesizer.synthesize(&synth_input)
+    })
+    .await
+    .map_err(|err| ApiError::internal(format!("inference task panicked: {err}")))?
+    .map_err(|err| {
+        tracing::error!("TTS inference failed: {err:?}");
+        ApiError::from_anyhow(err)
+    })?;
+    state.probe.record_success();
+    if let Some(reservation) = reservation {
+        reservation.commit();
+    }
 
     let encode_result = match format {
         AudioFormat::Wav => Ok(result.wav_base64()),
-        AudioFormat::Mp3 => audio::pcm_to_mp3(&result.pcm, result.sample_rate)
+        AudioFormat::Mp3 => audio::pcm_to_mp3(&result.pcm, result.sample_rate, state.mp3_bitrate)
             .map(|bytes| BASE64_STANDARD.encode(bytes))
             .map_err(|err| {
                 tracing::error!("MP3 encoding failed: {err:?}");
@@ -231,58 +537,252 @@ async fn create_speech(
             }),
     }?;
 
+    let subtitles = subtitle_format.map(|format| match format {
+        SubtitleFormat::Vtt => result.alignment.to_webvtt(),
+        SubtitleFormat::Srt => result.alignment.to_srt(),
+    });
+
     let response = SpeechResponse {
         model,
         voice: resolved_voice,
         style: resolved_style,
         audio_base64: encode_result,
-        audio_format: format.as_str(),
+        audio_format: format.as_str().to_string(),
         sample_rate: result.sample_rate,
         duration_ms: result.timings.total_ms,
+        seed: result.seed,
+        timestamps: timestamps.then(|| result.alignment.characters.clone()),
+        subtitles,
+        visemes: visemes.then(|| viseme::viseme_track(&result.alignment.phones)),
     };
 
-    Ok(Json(response))
+    let mut headers = HeaderMap::new();
+    if let (Some(cache), Some(key)) = (&state.cache, cache_key) {
+        match serde_json::to_vec(&response) {
+            Ok(bytes) => {
+                headers = cache_headers(&key, false);
+                cache.insert(key, bytes);
+            }
+            Err(err) => warn!("failed to serialise response for cache: {err}"),
+        }
+    }
+
+    Ok((headers, Json(response)))
+}
+
+fn cache_headers(key: &str, hit: bool) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "x-cache",
+        HeaderValue::from_static(if hit { "HIT" } else { "MISS" }),
+    );
+    if let Ok(etag) = HeaderValue::from_str(&format!("\"{key}\"")) {
+        headers.insert("etag", etag);
+    }
+    headers
+}
+
+async fn cache_stats(State(state): State<AppState>) -> ApiResult<Json<CacheStats>> {
+    let cache = state.cache.as_ref().ok_or_else(|| {
+        ApiError::not_found("synthesis cache is disabled").with_code("cache_disabled")
+    })?;
+    Ok(Json(cache.stats()))
+}
+
+async fn purge_cache(State(state): State<AppState>) -> ApiResult<Json<CachePurgeResponse>> {
+    let cache = state.cache.clone().ok_or_else(|| {
+        ApiError::not_found("synthesis cache is disabled").with_code("cache_disabled")
+    })?;
+    let purged = tokio::task::spawn_blocking(move || cache.purge())
+        .await
+        .map_err(|err| ApiError::internal(format!("cache purge panicked: {err}")))?;
+    info!("purged {purged} synthesis cache entries");
+    Ok(Json(CachePurgeResponse { purged }))
+}
+
+async fn model_details(
+    State(state): State<AppState>,
+    Query(query): Query<ModelQuery>,
+) -> ApiResult<Json<ModelDetails>> {
+    let (_, entry) = state.model(query.model.as_deref())?;
+    let project = entry.synthesizer.project();
+    let details = tokio::task::spawn_blocking(move || project.details())
+        .await
+        .map_err(|err| ApiError::internal(format!("model details task panicked: {err}")))?
+        .map_err(ApiError::from_anyhow)?;
+    Ok(Json(details))
+}
+
+/// Reloads the model named in the query, or every model when none is given.
+async fn reload_model(
+    State(state): State<AppState>,
+    Query(query): Query<ModelQuery>,
+) -> ApiResult<Json<Vec<ReloadReport>>> {
+    let reloaders: Vec<_> = match query.model.as_deref() {
+        Some(name) => vec![state.model(Some(name))?.1.reloader.clone()],
+        None => state
+            .models
+            .iter()
+            .map(|(_, entry)| entry.reloader.clone())
+            .collect(),
+    };
+    let mut reports = Vec::with_capacity(reloaders.len());
+    for reloader in reloaders {
+        let report = reloader.reload().await.map_err(|err| {
+            tracing::error!("model reload failed: {err:?}");
+            ApiError::from_anyhow(err)
+        })?;
+        reports.push(report);
+    }
+    Ok(Json(reports))
 }
 
+#[derive(Debug)]
 struct ApiError {
     status: StatusCode,
     message: String,
+    param: Option<&'static str>,
+    code: Option<&'static str>,
 }
 
 impl ApiError {
-    fn bad_request(message: impl Into<String>) -> Self {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
         Self {
-            status: StatusCode::BAD_REQUEST,
+            status,
             message: message.into(),
+            param: None,
+            code: None,
         }
     }
 
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+
     fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+
+    fn with_param(mut self, param: &'static str) -> Self {
+        self.param = Some(param);
+        self
+    }
+
+    fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    /// Uses the first `TtsError` in the chain to pick the status; anything
+    /// untyped is an internal error.
+    fn from_anyhow(err: anyhow::Error) -> Self {
+        match err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<TtsError>())
+        {
+            Some(typed) => Self::from(typed),
+            None => Self::internal(err.to_string()),
+        }
+    }
+
+    /// OpenAI error `type` for the status.
+    fn error_type(&self) -> &'static str {
+        match self.status {
+            StatusCode::UNAUTHORIZED => "authentication_error",
+            StatusCode::FORBIDDEN => "permission_error",
+            StatusCode::TOO_MANY_REQUESTS => "rate_limit_error",
+            status if status.is_server_error() => "server_error",
+            _ => "invalid_request_error",
+        }
+    }
+}
+
+impl From<&TtsError> for ApiError {
+    fn from(err: &TtsError) -> Self {
+        let status =
+            StatusCode::from_u16(err.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
         Self {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: message.into(),
+            status,
+            message: err.to_string(),
+            param: err.param(),
+            code: Some(err.code()),
         }
     }
+}
 
-    fn from_anyhow(err: anyhow::Error) -> Self {
-        Self::internal(err.to_string())
+impl From<TtsError> for ApiError {
+    fn from(err: TtsError) -> Self {
+        Self::from(&err)
+    }
+}
+
+/// [`Json`] whose rejections, such as a missing field or a wrong content
+/// type, are reported as 400 in the OpenAI error format.
+struct ApiJson<T>(T);
+
+impl<S, T> FromRequest<S> for ApiJson<T>
+where
+    Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(request, state).await {
+            Ok(Json(value)) => Ok(Self(value)),
+            Err(rejection) => {
+                Err(ApiError::bad_request(rejection.body_text()).with_code("invalid_request_body"))
+            }
+        }
     }
 }
 
+/// Error body in the OpenAI format.
+#[derive(Serialize)]
+struct ApiErrorBody {
+    error: ApiErrorDetail,
+}
+
+#[derive(Serialize)]
+struct ApiErrorDetail {
+    message: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    param: Option<&'static str>,
+    code: Option<&'static str>,
+}
+
 impl IntoResponse for ApiError {
     fn into_response(self) -> axum::response::Response {
         let body = Json(ApiErrorBody {
-            message: self.message,
+            error: ApiErrorDetail {
+                kind: self.error_type(),
+                message: self.message,
+                param: self.param,
+                code: self.code,
+            },
         });
         (self.status, body).into_response()
     }
 }
 
-async fn metadata(State(state): State<AppState>) -> Json<MetadataResponse> {
-    let project = state.synthesizer.project();
-    Json(MetadataResponse {
+async fn metadata(
+    State(state): State<AppState>,
+    Query(query): Query<ModelQuery>,
+) -> ApiResult<Json<MetadataResponse>> {
+    let (name, entry) = state.model(query.model.as_deref())?;
+    let project = entry.synthesizer.project();
+    Ok(Json(MetadataResponse {
+        models: state.models.names(),
+        model: name.to_string(),
         voices: project.available_speakers(),
         styles: project.available_styles(),
+        presets: state.presets.names(),
+        assist_prompts: project.bert().assist_prompt_names(),
         sample_rate: project.sample_rate(),
-    })
+    }))
 }
@@ -0,0 +1,404 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+use axum::{
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::Response,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use super::{ApiError, ApiResult, AppState};
+
+/// Comma-separated keys without quotas, in addition to any key file.
+const API_KEYS_ENV: &str = "SBV2_API_KEYS";
+const MINUTE: Duration = Duration::from_secs(60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// One entry of the key file.
+#[derive(Debug, Deserialize)]
+struct KeyConfig {
+    key: String,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    requests_per_minute: Option<u32>,
+    #[serde(default)]
+    chars_per_day: Option<u64>,
+    /// Models the key may use; empty allows all.
+    #[serde(default)]
+    models: Vec<String>,
+    /// Voices the key may use; empty allows all.
+    #[serde(default)]
+    voices: Vec<String>,
+    #[serde(default)]
+    admin: bool,
+}
+
+/// What a single API key is allowed to do.
+#[derive(Debug)]
+pub(super) struct KeyPolicy {
+    /// Digest of the key, used to track usage.
+    id: String,
+    name: String,
+    requests_per_minute: Option<u32>,
+    chars_per_day: Option<u64>,
+    models: Vec<String>,
+    voices: Vec<String>,
+    admin: bool,
+}
+
+#[derive(Debug)]
+struct Usage {
+    minute_start: Instant,
+    requests: u32,
+    day_start: Instant,
+    chars: u64,
+}
+
+/// Bearer-token authentication with per-key quotas. Keys are stored by their
+/// SHA-256 digest, so lookups do not compare secrets byte by byte.
+pub(super) struct ApiKeys {
+    keys: HashMap<String, Arc<KeyPolicy>>,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl ApiKeys {
+    /// Loads keys from `file` (a JSON array of key entries) and from
+    /// `SBV2_API_KEYS`. Returns `None` when neither provides a key, which
+    /// leaves the server open.
+    pub(super) fn load(file: Option<&Path>) -> Result<Option<Self>> {
+        let mut configs: Vec<KeyConfig> = match file {
+            Some(path) => {
+                let buf = fs::read_to_string(path)
+                    .with_context(|| format!("failed to read API keys from {}", path.display()))?;
+                serde_json::from_str(&buf)
+                    .with_context(|| format!("failed to parse API keys at {}", path.display()))?
+            }
+            None => Vec::new(),
+        };
+        if let Ok(value) = env::var(API_KEYS_ENV) {
+            configs.extend(
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|key| !key.is_empty())
+                    .enumerate()
+                    .map(|(idx, key)| KeyConfig {
+                        key: key.to_string(),
+                        name: Some(format!("env-{idx}")),
+                        requests_per_minute: None,
+                        chars_per_day: None,
+                        models: Vec::new(),
+                        voices: Vec::new(),
+                        admin: false,
+                    }),
+            );
+        }
+        if configs.is_empty() {
+            return Ok(None);
+        }
+        Self::from_configs(configs).map(Some)
+    }
+
+    fn from_configs(configs: Vec<KeyConfig>) -> Result<Self> {
+        let mut keys = HashMap::new();
+        for (idx, config) in configs.into_iter().enumerate() {
+            if config.key.is_empty() {
+                bail!("API key entry {idx} has an empty key");
+            }
+            let digest = digest(&config.key);
+            let policy = KeyPolicy {
+                id: digest.clone(),
+                name: config.name.unwrap_or_else(|| format!("key-{idx}")),
+                requests_per_minute: config.requests_per_minute,
+                chars_per_day: config.chars_per_day,
+                models: config.models,
+                voices: config.voices,
+                admin: config.admin,
+            };
+            if keys.insert(digest, Arc::new(policy)).is_some() {
+                bail!("API key entry {idx} duplicates an earlier key");
+            }
+        }
+        Ok(Self {
+            keys,
+            usage: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    fn authenticate(&self, authorization: Option<&str>) -> ApiResult<Arc<KeyPolicy>> {
+        let token = authorization
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| token.trim())
+            .filter(|token| !token.is_empty())
+            .ok_or_else(|| ApiError::unauthorized("missing bearer token"))?;
+        self.keys
+            .get(&digest(token))
+            .cloned()
+            .ok_or_else(|| ApiError::unauthorized("invalid API key"))
+    }
+
+    fn charge_request(&self, policy: &KeyPolicy, now: Instant) -> ApiResult<()> {
+        let mut usage = self.lock();
+        let usage = usage_for(&mut usage, policy, now);
+        if let Some(limit) = policy.requests_per_minute
+            && usage.requests >= limit
+        {
+            return Err(ApiError::too_many_requests(format!(
+                "rate limit of {limit} requests per minute exceeded"
+            )));
+        }
+        usage.requests += 1;
+        Ok(())
+    }
+
+    /// Counts `chars` of input against the key's daily quota.
+    fn charge_chars(&self, policy: &KeyPolicy, chars: usize) -> ApiResult<()> {
+        let Some(limit) = policy.chars_per_day else {
+            return Ok(());
+        };
+        let mut usage = self.lock();
+        let usage = usage_for(&mut usage, policy, Instant::now());
+        let chars = chars as u64;
+        if usage.chars + chars > limit {
+            return Err(ApiError::too_many_requests(format!(
+                "daily quota of {limit} characters exceeded"
+            ))
+            .with_code("insufficient_quota"));
+        }
+        usage.chars += chars;
+        Ok(())
+    }
+
+    /// Gives back characters charged by [`Self::charge_chars`].
+    fn refund_chars(&self, policy: &KeyPolicy, chars: usize) {
+        if policy.chars_per_day.is_none() {
+            return;
+        }
+        let mut usage = self.lock();
+        let usage = usage_for(&mut usage, policy, Instant::now());
+        usage.chars = usage.chars.saturating_sub(chars as u64);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Usage>> {
+        self.usage.lock().expect("API key usage mutex poisoned")
+    }
+}
+
+impl KeyPolicy {
+    fn check_model(&self, model: &str) -> ApiResult<()> {
+        if self.models.is_empty() || self.models.iter().any(|allowed| allowed == model) {
+            Ok(())
+        } else {
+            Err(ApiError::forbidden(format!(
+                "API key '{}' may not use model '{model}'",
+                self.name
+            )))
+        }
+    }
+
+    fn check_voice(&self, voice: Option<&str>) -> ApiResult<()> {
+        match voice {
+            Some(voice) if !self.voices.is_empty() && !self.voices.iter().any(|v| v == voice) => {
+                Err(ApiError::forbidden(format!(
+                    "API key '{}' may not use voice '{voice}'",
+                    self.name
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The authenticated key of a request, attached as a request extension.
+#[derive(Clone)]
+pub(super) struct Caller {
+    keys: Arc<ApiKeys>,
+    policy: Arc<KeyPolicy>,
+}
+
+impl Caller {
+    /// Checks the key may use `model` and `voice`.
+    pub(super) fn authorize(&self, model: &str, voice: Option<&str>) -> ApiResult<()> {
+        self.policy.check_model(model)?;
+        self.policy.check_voice(voice)
+    }
+
+    /// Charges `chars` of input against the key's daily quota. Taken right
+    /// before synthesis, so cache hits are free, and given back unless the
+    /// synthesis succeeds.
+    pub(super) fn reserve(&self, chars: usize) -> ApiResult<Reservation> {
+        self.keys.charge_chars(&self.policy, chars)?;
+        Ok(Reservation {
+            caller: self.clone(),
+            chars,
+            committed: false,
+        })
+    }
+}
+
+/// Characters taken from a key's daily quota, refunded when dropped without
+/// [`Self::commit`].
+pub(super) struct Reservation {
+    caller: Caller,
+    chars: usize,
+    committed: bool,
+}
+
+impl Reservation {
+    pub(super) fn commit(mut self) {
+        self.committed = true;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.caller
+                .keys
+                .refund_chars(&self.caller.policy, self.chars);
+        }
+    }
+}
+
+/// Rejects requests without a valid key, enforces the per-minute quota and
+/// restricts `/admin` routes to admin keys. The key's policy is attached to
+/// the request for handlers to check models, voices and characters.
+pub(super) async fn require_key(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let Some(keys) = state.api_keys.clone() else {
+        return Ok(next.run(request).await);
+    };
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    let policy = keys.authenticate(authorization)?;
+    if request.uri().path().starts_with("/admin") && !policy.admin {
+        return Err(ApiError::forbidden(format!(
+            "API key '{}' may not use admin endpoints",
+            policy.name
+        )));
+    }
+    keys.charge_request(&policy, Instant::now())?;
+    request.extensions_mut().insert(Caller { keys, policy });
+    Ok(next.run(request).await)
+}
+
+fn usage_for<'a>(
+    usage: &'a mut HashMap<String, Usage>,
+    policy: &KeyPolicy,
+    now: Instant,
+) -> &'a mut Usage {
+    let entry = usage.entry(policy.id.clone()).or_insert(Usage {
+        minute_start: now,
+        requests: 0,
+        day_start: now,
+        chars: 0,
+    });
+    if now.duration_since(entry.minute_start) >= MINUTE {
+        entry.minute_start = now;
+        entry.requests = 0;
+    }
+    if now.duration_since(entry.day_start) >= DAY {
+        entry.day_start = now;
+        entry.chars = 0;
+    }
+    entry
+}
+
+fn digest(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+impl ApiError {
+    fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, message).with_code("invalid_api_key")
+    }
+
+    fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, message).with_code("permission_denied")
+    }
+
+    fn too_many_requests(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::TOO_MANY_REQUESTS, message).with_code("rate_limit_exceeded")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> ApiKeys {
+        let configs: Vec<KeyConfig> = serde_json::from_str(
+            r#"[
+                {"key": "sk-limited", "name": "limited", "requests_per_minute": 2,
+                 "chars_per_day": 10, "models": ["alpha"], "voices": ["a"]},
+                {"key": "sk-admin", "admin": true}
+            ]"#,
+        )
+        .unwrap();
+        ApiKeys::from_configs(configs).unwrap()
+    }
+
+    #[test]
+    fn authenticates_bearer_tokens() {
+        let keys = keys();
+        assert!(keys.authenticate(Some("Bearer sk-admin")).unwrap().admin);
+        // The scheme is case-insensitive; the token is not.
+        assert!(keys.authenticate(Some("bearer sk-admin")).unwrap().admin);
+        assert!(keys.authenticate(Some("BEARER  sk-admin")).unwrap().admin);
+        assert!(keys.authenticate(Some("Bearer SK-ADMIN")).is_err());
+        assert!(keys.authenticate(Some("Basic sk-admin")).is_err());
+        let err = keys.authenticate(Some("Bearer sk-wrong")).unwrap_err();
+        assert_eq!(err.status, StatusCode::UNAUTHORIZED);
+        assert!(keys.authenticate(None).is_err());
+    }
+
+    #[test]
+    fn enforces_quotas_and_allow_lists() {
+        let keys = keys();
+        let policy = keys.authenticate(Some("Bearer sk-limited")).unwrap();
+        let now = Instant::now();
+        keys.charge_request(&policy, now).unwrap();
+        keys.charge_request(&policy, now).unwrap();
+        let err = keys.charge_request(&policy, now).unwrap_err();
+        assert_eq!(err.status, StatusCode::TOO_MANY_REQUESTS);
+        keys.charge_request(&policy, now + MINUTE).unwrap();
+
+        let caller = Caller {
+            keys: Arc::new(keys),
+            policy: policy.clone(),
+        };
+        // A failed request gives its characters back.
+        drop(caller.reserve(8).unwrap());
+        caller.reserve(8).unwrap().commit();
+        assert!(caller.reserve(3).is_err());
+
+        assert!(policy.check_model("alpha").is_ok());
+        assert_eq!(
+            policy.check_model("beta").unwrap_err().status,
+            StatusCode::FORBIDDEN
+        );
+        assert!(policy.check_voice(Some("a")).is_ok());
+        assert!(policy.check_voice(Some("b")).is_err());
+        assert!(policy.check_voice(None).is_ok());
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use tokio::sync::{Notify, watch};
+use tracing::{error, info};
+
+/// Tracks shutdown state and the number of inferences still running so the
+/// server can drain before exiting.
+pub(super) struct Lifecycle {
+    stopping: watch::Sender<bool>,
+    in_flight: AtomicUsize,
+    idle: Notify,
+}
+
+/// Held for the duration of one inference; moved into the blocking task so it
+/// is released when the work finishes, even if the client went away.
+pub(super) struct InFlight(Arc<Lifecycle>);
+
+impl Lifecycle {
+    pub(super) fn new() -> Arc<Self> {
+        Arc::new(Self {
+            stopping: watch::Sender::new(false),
+            in_flight: AtomicUsize::new(0),
+            idle: Notify::new(),
+        })
+    }
+
+    pub(super) fn is_stopping(&self) -> bool {
+        *self.stopping.borrow()
+    }
+
+    pub(super) fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Registers a new inference, or returns `None` once shutdown has begun.
+    pub(super) fn begin(self: &Arc<Self>) -> Option<InFlight> {
+        if self.is_stopping() {
+            return None;
+        }
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        Some(InFlight(self.clone()))
+    }
+
+    pub(super) fn stop(&self) {
+        self.stopping.send_replace(true);
+    }
+
+    /// Resolves once shutdown has begun.
+    pub(super) async fn stopped(&self) {
+        let mut rx = self.stopping.subscribe();
+        let _ = rx.wait_for(|stopping| *stopping).await;
+    }
+
+    /// Resolves once no inference is running.
+    pub(super) async fn idle(&self) {
+        loop {
+            let notified = self.idle.notified();
+            if self.in_flight() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Waits for SIGTERM or SIGINT, then marks the server as stopping.
+    pub(super) async fn stop_on_signal(self: Arc<Self>) {
+        let ctrl_c = async {
+            if let Err(err) = tokio::signal::ctrl_c().await {
+                error!("failed to listen for SIGINT: {err:?}");
+                std::future::pending::<()>().await;
+            }
+        };
+        #[cfg(unix)]
+        let terminate = async {
+            use tokio::signal::unix::{SignalKind, signal};
+            match signal(SignalKind::terminate()) {
+                Ok(mut stream) => {
+                    stream.recv().await;
+                }
+                Err(err) => {
+                    error!("failed to install SIGTERM handler: {err:?}");
+                    std::future::pending::<()>().await;
+                }
+            }
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = ctrl_c => {}
+            _ = terminate => {}
+            _ = self.stopped() => {}
+        }
+        info!("shutdown requested, draining in-flight requests");
+        self.stop();
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rejects_new_work_and_waits_for_running_work() {
+        let lifecycle = Lifecycle::new();
+        let running = lifecycle.begin().unwrap();
+        lifecycle.stop();
+        assert!(lifecycle.begin().is_none());
+        assert_eq!(lifecycle.in_flight(), 1);
+
+        let waiter = tokio::spawn({
+            let lifecycle = lifecycle.clone();
+            async move { lifecycle.idle().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+        drop(running);
+        waiter.await.unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use tracing::info;
+
+use super::{ApiError, ApiJson, ApiResult, AppState};
+use crate::settings::Preset;
+
+/// Presets from the configuration, plus any added or replaced through the
+/// admin API since startup.
+pub(super) struct Presets {
+    presets: RwLock<BTreeMap<String, Arc<Preset>>>,
+}
+
+impl Presets {
+    pub(super) fn new(presets: BTreeMap<String, Preset>) -> Self {
+        Self {
+            presets: RwLock::new(
+                presets
+                    .into_iter()
+                    .map(|(name, preset)| (name, Arc::new(preset)))
+                    .collect(),
+            ),
+        }
+    }
+
+    pub(super) fn names(&self) -> Vec<String> {
+        self.read().keys().cloned().collect()
+    }
+
+    /// The preset a request selects: the one named by `preset`, or else the
+    /// one named by `voice` when `is_speaker` says the model has no speaker
+    /// of that name. A voice that names a preset is cleared, so the preset's
+    /// voice applies.
+    pub(super) fn select(
+        &self,
+        preset: Option<&str>,
+        voice: &mut Option<String>,
+        is_speaker: impl Fn(&str) -> bool,
+    ) -> ApiResult<Option<Arc<Preset>>> {
+        let presets = self.read();
+        if let Some(name) = preset {
+            return match presets.get(name) {
+                Some(preset) => Ok(Some(preset.clone())),
+                None => Err(ApiError::not_found(format!(
+                    "preset '{name}' not found; available presets: {}",
+                    presets.keys().cloned().collect::<Vec<_>>().join(", ")
+                ))
+                .with_param("preset")
+                .with_code("preset_not_found")),
+            };
+        }
+        let Some(name) = voice.as_deref() else {
+            return Ok(None);
+        };
+        if is_speaker(name) {
+            return Ok(None);
+        }
+        let selected = presets.get(name).cloned();
+        if selected.is_some() {
+            *voice = None;
+        }
+        Ok(selected)
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, BTreeMap<String, Arc<Preset>>> {
+        self.presets.read().expect("presets lock poisoned")
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, BTreeMap<String, Arc<Preset>>> {
+        self.presets.write().expect("presets lock poisoned")
+    }
+}
+
+pub(super) async fn list_presets(State(state): State<AppState>) -> Json<BTreeMap<String, Preset>> {
+    let presets = state.presets.read();
+    Json(
+        presets
+            .iter()
+            .map(|(name, preset)| (name.clone(), Preset::clone(preset)))
+            .collect(),
+    )
+}
+
+pub(super) async fn get_preset(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> ApiResult<Json<Preset>> {
+    let presets = state.presets.read();
+    let preset = presets.get(&name).ok_or_else(|| not_found(&name))?;
+    Ok(Json(Preset::clone(preset)))
+}
+
+/// Adds the preset or replaces the one of the same name.
+pub(super) async fn put_preset(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    ApiJson(preset): ApiJson<Preset>,
+) -> ApiResult<Json<Preset>> {
+    if name.trim().is_empty() {
+        return Err(ApiError::bad_request("preset name must not be empty"));
+    }
+    preset.validate()?;
+    info!("storing preset '{name}'");
+    state.presets.write().insert(name, Arc::new(preset.clone()));
+    Ok(Json(preset))
+}
+
+pub(super) async fn delete_preset(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> ApiResult<StatusCode> {
+    state
+        .presets
+        .write()
+        .remove(&name)
+        .ok_or_else(|| not_found(&name))?;
+    info!("deleted preset '{name}'");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn not_found(name: &str) -> ApiError {
+    ApiError::not_found(format!("preset '{name}' not found")).with_code("preset_not_found")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn presets() -> Presets {
+        let preset = |voice: &str| Preset {
+            voice: Some(voice.to_string()),
+            ..Default::default()
+        };
+        Presets::new(BTreeMap::from([
+            ("narrator".to_string(), preset("xiaoming")),
+            ("xiaohong".to_string(), preset("xiaoming")),
+        ]))
+    }
+
+    fn is_speaker(name: &str) -> bool {
+        matches!(name, "xiaoming" | "xiaohong")
+    }
+
+    #[test]
+    fn a_voice_naming_a_preset_selects_it_unless_it_is_a_speaker() {
+        let presets = presets();
+
+        let mut voice = Some("narrator".to_string());
+        let selected = presets.select(None, &mut voice, is_speaker).unwrap();
+        assert_eq!(selected.unwrap().voice.as_deref(), Some("xiaoming"));
+        assert_eq!(voice, None);
+
+        // A real speaker shadows the preset of the same name.
+        let mut voice = Some("xiaohong".to_string());
+        assert!(
+            presets
+                .select(None, &mut voice, is_speaker)
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(voice.as_deref(), Some("xiaohong"));
+
+        let mut voice = Some("xiaohong".to_string());
+        assert!(
+            presets
+                .select(Some("narrator"), &mut voice, is_speaker)
+                .unwrap()
+                .is_some()
+        );
+        assert_eq!(voice.as_deref(), Some("xiaohong"));
+    }
+
+    #[test]
+    fn an_unknown_preset_is_not_found() {
+        let err = presets()
+            .select(Some("missing"), &mut None, is_speaker)
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+        assert_eq!(err.param, Some("preset"));
+        assert_eq!(err.code, Some("preset_not_found"));
+        assert!(err.message.contains("narrator, xiaohong"));
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU8, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::{error, info};
+
+use crate::{
+    inference::ChineseSynthesisInput,
+    registry::{ModelEntry, ModelRegistry},
+};
+
+const PROBE_TEXT: &str = "嗯。";
+/// Minimum time between two liveness syntheses; probes in between reuse the
+/// previous outcome. A request that succeeded within this time also proves
+/// the sessions work.
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+const WARMING_UP: u8 = 0;
+const READY: u8 = 1;
+const FAILED: u8 = 2;
+
+/// Readiness after warm-up and a rate-limited liveness check of the sessions.
+pub(super) struct Probe {
+    state: AtomicU8,
+    /// Held for the whole check, so concurrent probes share one synthesis.
+    last_check: AsyncMutex<Option<(Instant, Result<(), String>)>>,
+    last_success: Mutex<Option<Instant>>,
+}
+
+pub(super) enum Readiness {
+    WarmingUp,
+    Ready,
+    Failed,
+}
+
+impl Probe {
+    pub(super) fn new() -> Arc<Self> {
+        Arc::new(Self {
+            state: AtomicU8::new(WARMING_UP),
+            last_check: AsyncMutex::new(None),
+            last_success: Mutex::new(None),
+        })
+    }
+
+    pub(super) fn readiness(&self) -> Readiness {
+        match self.state.load(Ordering::SeqCst) {
+            READY => Readiness::Ready,
+            FAILED => Readiness::Failed,
+            _ => Readiness::WarmingUp,
+        }
+    }
+
+    /// Runs every warm-up text through every model so ORT compiles its kernels
+    /// before the first real request, then marks the server ready.
+    pub(super) async fn warm_up(self: Arc<Self>, models: Arc<ModelRegistry>, texts: Vec<String>) {
+        let start = Instant::now();
+        let result = tokio::task::spawn_blocking(move || synthesize_all(&models, &texts))
+            .await
+            .context("warm-up task panicked")
+            .and_then(|result| result);
+        match result {
+            Ok(()) => {
+                info!("warm-up finished in {} ms", start.elapsed().as_millis());
+                self.state.store(READY, Ordering::SeqCst);
+            }
+            Err(err) => {
+                error!("warm-up failed, server stays unready: {err:?}");
+                self.state.store(FAILED, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Notes a request that synthesised successfully.
+    pub(super) fn record_success(&self) {
+        *self.last_success.lock().expect("probe mutex poisoned") = Some(Instant::now());
+    }
+
+    /// Checks the sessions can still synthesise. Only runs once warm-up is
+    /// done; until then the process is alive but busy. A recent successful
+    /// request counts as a check, and models with every VITS replica busy are
+    /// skipped rather than waited for, so probes never queue behind requests.
+    pub(super) async fn check(&self, models: &Arc<ModelRegistry>) -> Result<(), String> {
+        if !matches!(self.readiness(), Readiness::Ready) {
+            return Ok(());
+        }
+        let mut last_check = self.last_check.lock().await;
+        if let Some((at, result)) = last_check.as_ref()
+            && at.elapsed() < PROBE_INTERVAL
+        {
+            return result.clone();
+        }
+        let served = *self.last_success.lock().expect("probe mutex poisoned");
+        if served.is_some_and(|at| at.elapsed() < PROBE_INTERVAL) {
+            return Ok(());
+        }
+
+        let models = models.clone();
+        let result = tokio::task::spawn_blocking(move || synthesize_idle(&models))
+            .await
+            .map_err(|err| format!("health check panicked: {err}"))
+            .and_then(|result| result.map_err(|err| format!("{err:#}")));
+        *last_check = Some((Instant::now(), result.clone()));
+        result
+    }
+}
+
+/// Synthesises the probe text with every model that has an idle VITS
+/// replica.
+fn synthesize_idle(models: &ModelRegistry) -> Result<()> {
+    let texts = [PROBE_TEXT.to_string()];
+    for (name, entry) in models.iter() {
+        if entry.synthesizer.project().is_busy() {
+            continue;
+        }
+        synthesize(name, entry, &texts)?;
+    }
+    Ok(())
+}
+
+fn synthesize_all(models: &ModelRegistry, texts: &[String]) -> Result<()> {
+    for (name, entry) in models.iter() {
+        synthesize(name, entry, texts)?;
+    }
+    Ok(())
+}
+
+fn synthesize(name: &str, entry: &ModelEntry, texts: &[String]) -> Result<()> {
+    for text in texts {
+        let result = entry
+            .synthesizer
+            .synthesize(&ChineseSynthesisInput::new(text.as_str()))
+            .with_context(|| format!("model '{name}' failed to synthesise '{text}'"))?;
+        if result.pcm.is_empty() {
+            bail!("model '{name}' produced no audio for '{text}'");
+        }
+    }
+    Ok(())
+}
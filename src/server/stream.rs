@@ -0,0 +1,549 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use axum::{
+    Extension,
+    extract::{
+        State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    response::Response,
+};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{debug, error};
+
+use super::{ApiError, AppState, AudioFormat, auth::Caller};
+use crate::{
+    audio,
+    constants::MAX_INPUT_CHARS,
+    errors::TtsError,
+    inference::ChineseSynthesisInput,
+    nlp::chinese::{g2p::is_punctuation_char, normalizer},
+};
+
+const SENTENCE_TERMINATORS: [char; 4] = ['.', '!', '?', '…'];
+
+/// Sentences queued for synthesis; text beyond that is turned away.
+const JOB_QUEUE: usize = 32;
+/// Messages queued for a client that is slow to read before synthesis waits.
+const OUTGOING_QUEUE: usize = 16;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Config(Box<StreamConfig>),
+    Text { text: String },
+    Flush,
+    Cancel,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+struct StreamConfig {
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    voice: Option<String>,
+    #[serde(default)]
+    preset: Option<String>,
+    #[serde(default)]
+    style: Option<String>,
+    #[serde(default)]
+    style_weight: Option<f32>,
+    #[serde(default)]
+    style_mix: Option<BTreeMap<String, f32>>,
+    #[serde(default)]
+    style_vector: Option<Vec<f32>>,
+    #[serde(default)]
+    noise: Option<f32>,
+    #[serde(default)]
+    noise_w: Option<f32>,
+    #[serde(default)]
+    sdp_ratio: Option<f32>,
+    #[serde(default)]
+    speed: Option<f32>,
+    #[serde(default)]
+    length_scale: Option<f32>,
+    #[serde(default)]
+    assist_text: Option<String>,
+    #[serde(default)]
+    assist_prompt: Option<String>,
+    #[serde(default)]
+    assist_weight: Option<f32>,
+    #[serde(default)]
+    pitch: Option<f32>,
+    #[serde(default)]
+    volume: Option<f32>,
+    #[serde(default)]
+    seed: Option<u64>,
+    #[serde(default)]
+    audio_format: Option<AudioFormat>,
+}
+
+impl StreamConfig {
+    fn to_input(&self, text: String) -> Result<ChineseSynthesisInput, TtsError> {
+        let mut input = ChineseSynthesisInput::new(text);
+        input.speaker = self.voice.clone();
+        input.style = self.style.clone();
+        input.style_weight = self.style_weight;
+        input.style_mix = self.style_mix.clone();
+        input.style_vector = self.style_vector.clone();
+        input.noise = self.noise;
+        input.noise_w = self.noise_w;
+        input.sdp_ratio = self.sdp_ratio;
+        input.assist_text = self.assist_text.clone();
+        input.assist_prompt = self.assist_prompt.clone();
+        input.assist_weight = self.assist_weight;
+        input.pitch = self.pitch;
+        input.volume = self.volume;
+        input.seed = self.seed;
+        if let Some(ls) = self.length_scale {
+            input.length_scale = Some(ls);
+        } else if let Some(speed) = self.speed {
+            if speed <= 0.0 {
+                return Err(TtsError::invalid("speed", "speed must be greater than 0"));
+            }
+            input.length_scale = Some(1.0 / speed);
+        }
+        Ok(input)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerEvent {
+    Audio {
+        index: usize,
+        text: String,
+        audio_format: &'static str,
+        sample_rate: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seed: Option<u64>,
+    },
+    Flushed,
+    Cancelled,
+    Error {
+        message: String,
+        code: Option<&'static str>,
+    },
+}
+
+impl ServerEvent {
+    fn error(message: impl Into<String>) -> Message {
+        Self::Error {
+            message: message.into(),
+            code: None,
+        }
+        .into_message()
+    }
+
+    fn api_error(err: ApiError) -> Message {
+        Self::Error {
+            message: err.message,
+            code: err.code,
+        }
+        .into_message()
+    }
+
+    fn into_message(self) -> Message {
+        let json = serde_json::to_string(&self).unwrap_or_default();
+        Message::Text(json.into())
+    }
+}
+
+enum Job {
+    Sentence {
+        generation: u64,
+        index: usize,
+        text: String,
+        config: Arc<StreamConfig>,
+    },
+    Flush {
+        generation: u64,
+    },
+}
+
+pub(super) async fn speech_stream(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    caller: Option<Extension<Caller>>,
+) -> Response {
+    let caller = caller.map(|Extension(caller)| caller);
+    ws.on_upgrade(move |socket| handle_socket(socket, state, caller))
+}
+
+async fn handle_socket(socket: WebSocket, state: AppState, caller: Option<Caller>) {
+    let lifecycle = state.lifecycle.clone();
+    let (mut sink, mut stream) = socket.split();
+    let (out_tx, mut out_rx) = mpsc::channel::<Message>(OUTGOING_QUEUE);
+    let (job_tx, job_rx) = mpsc::channel::<Job>(JOB_QUEUE);
+    // Bumped on cancel; jobs and results from an older generation are dropped.
+    let generation = Arc::new(AtomicU64::new(0));
+
+    let writer = tokio::spawn(async move {
+        while let Some(message) = out_rx.recv().await {
+            if sink.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
+    let worker = tokio::spawn(run_jobs(
+        state,
+        caller,
+        job_rx,
+        out_tx.clone(),
+        generation.clone(),
+    ));
+
+    let mut reader = Reader::new(job_tx, generation.clone());
+    loop {
+        // Stop reading on shutdown; sentences still queued are answered with
+        // an error while the one being synthesised completes.
+        let message = tokio::select! {
+            message = stream.next() => message,
+            _ = lifecycle.stopped() => break,
+        };
+        let Some(Ok(message)) = message else {
+            break;
+        };
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let reply = match serde_json::from_str::<ClientMessage>(text.as_str()) {
+            Ok(parsed) => reader.handle(parsed),
+            Err(err) => Some(ServerEvent::error(format!("invalid message: {err}"))),
+        };
+        if let Some(reply) = reply {
+            let _ = out_tx.send(reply).await;
+        }
+    }
+
+    debug!("speech stream closed");
+    generation.fetch_add(1, Ordering::SeqCst);
+    drop(reader);
+    drop(out_tx);
+    let _ = worker.await;
+    let _ = writer.await;
+}
+
+/// The client's side of a stream: the current config and the text not yet
+/// split into sentences. Sentences are queued without waiting, so a full
+/// queue turns text away instead of holding up `cancel` behind it.
+struct Reader {
+    config: Arc<StreamConfig>,
+    buffer: String,
+    next_index: usize,
+    generation: Arc<AtomicU64>,
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Reader {
+    fn new(jobs: mpsc::Sender<Job>, generation: Arc<AtomicU64>) -> Self {
+        Self {
+            config: Arc::new(StreamConfig::default()),
+            buffer: String::new(),
+            next_index: 0,
+            generation,
+            jobs,
+        }
+    }
+
+    /// Applies one client message, returning the reply to send right away.
+    fn handle(&mut self, message: ClientMessage) -> Option<Message> {
+        match message {
+            ClientMessage::Config(update) => {
+                self.config = Arc::new(*update);
+                None
+            }
+            ClientMessage::Text { text } => {
+                let mut buffer = format!("{}{text}", self.buffer);
+                let sentences = take_sentences(&mut buffer, MAX_INPUT_CHARS);
+                if let Err(reply) = self.reserve(sentences.len()) {
+                    return Some(reply);
+                }
+                self.buffer = buffer;
+                for sentence in sentences {
+                    self.enqueue_sentence(sentence);
+                }
+                None
+            }
+            ClientMessage::Flush => {
+                let has_rest = !self.buffer.trim().is_empty();
+                if let Err(reply) = self.reserve(usize::from(has_rest) + 1) {
+                    return Some(reply);
+                }
+                let rest = std::mem::take(&mut self.buffer);
+                if has_rest {
+                    self.enqueue_sentence(rest);
+                }
+                self.enqueue(Job::Flush {
+                    generation: self.generation.load(Ordering::SeqCst),
+                });
+                None
+            }
+            ClientMessage::Cancel => {
+                self.buffer.clear();
+                self.generation.fetch_add(1, Ordering::SeqCst);
+                Some(ServerEvent::Cancelled.into_message())
+            }
+        }
+    }
+
+    /// Checks the queue has room for `jobs` more. Only this reader queues
+    /// jobs, so the room cannot shrink before they are sent.
+    fn reserve(&self, jobs: usize) -> Result<(), Message> {
+        if jobs <= self.jobs.capacity() {
+            return Ok(());
+        }
+        let err = TtsError::Overloaded(format!(
+            "{JOB_QUEUE} sentences are already waiting for synthesis; the message was \
+             dropped, retry once audio has arrived or send cancel"
+        ));
+        Err(ServerEvent::api_error(err.into()))
+    }
+
+    fn enqueue_sentence(&mut self, text: String) {
+        self.enqueue(Job::Sentence {
+            generation: self.generation.load(Ordering::SeqCst),
+            index: self.next_index,
+            text,
+            config: self.config.clone(),
+        });
+        self.next_index += 1;
+    }
+
+    fn enqueue(&self, job: Job) {
+        // Room was reserved; a closed queue means the stream is ending.
+        let _ = self.jobs.try_send(job);
+    }
+}
+
+async fn run_jobs(
+    state: AppState,
+    caller: Option<Caller>,
+    mut jobs: mpsc::Receiver<Job>,
+    out: mpsc::Sender<Message>,
+    generation: Arc<AtomicU64>,
+) {
+    while let Some(job) = jobs.recv().await {
+        match job {
+            Job::Flush {
+                generation: job_gen,
+            } => {
+                if job_gen == generation.load(Ordering::SeqCst) {
+                    let _ = out.send(ServerEvent::Flushed.into_message()).await;
+                }
+            }
+            Job::Sentence {
+                generation: job_gen,
+                index,
+                text,
+                config,
+            } => {
+                if job_gen != generation.load(Ordering::SeqCst) {
+                    continue;
+                }
+                let messages =
+                    synthesize_sentence(&state, caller.as_ref(), index, text, &config).await;
+                if job_gen != generation.load(Ordering::SeqCst) {
+                    continue;
+                }
+                for message in messages {
+                    let _ = out.send(message).await;
+                }
+            }
+        }
+    }
+}
+
+async fn synthesize_sentence(
+    state: &AppState,
+    caller: Option<&Caller>,
+    index: usize,
+    text: String,
+    config: &StreamConfig,
+) -> Vec<Message> {
+    let format = config.audio_format.unwrap_or_default();
+    let models = &state.models;
+    let Some((model_name, entry)) = models.resolve(config.model.as_deref()) else {
+        let err = models.unknown(config.model.as_deref());
+        return vec![ServerEvent::api_error(err.into())];
+    };
+    let mut input = match config.to_input(text.clone()) {
+        Ok(input) => input,
+        Err(err) => return vec![ServerEvent::api_error(err.into())],
+    };
+    let project = entry.synthesizer.project();
+    input.preset =
+        match state
+            .presets
+            .select(config.preset.as_deref(), &mut input.speaker, |name| {
+                project.speaker_id(name).is_some()
+            }) {
+            Ok(preset) => preset,
+            Err(err) => return vec![ServerEvent::api_error(err)],
+        };
+
+    let reservation = match caller {
+        Some(caller) => {
+            let voice = input.speaker().or_else(|| project.default_speaker_name());
+            match caller
+                .authorize(model_name, voice)
+                .and_then(|()| caller.reserve(text.chars().count()))
+            {
+                Ok(reservation) => Some(reservation),
+                Err(err) => return vec![ServerEvent::api_error(err)],
+            }
+        }
+        None => None,
+    };
+
+    let Some(in_flight) = state.lifecycle.begin() else {
+        let err = TtsError::Overloaded("server is shutting down".into());
+        return vec![ServerEvent::api_error(err.into())];
+    };
+
+    let synthesizer = entry.synthesizer.clone();
+    let mp3_bitrate = state.mp3_bitrate;
+    let result = tokio::task::spawn_blocking(move || {
+        let _in_flight = in_flight;
+        let result = synthesizer.synthesize(&input)?;
+        let bytes = match format {
+            AudioFormat::Wav => result.wav,
+            AudioFormat::Mp3 => audio::pcm_to_mp3(&result.pcm, result.sample_rate, mp3_bitrate)?,
+        };
+        anyhow::Ok((bytes, result.sample_rate, result.seed))
+    })
+    .await;
+
+    match result {
+        Ok(Ok((bytes, sample_rate, seed))) => {
+            state.probe.record_success();
+            if let Some(reservation) = reservation {
+                reservation.commit();
+            }
+            vec![
+                ServerEvent::Audio {
+                    index,
+                    text,
+                    audio_format: format.as_str(),
+                    sample_rate,
+                    seed,
+                }
+                .into_message(),
+                Message::Binary(bytes.into()),
+            ]
+        }
+        Ok(Err(err)) => {
+            error!("streaming synthesis failed: {err:?}");
+            vec![ServerEvent::api_error(ApiError::from_anyhow(err))]
+        }
+        Err(err) => vec![ServerEvent::error(format!(
+            "inference task panicked: {err}"
+        ))],
+    }
+}
+
+/// Removes every complete sentence from the front of `buffer`, leaving the
+/// unterminated tail for the next fragment. Text running to `max_chars`
+/// characters without a sentence end is cut there, so neither the buffer
+/// nor a sentence grows past what one synthesis accepts.
+fn take_sentences(buffer: &mut String, max_chars: usize) -> Vec<String> {
+    let mut sentences = Vec::new();
+    let mut consumed = 0usize;
+    let mut pending = 0usize;
+    for (idx, ch) in buffer.char_indices() {
+        pending += 1;
+        if !is_sentence_boundary(ch) && pending < max_chars {
+            continue;
+        }
+        pending = 0;
+        let end = idx + ch.len_utf8();
+        let sentence = &buffer[consumed..end];
+        if !sentence.trim().is_empty() {
+            sentences.push(sentence.to_string());
+        }
+        consumed = end;
+    }
+    buffer.drain(..consumed);
+    sentences
+}
+
+fn is_sentence_boundary(ch: char) -> bool {
+    let mut utf8 = [0u8; 4];
+    normalizer::replace_punctuation(ch.encode_utf8(&mut utf8))
+        .chars()
+        .next()
+        .is_some_and(|mapped| is_punctuation_char(mapped) && SENTENCE_TERMINATORS.contains(&mapped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_sentences_keeps_unterminated_tail() {
+        let mut buffer = String::from("你好。今天天气不错！我们");
+        let sentences = take_sentences(&mut buffer, MAX_INPUT_CHARS);
+        assert_eq!(sentences, vec!["你好。", "今天天气不错！"]);
+        assert_eq!(buffer, "我们");
+    }
+
+    #[test]
+    fn take_sentences_ignores_commas() {
+        let mut buffer = String::from("首先，");
+        assert!(take_sentences(&mut buffer, MAX_INPUT_CHARS).is_empty());
+        assert_eq!(buffer, "首先，");
+    }
+
+    #[test]
+    fn take_sentences_cuts_text_without_sentence_ends() {
+        let mut buffer = "一二三四五六七".to_string();
+        assert_eq!(take_sentences(&mut buffer, 3), vec!["一二三", "四五六"]);
+        assert_eq!(buffer, "七");
+
+        let mut buffer = "一二。三四五六".to_string();
+        assert_eq!(take_sentences(&mut buffer, 3), vec!["一二。", "三四五"]);
+        assert_eq!(buffer, "六");
+    }
+
+    #[test]
+    fn cancel_is_answered_while_the_queue_is_full() {
+        let (jobs, mut queued) = mpsc::channel(JOB_QUEUE);
+        let generation = Arc::new(AtomicU64::new(0));
+        let mut reader = Reader::new(jobs, generation.clone());
+        let text = |text: String| ClientMessage::Text { text };
+
+        assert!(reader.handle(text("好。".repeat(JOB_QUEUE))).is_none());
+        assert_eq!(reader.jobs.capacity(), 0);
+
+        // Rejected as a whole, without waiting for room.
+        let reply = reader.handle(text("再来。还有".to_string())).unwrap();
+        assert!(matches!(&reply, Message::Text(json) if json.contains("overloaded")));
+        assert!(reader.buffer.is_empty());
+        assert!(reader.handle(ClientMessage::Flush).is_some());
+
+        let reply = reader.handle(ClientMessage::Cancel).unwrap();
+        assert!(matches!(&reply, Message::Text(json) if json.contains("cancelled")));
+        assert_eq!(generation.load(Ordering::SeqCst), 1);
+
+        // Once the worker has skipped the stale jobs, text is queued again
+        // under the new generation.
+        while queued.try_recv().is_ok() {}
+        assert!(reader.handle(text("新的。".to_string())).is_none());
+        match queued.try_recv().unwrap() {
+            Job::Sentence {
+                generation, text, ..
+            } => assert_eq!((generation, text.as_str()), (1, "新的。")),
+            Job::Flush { .. } => panic!("expected a sentence"),
+        }
+    }
+}
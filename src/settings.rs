@@ -0,0 +1,1168 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use ort::GraphOptimizationLevel;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    constants::{
+        DEFAULT_ASSIST_TEXT_WEIGHT, DEFAULT_LENGTH, DEFAULT_NOISE, DEFAULT_NOISEW,
+        DEFAULT_SDP_RATIO, DEFAULT_STYLE_WEIGHT,
+    },
+    errors::TtsError,
+    inference::SynthesisDefaults,
+};
+
+/// Effective server configuration. Built from the defaults below, then a
+/// TOML/YAML file, then `SBV2_*` environment variables and command-line flags,
+/// each layer overriding the previous one.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub runtime: RuntimeSettings,
+    pub model: ModelSettings,
+    pub audio: AudioSettings,
+    pub assets: AssetSettings,
+    pub presets: BTreeMap<String, Preset>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub listen: String,
+    /// Seconds to let in-flight requests finish after SIGTERM/SIGINT.
+    pub shutdown_timeout: u64,
+    pub warmup_texts: Vec<String>,
+    pub api_keys: Option<PathBuf>,
+    /// Maximum number of cached synthesis responses; 0 disables the cache.
+    pub cache_entries: usize,
+    pub cache_max_mb: usize,
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            listen: "0.0.0.0:8080".to_string(),
+            shutdown_timeout: 30,
+            warmup_texts: vec![
+                "你好。".to_string(),
+                "今天天气很好，我们一起去公园散步吧。".to_string(),
+            ],
+            api_keys: None,
+            cache_entries: 0,
+            cache_max_mb: 256,
+            cache_dir: None,
+        }
+    }
+}
+
+/// ONNX Runtime options, set separately for the VITS and BERT sessions so the
+/// two do not oversubscribe the CPU.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeSettings {
+    /// Execution providers tried in order for every session. The CPU is used
+    /// when none of them initialises, and providers after `cpu` are ignored.
+    pub execution_providers: Vec<Provider>,
+    pub cuda: DeviceOptions,
+    pub tensorrt: DeviceOptions,
+    pub rocm: DeviceOptions,
+    pub openvino: OpenVinoOptions,
+    pub vits: SessionSettings,
+    pub bert: SessionSettings,
+    /// Number of assist texts whose BERT features are kept in memory; 0
+    /// disables the cache. Named prompts are kept regardless.
+    pub assist_cache_entries: usize,
+    /// Directory the mean features of assist texts are saved in, so they
+    /// survive restarts.
+    pub assist_cache_dir: Option<PathBuf>,
+    /// Named assist texts, computed at startup and never evicted. Requests
+    /// select one with `assist_prompt`.
+    pub assist_prompts: BTreeMap<String, String>,
+    /// Number of sentences whose BERT features are kept in memory for
+    /// requests repeating them; 0 disables the cache.
+    pub bert_cache_entries: usize,
+    pub bert_cache_max_mb: usize,
+    /// How long a request waits for a free session replica before it is
+    /// rejected as overloaded.
+    pub checkout_timeout_ms: u64,
+    /// Seconds without BERT requests after which the BERT sessions are
+    /// released; they are loaded again on the next request. 0 keeps them.
+    pub bert_idle_timeout_secs: u64,
+    /// Unix socket of a `bert-sidecar` process to send BERT work to instead
+    /// of loading the BERT model in this process.
+    pub bert_socket: Option<PathBuf>,
+    /// How long a read or write on the sidecar socket may block before the
+    /// request is rejected as overloaded; 0 waits indefinitely.
+    pub bert_socket_timeout_ms: u64,
+}
+
+impl Default for RuntimeSettings {
+    fn default() -> Self {
+        Self {
+            execution_providers: Provider::compiled_defaults(),
+            cuda: DeviceOptions::default(),
+            tensorrt: DeviceOptions::default(),
+            rocm: DeviceOptions::default(),
+            openvino: OpenVinoOptions::default(),
+            vits: SessionSettings::default(),
+            bert: SessionSettings::default(),
+            assist_cache_entries: 256,
+            assist_cache_dir: None,
+            assist_prompts: BTreeMap::new(),
+            bert_cache_entries: 256,
+            bert_cache_max_mb: 64,
+            checkout_timeout_ms: 30_000,
+            bert_idle_timeout_secs: 0,
+            bert_socket: None,
+            bert_socket_timeout_ms: 60_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionSettings {
+    /// Independent sessions loaded from the same model, each serving one
+    /// request at a time. Combine with `intra_threads` to split the cores.
+    pub replicas: usize,
+    /// Model file variant to load; unset uses the file as given (VITS) or
+    /// the first of fp16, fp32 found (BERT).
+    pub precision: Option<Precision>,
+    pub optimization_level: OptimizationLevel,
+    pub execution_mode: ExecutionMode,
+    /// Threads used within an operator; unset lets ORT decide.
+    pub intra_threads: Option<i16>,
+    /// Threads used across operators in parallel execution mode.
+    pub inter_threads: Option<i16>,
+    /// Allocate inputs from ORT's arena allocator instead of the device
+    /// allocator.
+    pub memory_arena: bool,
+    /// Pre-plan allocations from the shapes of the first run.
+    pub memory_pattern: bool,
+    /// Memory-map the model file and run from the mapping instead of reading
+    /// it into the heap; the model must be in ORT format.
+    pub memory_map: bool,
+    /// Directory where the optimised graph is saved on first load and read
+    /// back on later starts, skipping graph optimisation.
+    pub optimized_model_dir: Option<PathBuf>,
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        Self {
+            replicas: 1,
+            precision: None,
+            optimization_level: OptimizationLevel::All,
+            execution_mode: ExecutionMode::Parallel,
+            intra_threads: None,
+            inter_threads: None,
+            memory_arena: false,
+            memory_pattern: true,
+            memory_map: false,
+            optimized_model_dir: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OptimizationLevel {
+    Disable,
+    Basic,
+    Extended,
+    All,
+}
+
+impl OptimizationLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Disable => "disable",
+            Self::Basic => "basic",
+            Self::Extended => "extended",
+            Self::All => "all",
+        }
+    }
+}
+
+impl From<OptimizationLevel> for GraphOptimizationLevel {
+    fn from(level: OptimizationLevel) -> Self {
+        match level {
+            OptimizationLevel::Disable => GraphOptimizationLevel::Disable,
+            OptimizationLevel::Basic => GraphOptimizationLevel::Level1,
+            OptimizationLevel::Extended => GraphOptimizationLevel::Level2,
+            OptimizationLevel::All => GraphOptimizationLevel::Level3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecutionMode {
+    Sequential,
+    Parallel,
+}
+
+/// Weight precision of a model file. Variants of `name.onnx` sit next to it
+/// as `name_fp16.onnx` and `name_int8.onnx`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    Fp32,
+    Fp16,
+    Int8,
+}
+
+impl Precision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Fp32 => "fp32",
+            Self::Fp16 => "fp16",
+            Self::Int8 => "int8",
+        }
+    }
+
+    /// The file of this precision next to `model`, which may itself be any
+    /// variant: `voice_fp16.onnx` at int8 is `voice_int8.onnx`.
+    pub fn variant_of(&self, model: &Path) -> PathBuf {
+        let stem = model
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let base = Self::base_stem(&stem);
+        let name = match self {
+            Self::Fp32 => format!("{base}.onnx"),
+            _ => format!("{base}_{}.onnx", self.as_str()),
+        };
+        model.with_file_name(name)
+    }
+
+    /// `stem` without a `_fp16`/`_int8` suffix.
+    pub fn base_stem(stem: &str) -> &str {
+        [Self::Fp16, Self::Int8]
+            .iter()
+            .find_map(|precision| stem.strip_suffix(&format!("_{}", precision.as_str())))
+            .unwrap_or(stem)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    Cpu,
+    Cuda,
+    Tensorrt,
+    Rocm,
+    Openvino,
+    Coreml,
+    Xnnpack,
+}
+
+impl Provider {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Cpu => "cpu",
+            Self::Cuda => "cuda",
+            Self::Tensorrt => "tensorrt",
+            Self::Rocm => "rocm",
+            Self::Openvino => "openvino",
+            Self::Coreml => "coreml",
+            Self::Xnnpack => "xnnpack",
+        }
+    }
+
+    /// Whether this binary was built with the `ort` bindings for the
+    /// provider. The `ort` release in use has no XNNPACK binding at all.
+    pub fn is_compiled(&self) -> bool {
+        match self {
+            Self::Cpu => true,
+            Self::Cuda => cfg!(feature = "cuda"),
+            Self::Tensorrt => cfg!(feature = "tensorrt"),
+            Self::Rocm => cfg!(feature = "rocm"),
+            Self::Openvino => cfg!(feature = "openvino"),
+            Self::Coreml => cfg!(feature = "coreml"),
+            Self::Xnnpack => false,
+        }
+    }
+
+    /// Every compiled-in accelerator, most specialised first, then the CPU.
+    fn compiled_defaults() -> Vec<Self> {
+        [
+            Self::Tensorrt,
+            Self::Cuda,
+            Self::Coreml,
+            Self::Rocm,
+            Self::Openvino,
+            Self::Cpu,
+        ]
+        .into_iter()
+        .filter(Self::is_compiled)
+        .collect()
+    }
+}
+
+/// Options for a GPU execution provider. For TensorRT the memory limit caps
+/// the builder workspace.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceOptions {
+    pub device_id: u32,
+    /// Device memory ORT may claim, in MiB; unset leaves it unlimited.
+    pub memory_limit_mb: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenVinoOptions {
+    /// Target device and precision such as `CPU_FP32` or `GPU_FP16`; unset
+    /// uses the OpenVINO build default.
+    pub device_type: Option<String>,
+}
+
+/// Model locations and the synthesis parameters used when a request leaves
+/// them unset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelSettings {
+    pub model: Option<PathBuf>,
+    pub config: Option<PathBuf>,
+    pub style_vectors: Option<PathBuf>,
+    pub model_dir: Option<PathBuf>,
+    pub bert_root: Option<PathBuf>,
+    #[serde(serialize_with = "shortest_f32")]
+    pub style_weight: f32,
+    #[serde(serialize_with = "shortest_f32")]
+    pub sdp_ratio: f32,
+    #[serde(serialize_with = "shortest_f32")]
+    pub noise: f32,
+    #[serde(serialize_with = "shortest_f32")]
+    pub noise_w: f32,
+    #[serde(serialize_with = "shortest_f32")]
+    pub length_scale: f32,
+    #[serde(serialize_with = "shortest_f32")]
+    pub assist_weight: f32,
+}
+
+impl Default for ModelSettings {
+    fn default() -> Self {
+        Self {
+            model: None,
+            config: None,
+            style_vectors: None,
+            model_dir: None,
+            bert_root: None,
+            style_weight: DEFAULT_STYLE_WEIGHT,
+            sdp_ratio: DEFAULT_SDP_RATIO,
+            noise: DEFAULT_NOISE,
+            noise_w: DEFAULT_NOISEW,
+            length_scale: DEFAULT_LENGTH,
+            assist_weight: DEFAULT_ASSIST_TEXT_WEIGHT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioSettings {
+    /// Peak amplitude output is normalised to, in `(0, 1]`.
+    #[serde(serialize_with = "shortest_f32")]
+    pub peak_target: f32,
+    /// MP3 bitrate in kbit/s.
+    pub mp3_bitrate: u32,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            peak_target: 0.97,
+            mp3_bitrate: 192,
+        }
+    }
+}
+
+/// Where the BERT model files are fetched from when they are missing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AssetSettings {
+    /// Never download; fail at startup if a file is missing.
+    pub offline: bool,
+    /// Base URL of Hugging Face or a compatible mirror.
+    pub mirror: String,
+    pub repo: String,
+    pub revision: String,
+    /// Proxy URL for downloads. Without it the usual `HTTPS_PROXY`
+    /// environment variables apply.
+    pub proxy: Option<String>,
+    /// Expected SHA-256 digests by file name. They take precedence over the
+    /// digests published by the mirror and those recorded in `SHA256SUMS`.
+    pub sha256: BTreeMap<String, String>,
+}
+
+impl Default for AssetSettings {
+    fn default() -> Self {
+        Self {
+            offline: false,
+            mirror: "https://huggingface.co".to_string(),
+            repo: "tsukumijima/chinese-roberta-wwm-ext-large-onnx".to_string(),
+            revision: "main".to_string(),
+            proxy: None,
+            sha256: BTreeMap::new(),
+        }
+    }
+}
+
+/// A named bundle of synthesis parameters. Requests select one with `preset`
+/// or by naming it as their `voice`; anything the request sets itself takes
+/// precedence.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Preset {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub voice: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub style: Option<String>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "shortest_opt_f32"
+    )]
+    pub style_weight: Option<f32>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "shortest_opt_f32"
+    )]
+    pub noise: Option<f32>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "shortest_opt_f32"
+    )]
+    pub noise_w: Option<f32>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "shortest_opt_f32"
+    )]
+    pub sdp_ratio: Option<f32>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "shortest_opt_f32"
+    )]
+    pub speed: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assist_text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assist_prompt: Option<String>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "shortest_opt_f32"
+    )]
+    pub assist_weight: Option<f32>,
+}
+
+impl Preset {
+    /// Rejects the values a request carrying them would be rejected for.
+    /// Speakers, styles and prompts are model specific and checked per
+    /// request.
+    pub fn validate(&self) -> std::result::Result<(), TtsError> {
+        for (param, value) in [
+            ("style_weight", self.style_weight),
+            ("noise", self.noise),
+            ("noise_w", self.noise_w),
+            ("sdp_ratio", self.sdp_ratio),
+            ("speed", self.speed),
+            ("assist_weight", self.assist_weight),
+        ] {
+            if value.is_some_and(|value| !value.is_finite()) {
+                return Err(TtsError::invalid(
+                    param,
+                    format!("{param} must be a finite number"),
+                ));
+            }
+        }
+        for (param, value) in [
+            ("style_weight", self.style_weight),
+            ("sdp_ratio", self.sdp_ratio),
+            ("assist_weight", self.assist_weight),
+        ] {
+            if value.is_some_and(|value| !(0.0..=1.0).contains(&value)) {
+                return Err(TtsError::invalid(
+                    param,
+                    format!("{param} must be within [0.0, 1.0]"),
+                ));
+            }
+        }
+        if self.speed.is_some_and(|speed| speed <= 0.0) {
+            return Err(TtsError::invalid("speed", "speed must be greater than 0"));
+        }
+        if self.assist_text.is_some() && self.assist_prompt.is_some() {
+            return Err(TtsError::invalid(
+                "assist_prompt",
+                "assist_prompt cannot be combined with assist_text",
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Command-line flags for every setting. Each flag can also be given as the
+// matching `SBV2_*` environment variable; both override the config file. Not a
+// doc comment, since clap would use it as the program description.
+#[derive(clap::Args, Debug, Default)]
+pub struct SettingsArgs {
+    /// TOML or YAML configuration file
+    #[arg(long = "config-file", env = "SBV2_CONFIG_FILE")]
+    pub config_file: Option<PathBuf>,
+
+    /// Print the effective configuration as TOML and exit
+    #[arg(long = "print-config")]
+    pub print_config: bool,
+
+    /// Path to Style-Bert-VITS2 ONNX model (.onnx)
+    #[arg(long, env = "SBV2_MODEL", conflicts_with = "model_dir")]
+    pub model: Option<PathBuf>,
+
+    /// Path to config.json for the ONNX model
+    #[arg(long, env = "SBV2_CONFIG", conflicts_with = "model_dir")]
+    pub config: Option<PathBuf>,
+
+    /// Path to style_vectors.npy
+    #[arg(
+        long = "style-vectors",
+        env = "SBV2_STYLE_VECTORS",
+        conflicts_with = "model_dir"
+    )]
+    pub style_vectors: Option<PathBuf>,
+
+    /// Style-Bert-VITS2 model folder (config.json, style_vectors.npy and one
+    /// .onnx model), or a directory of such folders to serve several models
+    #[arg(long = "model-dir", env = "SBV2_MODEL_DIR")]
+    pub model_dir: Option<PathBuf>,
+
+    /// Root directory for ONNX BERT models (expects chinese-roberta-wwm-ext-large-onnx)
+    #[arg(long = "bert-root", env = "SBV2_BERT_ROOT")]
+    pub bert_root: Option<PathBuf>,
+
+    /// Address to bind the HTTP server to [default: 0.0.0.0:8080]
+    #[arg(long, env = "SBV2_LISTEN")]
+    pub listen: Option<String>,
+
+    /// Maximum number of cached synthesis responses (0 disables the cache)
+    #[arg(long = "cache-entries", env = "SBV2_CACHE_ENTRIES")]
+    pub cache_entries: Option<usize>,
+
+    /// Memory budget for cached responses in MiB [default: 256]
+    #[arg(long = "cache-max-mb", env = "SBV2_CACHE_MAX_MB")]
+    pub cache_max_mb: Option<usize>,
+
+    /// Directory to persist cached responses across restarts
+    #[arg(long = "cache-dir", env = "SBV2_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Text synthesised with every model at startup before /readyz reports
+    /// ready; may be repeated
+    #[arg(long = "warmup-text", env = "SBV2_WARMUP_TEXT", value_delimiter = '|')]
+    pub warmup_texts: Vec<String>,
+
+    /// JSON file listing API keys with their quotas and allowed models/voices.
+    /// Keys in SBV2_API_KEYS (comma-separated) are accepted as well
+    #[arg(long = "api-keys", env = "SBV2_API_KEYS_FILE")]
+    pub api_keys: Option<PathBuf>,
+
+    /// Seconds to let in-flight requests finish after SIGTERM/SIGINT [default: 30]
+    #[arg(long = "shutdown-timeout", env = "SBV2_SHUTDOWN_TIMEOUT")]
+    pub shutdown_timeout: Option<u64>,
+
+    /// Execution providers to try in order, comma-separated; the CPU is used
+    /// when none initialises [default: every provider in this build, then cpu]
+    #[arg(
+        long = "execution-provider",
+        env = "SBV2_EXECUTION_PROVIDER",
+        value_delimiter = ','
+    )]
+    pub execution_providers: Vec<Provider>,
+
+    /// Device used by the CUDA, TensorRT and ROCm providers [default: 0]
+    #[arg(long = "device-id", env = "SBV2_DEVICE_ID")]
+    pub device_id: Option<u32>,
+
+    /// Device memory limit in MiB for the CUDA, TensorRT and ROCm providers
+    #[arg(long = "gpu-memory-limit-mb", env = "SBV2_GPU_MEMORY_LIMIT_MB")]
+    pub gpu_memory_limit_mb: Option<usize>,
+
+    /// OpenVINO device type, e.g. CPU_FP32 or GPU_FP16
+    #[arg(long = "openvino-device-type", env = "SBV2_OPENVINO_DEVICE_TYPE")]
+    pub openvino_device_type: Option<String>,
+
+    /// Number of VITS sessions serving requests concurrently [default: 1]
+    #[arg(long = "vits-replicas", env = "SBV2_VITS_REPLICAS")]
+    pub vits_replicas: Option<usize>,
+
+    /// VITS model variant, loaded from NAME_fp16.onnx or NAME_int8.onnx next
+    /// to the model
+    #[arg(long = "vits-precision", env = "SBV2_VITS_PRECISION")]
+    pub vits_precision: Option<Precision>,
+
+    /// VITS graph optimisation level [default: all]
+    #[arg(long = "vits-optimization-level", env = "SBV2_VITS_OPTIMIZATION_LEVEL")]
+    pub vits_optimization_level: Option<OptimizationLevel>,
+
+    /// VITS execution mode [default: parallel]
+    #[arg(long = "vits-execution-mode", env = "SBV2_VITS_EXECUTION_MODE")]
+    pub vits_execution_mode: Option<ExecutionMode>,
+
+    /// Threads used within a VITS operator
+    #[arg(long = "vits-intra-threads", env = "SBV2_VITS_INTRA_THREADS")]
+    pub vits_intra_threads: Option<i16>,
+
+    /// Threads used across VITS operators in parallel mode
+    #[arg(long = "vits-inter-threads", env = "SBV2_VITS_INTER_THREADS")]
+    pub vits_inter_threads: Option<i16>,
+
+    /// Use the arena allocator for VITS inputs [default: false]
+    #[arg(long = "vits-memory-arena", env = "SBV2_VITS_MEMORY_ARENA")]
+    pub vits_memory_arena: Option<bool>,
+
+    /// Enable memory pattern planning for VITS [default: true]
+    #[arg(long = "vits-memory-pattern", env = "SBV2_VITS_MEMORY_PATTERN")]
+    pub vits_memory_pattern: Option<bool>,
+
+    /// Memory-map the VITS model, which must be in ORT format
+    /// [default: false]
+    #[arg(long = "vits-memory-map", env = "SBV2_VITS_MEMORY_MAP")]
+    pub vits_memory_map: Option<bool>,
+
+    /// Directory to save the optimised VITS graph in for faster startup
+    #[arg(
+        long = "vits-optimized-model-dir",
+        env = "SBV2_VITS_OPTIMIZED_MODEL_DIR"
+    )]
+    pub vits_optimized_model_dir: Option<PathBuf>,
+
+    /// Number of BERT sessions serving requests concurrently [default: 1]
+    #[arg(long = "bert-replicas", env = "SBV2_BERT_REPLICAS")]
+    pub bert_replicas: Option<usize>,
+
+    /// BERT model variant: model.onnx, model_fp16.onnx or model_int8.onnx
+    /// [default: fp16 if present, else fp32]
+    #[arg(long = "bert-precision", env = "SBV2_BERT_PRECISION")]
+    pub bert_precision: Option<Precision>,
+
+    /// BERT graph optimisation level [default: all]
+    #[arg(long = "bert-optimization-level", env = "SBV2_BERT_OPTIMIZATION_LEVEL")]
+    pub bert_optimization_level: Option<OptimizationLevel>,
+
+    /// BERT execution mode [default: parallel]
+    #[arg(long = "bert-execution-mode", env = "SBV2_BERT_EXECUTION_MODE")]
+    pub bert_execution_mode: Option<ExecutionMode>,
+
+    /// Threads used within a BERT operator
+    #[arg(long = "bert-intra-threads", env = "SBV2_BERT_INTRA_THREADS")]
+    pub bert_intra_threads: Option<i16>,
+
+    /// Threads used across BERT operators in parallel mode
+    #[arg(long = "bert-inter-threads", env = "SBV2_BERT_INTER_THREADS")]
+    pub bert_inter_threads: Option<i16>,
+
+    /// Use the arena allocator for BERT inputs [default: false]
+    #[arg(long = "bert-memory-arena", env = "SBV2_BERT_MEMORY_ARENA")]
+    pub bert_memory_arena: Option<bool>,
+
+    /// Enable memory pattern planning for BERT [default: true]
+    #[arg(long = "bert-memory-pattern", env = "SBV2_BERT_MEMORY_PATTERN")]
+    pub bert_memory_pattern: Option<bool>,
+
+    /// Memory-map the BERT model, which must be in ORT format
+    /// [default: false]
+    #[arg(long = "bert-memory-map", env = "SBV2_BERT_MEMORY_MAP")]
+    pub bert_memory_map: Option<bool>,
+
+    /// Directory to save the optimised BERT graph in for faster startup
+    #[arg(
+        long = "bert-optimized-model-dir",
+        env = "SBV2_BERT_OPTIMIZED_MODEL_DIR"
+    )]
+    pub bert_optimized_model_dir: Option<PathBuf>,
+
+    /// Release the BERT sessions after this many idle seconds and reload
+    /// them on demand (0 keeps them loaded) [default: 0]
+    #[arg(long = "bert-idle-timeout-secs", env = "SBV2_BERT_IDLE_TIMEOUT_SECS")]
+    pub bert_idle_timeout_secs: Option<u64>,
+
+    /// Unix socket of a shared `bert-sidecar` process to use instead of
+    /// loading BERT in this process
+    #[arg(long = "bert-socket", env = "SBV2_BERT_SOCKET")]
+    pub bert_socket: Option<PathBuf>,
+
+    /// Milliseconds a read or write on the BERT sidecar socket may block
+    /// before the request is rejected with 503 (0 waits indefinitely)
+    /// [default: 60000]
+    #[arg(long = "bert-socket-timeout-ms", env = "SBV2_BERT_SOCKET_TIMEOUT_MS")]
+    pub bert_socket_timeout_ms: Option<u64>,
+
+    /// Milliseconds a request waits for a free session before it is
+    /// rejected with 503 [default: 30000]
+    #[arg(long = "checkout-timeout-ms", env = "SBV2_CHECKOUT_TIMEOUT_MS")]
+    pub checkout_timeout_ms: Option<u64>,
+
+    /// Number of assist texts whose BERT features are cached (0 disables the
+    /// cache) [default: 256]
+    #[arg(long = "assist-cache-entries", env = "SBV2_ASSIST_CACHE_ENTRIES")]
+    pub assist_cache_entries: Option<usize>,
+
+    /// Directory to persist assist text features in across restarts
+    #[arg(long = "assist-cache-dir", env = "SBV2_ASSIST_CACHE_DIR")]
+    pub assist_cache_dir: Option<PathBuf>,
+
+    /// Named assist text as NAME=TEXT, precomputed at startup; may be
+    /// repeated or separated by '|'
+    #[arg(
+        long = "assist-prompt",
+        env = "SBV2_ASSIST_PROMPT",
+        value_parser = parse_assist_prompt,
+        value_delimiter = '|'
+    )]
+    pub assist_prompts: Vec<(String, String)>,
+
+    /// Number of sentences whose BERT features are cached (0 disables the
+    /// cache) [default: 256]
+    #[arg(long = "bert-cache-entries", env = "SBV2_BERT_CACHE_ENTRIES")]
+    pub bert_cache_entries: Option<usize>,
+
+    /// Memory budget for cached BERT features in MiB [default: 64]
+    #[arg(long = "bert-cache-max-mb", env = "SBV2_BERT_CACHE_MAX_MB")]
+    pub bert_cache_max_mb: Option<usize>,
+
+    /// Default style weight for requests that omit it
+    #[arg(long = "style-weight", env = "SBV2_STYLE_WEIGHT")]
+    pub style_weight: Option<f32>,
+
+    /// Default SDP ratio for requests that omit it
+    #[arg(long = "sdp-ratio", env = "SBV2_SDP_RATIO")]
+    pub sdp_ratio: Option<f32>,
+
+    /// Default noise scale for requests that omit it
+    #[arg(long, env = "SBV2_NOISE")]
+    pub noise: Option<f32>,
+
+    /// Default duration noise scale for requests that omit it
+    #[arg(long = "noise-w", env = "SBV2_NOISE_W")]
+    pub noise_w: Option<f32>,
+
+    /// Default length scale for requests that omit it
+    #[arg(long = "length-scale", env = "SBV2_LENGTH_SCALE")]
+    pub length_scale: Option<f32>,
+
+    /// Default assist text weight for requests that omit it
+    #[arg(long = "assist-weight", env = "SBV2_ASSIST_WEIGHT")]
+    pub assist_weight: Option<f32>,
+
+    /// Peak amplitude output is normalised to [default: 0.97]
+    #[arg(long = "peak-target", env = "SBV2_PEAK_TARGET")]
+    pub peak_target: Option<f32>,
+
+    /// MP3 bitrate in kbit/s [default: 192]
+    #[arg(long = "mp3-bitrate", env = "SBV2_MP3_BITRATE")]
+    pub mp3_bitrate: Option<u32>,
+
+    /// Never download BERT assets; fail if any are missing. `--offline=false`
+    /// turns off offline mode set in the config file [default: false]
+    #[arg(
+        long,
+        env = "SBV2_OFFLINE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub offline: Option<bool>,
+
+    /// Base URL of a Hugging Face mirror to download BERT assets from
+    /// [default: https://huggingface.co]
+    #[arg(long = "bert-mirror", env = "SBV2_BERT_MIRROR")]
+    pub bert_mirror: Option<String>,
+
+    /// Proxy URL for asset downloads
+    #[arg(long = "download-proxy", env = "SBV2_DOWNLOAD_PROXY")]
+    pub download_proxy: Option<String>,
+}
+
+fn parse_assist_prompt(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, text)) if !name.is_empty() => Ok((name.to_string(), text.to_string())),
+        _ => Err("expected NAME=TEXT".to_string()),
+    }
+}
+
+impl Settings {
+    /// Merges the config file named in `args` (if any) with the flags and
+    /// environment variables in `args`, then validates the result.
+    pub fn load(args: &SettingsArgs) -> Result<Self> {
+        let mut settings = match &args.config_file {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        settings.apply(args);
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let buf = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("toml") => {
+                toml::from_str(&buf).with_context(|| format!("failed to parse {}", path.display()))
+            }
+            Some("yaml" | "yml") => serde_yaml::from_str(&buf)
+                .with_context(|| format!("failed to parse {}", path.display())),
+            _ => bail!(
+                "config file {} must end in .toml, .yaml or .yml",
+                path.display()
+            ),
+        }
+    }
+
+    fn apply(&mut self, args: &SettingsArgs) {
+        fn set<T: Clone>(target: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *target = value.clone();
+            }
+        }
+        fn set_opt<T: Clone>(target: &mut Option<T>, value: &Option<T>) {
+            if value.is_some() {
+                *target = value.clone();
+            }
+        }
+
+        let model = &mut self.model;
+        // A model folder and explicit files are alternatives; the layer that
+        // names one replaces the other.
+        if args.model_dir.is_some() {
+            model.model = None;
+            model.config = None;
+            model.style_vectors = None;
+        }
+        if args.model.is_some() || args.config.is_some() || args.style_vectors.is_some() {
+            model.model_dir = None;
+        }
+        set_opt(&mut model.model, &args.model);
+        set_opt(&mut model.config, &args.config);
+        set_opt(&mut model.style_vectors, &args.style_vectors);
+        set_opt(&mut model.model_dir, &args.model_dir);
+        set_opt(&mut model.bert_root, &args.bert_root);
+        set(&mut model.style_weight, &args.style_weight);
+        set(&mut model.sdp_ratio, &args.sdp_ratio);
+        set(&mut model.noise, &args.noise);
+        set(&mut model.noise_w, &args.noise_w);
+        set(&mut model.length_scale, &args.length_scale);
+        set(&mut model.assist_weight, &args.assist_weight);
+
+        let server = &mut self.server;
+        set(&mut server.listen, &args.listen);
+        set(&mut server.shutdown_timeout, &args.shutdown_timeout);
+        if !args.warmup_texts.is_empty() {
+            server.warmup_texts = args.warmup_texts.clone();
+        }
+        set_opt(&mut server.api_keys, &args.api_keys);
+        set(&mut server.cache_entries, &args.cache_entries);
+        set(&mut server.cache_max_mb, &args.cache_max_mb);
+        set_opt(&mut server.cache_dir, &args.cache_dir);
+
+        let runtime = &mut self.runtime;
+        if !args.execution_providers.is_empty() {
+            runtime.execution_providers = args.execution_providers.clone();
+        }
+        for device in [&mut runtime.cuda, &mut runtime.tensorrt, &mut runtime.rocm] {
+            set(&mut device.device_id, &args.device_id);
+            set_opt(&mut device.memory_limit_mb, &args.gpu_memory_limit_mb);
+        }
+        set_opt(
+            &mut runtime.openvino.device_type,
+            &args.openvino_device_type,
+        );
+        set(&mut runtime.vits.replicas, &args.vits_replicas);
+        set_opt(&mut runtime.vits.precision, &args.vits_precision);
+        set(
+            &mut runtime.vits.optimization_level,
+            &args.vits_optimization_level,
+        );
+        set(&mut runtime.vits.execution_mode, &args.vits_execution_mode);
+        set_opt(&mut runtime.vits.intra_threads, &args.vits_intra_threads);
+        set_opt(&mut runtime.vits.inter_threads, &args.vits_inter_threads);
+        set(&mut runtime.vits.memory_arena, &args.vits_memory_arena);
+        set(&mut runtime.vits.memory_pattern, &args.vits_memory_pattern);
+        set(&mut runtime.vits.memory_map, &args.vits_memory_map);
+        set_opt(
+            &mut runtime.vits.optimized_model_dir,
+            &args.vits_optimized_model_dir,
+        );
+        set(&mut runtime.bert.replicas, &args.bert_replicas);
+        set_opt(&mut runtime.bert.precision, &args.bert_precision);
+        set(
+            &mut runtime.bert.optimization_level,
+            &args.bert_optimization_level,
+        );
+        set(&mut runtime.bert.execution_mode, &args.bert_execution_mode);
+        set_opt(&mut runtime.bert.intra_threads, &args.bert_intra_threads);
+        set_opt(&mut runtime.bert.inter_threads, &args.bert_inter_threads);
+        set(&mut runtime.bert.memory_arena, &args.bert_memory_arena);
+        set(&mut runtime.bert.memory_pattern, &args.bert_memory_pattern);
+        set(&mut runtime.bert.memory_map, &args.bert_memory_map);
+        set_opt(
+            &mut runtime.bert.optimized_model_dir,
+            &args.bert_optimized_model_dir,
+        );
+        set(
+            &mut runtime.assist_cache_entries,
+            &args.assist_cache_entries,
+        );
+        set_opt(&mut runtime.assist_cache_dir, &args.assist_cache_dir);
+        runtime
+            .assist_prompts
+            .extend(args.assist_prompts.iter().cloned());
+        set(&mut runtime.bert_cache_entries, &args.bert_cache_entries);
+        set(&mut runtime.bert_cache_max_mb, &args.bert_cache_max_mb);
+        set(&mut runtime.checkout_timeout_ms, &args.checkout_timeout_ms);
+        set(
+            &mut runtime.bert_idle_timeout_secs,
+            &args.bert_idle_timeout_secs,
+        );
+        set_opt(&mut runtime.bert_socket, &args.bert_socket);
+        set(
+            &mut runtime.bert_socket_timeout_ms,
+            &args.bert_socket_timeout_ms,
+        );
+
+        set(&mut self.audio.peak_target, &args.peak_target);
+        set(&mut self.audio.mp3_bitrate, &args.mp3_bitrate);
+
+        let assets = &mut self.assets;
+        set(&mut assets.offline, &args.offline);
+        set(&mut assets.mirror, &args.bert_mirror);
+        set_opt(&mut assets.proxy, &args.download_proxy);
+    }
+
+    fn validate(&self) -> Result<()> {
+        let model = &self.model;
+        if model.model_dir.is_some()
+            && (model.model.is_some() || model.config.is_some() || model.style_vectors.is_some())
+        {
+            bail!(
+                "model.model_dir conflicts with model.model, model.config and model.style_vectors"
+            );
+        }
+        if !(self.audio.peak_target > 0.0 && self.audio.peak_target <= 1.0) {
+            bail!("audio.peak_target must be within (0.0, 1.0]");
+        }
+        if self.audio.mp3_bitrate == 0 {
+            bail!("audio.mp3_bitrate must be greater than 0");
+        }
+        if !(model.length_scale > 0.0 && model.length_scale.is_finite()) {
+            bail!("model.length_scale must be positive and finite");
+        }
+        for (name, value) in [
+            ("model.noise", model.noise),
+            ("model.noise_w", model.noise_w),
+        ] {
+            if !(value >= 0.0 && value.is_finite()) {
+                bail!("{name} must be non-negative and finite");
+            }
+        }
+        for (name, value) in [
+            ("model.style_weight", model.style_weight),
+            ("model.sdp_ratio", model.sdp_ratio),
+            ("model.assist_weight", model.assist_weight),
+        ] {
+            if !(0.0..=1.0).contains(&value) {
+                bail!("{name} must be within [0.0, 1.0]");
+            }
+        }
+        for (file, digest) in &self.assets.sha256 {
+            if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+                bail!("assets.sha256.\"{file}\" is not a hex SHA-256 digest");
+            }
+        }
+        for (name, text) in &self.runtime.assist_prompts {
+            if text.trim().is_empty() {
+                bail!("runtime.assist_prompts.{name} must not be empty");
+            }
+        }
+        for (name, preset) in &self.presets {
+            preset
+                .validate()
+                .with_context(|| format!("invalid preset '{name}'"))?;
+        }
+        for (name, session) in [("vits", &self.runtime.vits), ("bert", &self.runtime.bert)] {
+            if session.replicas == 0 {
+                bail!("runtime.{name}.replicas must be at least 1");
+            }
+            if session.intra_threads.is_some_and(|threads| threads < 0) {
+                bail!("runtime.{name}.intra_threads must not be negative");
+            }
+            if session.inter_threads.is_some_and(|threads| threads < 0) {
+                bail!("runtime.{name}.inter_threads must not be negative");
+            }
+            if session.memory_map && session.optimized_model_dir.is_some() {
+                bail!(
+                    "runtime.{name}.memory_map needs an ORT-format model and cannot load the \
+                     ONNX graph saved in runtime.{name}.optimized_model_dir"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the settings as TOML for `--print-config`.
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string_pretty(self).context("failed to serialise configuration")
+    }
+
+    pub fn synthesis_defaults(&self) -> SynthesisDefaults {
+        SynthesisDefaults {
+            style_weight: self.model.style_weight,
+            sdp_ratio: self.model.sdp_ratio,
+            noise: self.model.noise,
+            noise_w: self.model.noise_w,
+            length_scale: self.model.length_scale,
+            assist_weight: self.model.assist_weight,
+            peak_target: self.audio.peak_target,
+        }
+    }
+}
+
+/// Writes `f32` values as their shortest decimal form, so `0.2` is not
+/// printed as `0.20000000298023224`.
+fn shortest_f32<S: serde::Serializer>(value: &f32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(value.to_string().parse().unwrap_or(f64::from(*value)))
+}
+
+fn shortest_opt_f32<S: serde::Serializer>(
+    value: &Option<f32>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => shortest_f32(value, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn cli_overrides_file_which_overrides_defaults() {
+        let file: Settings = toml::from_str(
+            r#"
+            [server]
+            listen = "127.0.0.1:9000"
+            cache_entries = 64
+
+            [model]
+            model_dir = "/models"
+            noise = 0.3
+
+            [audio]
+            mp3_bitrate = 128
+
+            [presets.narrator]
+            voice = "xiaoming"
+            speed = 0.9
+            assist_prompt = "calm"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.server.cache_max_mb, 256);
+
+        let mut settings = file;
+        settings.apply(&SettingsArgs {
+            listen: Some("0.0.0.0:9001".into()),
+            model: Some("a.onnx".into()),
+            config: Some("config.json".into()),
+            style_vectors: Some("style_vectors.npy".into()),
+            ..Default::default()
+        });
+        settings.validate().unwrap();
+        assert_eq!(settings.server.listen, "0.0.0.0:9001");
+        assert_eq!(settings.server.cache_entries, 64);
+        assert_eq!(settings.model.noise, 0.3);
+        assert_eq!(settings.model.model_dir, None);
+        assert_eq!(settings.audio.mp3_bitrate, 128);
+
+        let printed: Settings = toml::from_str(&settings.to_toml().unwrap()).unwrap();
+        assert_eq!(printed.server.listen, settings.server.listen);
+        assert_eq!(printed.presets, settings.presets);
+        assert_eq!(printed.presets["narrator"].speed, Some(0.9));
+    }
+
+    #[test]
+    fn offline_can_be_switched_off_and_prompts_split() {
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            args: SettingsArgs,
+        }
+        let parse = |argv: &[&str]| {
+            Cli::try_parse_from(iter::once("sbv2").chain(argv.iter().copied()))
+                .unwrap()
+                .args
+        };
+
+        let mut settings: Settings = toml::from_str("[assets]\noffline = true").unwrap();
+        settings.apply(&parse(&[]));
+        assert!(settings.assets.offline);
+        settings.apply(&parse(&["--offline=false"]));
+        assert!(!settings.assets.offline);
+        settings.apply(&parse(&["--offline"]));
+        assert!(settings.assets.offline);
+
+        let args = parse(&["--assist-prompt", "calm=平静|happy=开心"]);
+        assert_eq!(
+            args.assist_prompts,
+            vec![
+                ("calm".to_string(), "平静".to_string()),
+                ("happy".to_string(), "开心".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_bad_values() {
+        assert!(toml::from_str::<Settings>("[server]\nlisen = \"x\"").is_err());
+        let yaml: Settings = serde_yaml::from_str("audio:\n  peak_target: 1.5\n").unwrap();
+        assert!(yaml.validate().is_err());
+        let preset: Settings = toml::from_str("[presets.fast]\nspeed = 0").unwrap();
+        assert!(preset.validate().is_err());
+        for value in [
+            "noise = -0.1",
+            "noise_w = inf",
+            "sdp_ratio = nan",
+            "length_scale = nan",
+            "length_scale = inf",
+        ] {
+            let model: Settings = toml::from_str(&format!("[model]\n{value}")).unwrap();
+            assert!(model.validate().is_err(), "{value} was accepted");
+        }
+        for value in [
+            "speed = nan",
+            "speed = inf",
+            "noise = inf",
+            "noise_w = -inf",
+        ] {
+            let preset: Settings = toml::from_str(&format!("[presets.odd]\n{value}")).unwrap();
+            assert!(preset.validate().is_err(), "{value} was accepted");
+        }
+    }
+}
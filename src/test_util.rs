@@ -0,0 +1,37 @@
+//! Helpers shared by the unit tests.
+
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// An empty directory under the system temp dir, removed with everything in
+/// it when dropped, including when the test panics.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates `sbv2-<name>-<pid>`, clearing what an earlier, aborted run
+    /// left there. `name` must be unique among the tests.
+    pub(crate) fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("sbv2-{name}-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("failed to create test directory");
+        Self(dir)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,214 @@
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::nlp::{PAD, PUNCTUATIONS};
+
+const BLANK_WEIGHT: f32 = 0.3;
+const PUNCTUATION_WEIGHT: f32 = 2.0;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PhoneTimestamp {
+    pub phone: String,
+    pub start: f32,
+    pub end: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CharTimestamp {
+    pub text: String,
+    pub start: f32,
+    pub end: f32,
+    pub phones: Vec<PhoneTimestamp>,
+}
+
+/// Time alignment of one synthesised utterance. `phones` covers the whole phone
+/// sequence fed to the model, including blanks and the padding at both ends.
+#[derive(Debug, Clone, Default)]
+pub struct Alignment {
+    pub characters: Vec<CharTimestamp>,
+    pub phones: Vec<PhoneTimestamp>,
+}
+
+impl Alignment {
+    /// Builds an alignment from per-phone durations in samples. `word2ph` must
+    /// hold one leading and one trailing entry around the characters of `text`.
+    pub fn build(
+        text: &str,
+        phones: &[String],
+        word2ph: &[usize],
+        durations: &[f32],
+        sample_rate: u32,
+    ) -> Self {
+        let rate = sample_rate.max(1) as f32;
+        let mut cursor = 0.0_f32;
+        let phones: Vec<PhoneTimestamp> = phones
+            .iter()
+            .zip(durations)
+            .map(|(phone, &duration)| {
+                let start = cursor;
+                cursor += duration.max(0.0);
+                PhoneTimestamp {
+                    phone: phone.clone(),
+                    start: start / rate,
+                    end: cursor / rate,
+                }
+            })
+            .collect();
+
+        let mut characters = Vec::new();
+        let mut offset = word2ph.first().copied().unwrap_or(0);
+        for (ch, &count) in text.chars().zip(word2ph.iter().skip(1)) {
+            let range = offset.min(phones.len())..(offset + count).min(phones.len());
+            offset += count;
+            if range.is_empty() {
+                continue;
+            }
+            let spoken = &phones[range];
+            characters.push(CharTimestamp {
+                text: ch.to_string(),
+                start: spoken[0].start,
+                end: spoken[spoken.len() - 1].end,
+                phones: spoken
+                    .iter()
+                    .filter(|phone| phone.phone != PAD)
+                    .cloned()
+                    .collect(),
+            });
+        }
+
+        Self { characters, phones }
+    }
+
+    pub fn to_webvtt(&self) -> String {
+        let mut out = String::from("WEBVTT\n\n");
+        for (idx, (start, end, text)) in self.cues().into_iter().enumerate() {
+            let _ = write!(
+                out,
+                "{}\n{} --> {}\n{}\n\n",
+                idx + 1,
+                format_time(start, '.'),
+                format_time(end, '.'),
+                text
+            );
+        }
+        out
+    }
+
+    pub fn to_srt(&self) -> String {
+        let mut out = String::new();
+        for (idx, (start, end, text)) in self.cues().into_iter().enumerate() {
+            let _ = write!(
+                out,
+                "{}\n{} --> {}\n{}\n\n",
+                idx + 1,
+                format_time(start, ','),
+                format_time(end, ','),
+                text
+            );
+        }
+        out
+    }
+
+    /// Groups characters into subtitle cues that end at sentence punctuation.
+    fn cues(&self) -> Vec<(f32, f32, String)> {
+        let mut cues = Vec::new();
+        let mut current: Option<(f32, f32, String)> = None;
+        for character in &self.characters {
+            let cue =
+                current.get_or_insert_with(|| (character.start, character.end, String::new()));
+            cue.1 = character.end;
+            cue.2.push_str(&character.text);
+            if PUNCTUATIONS.contains(&character.text.as_str()) {
+                cues.extend(current.take());
+            }
+        }
+        cues.extend(current);
+        cues.retain(|(_, _, text)| !text.trim().is_empty());
+        cues
+    }
+}
+
+/// Distributes `total_samples` over `phones` when the model does not report
+/// durations: blanks are short, punctuation stands in for a pause.
+pub fn estimate_durations(phones: &[String], total_samples: usize) -> Vec<f32> {
+    let weights: Vec<f32> = phones
+        .iter()
+        .map(|phone| {
+            if phone == PAD {
+                BLANK_WEIGHT
+            } else if PUNCTUATIONS.contains(&phone.as_str()) {
+                PUNCTUATION_WEIGHT
+            } else {
+                1.0
+            }
+        })
+        .collect();
+    scale_durations(&weights, total_samples)
+}
+
+/// Rescales raw durations so they sum to `total_samples`.
+pub fn scale_durations(durations: &[f32], total_samples: usize) -> Vec<f32> {
+    let sum: f32 = durations.iter().map(|d| d.max(0.0)).sum();
+    if sum <= 0.0 {
+        return vec![0.0; durations.len()];
+    }
+    let scale = total_samples as f32 / sum;
+    durations.iter().map(|d| d.max(0.0) * scale).collect()
+}
+
+fn format_time(seconds: f32, separator: char) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        millis / 3_600_000,
+        (millis / 60_000) % 60,
+        (millis / 1000) % 60,
+        separator,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_alignment() -> Alignment {
+        let phones: Vec<String> = ["_", "n", "i", "h", "ao", ",", "_"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let durations = vec![100.0; phones.len()];
+        Alignment::build("你好,", &phones, &[1, 2, 2, 1, 1], &durations, 1000)
+    }
+
+    #[test]
+    fn build_maps_characters_to_phone_spans() {
+        let alignment = sample_alignment();
+        assert_eq!(alignment.phones.len(), 7);
+        assert_eq!(alignment.characters.len(), 3);
+        let first = &alignment.characters[0];
+        assert_eq!(first.text, "你");
+        assert!((first.start - 0.1).abs() < 1e-6);
+        assert!((first.end - 0.3).abs() < 1e-6);
+        assert_eq!(first.phones.len(), 2);
+    }
+
+    #[test]
+    fn estimate_durations_fills_total() {
+        let phones: Vec<String> = ["_", "n", "i", "_"].iter().map(|s| s.to_string()).collect();
+        let durations = estimate_durations(&phones, 1000);
+        let total: f32 = durations.iter().sum();
+        assert!((total - 1000.0).abs() < 1e-3);
+        assert!(durations[0] < durations[1]);
+    }
+
+    #[test]
+    fn subtitles_split_on_punctuation() {
+        let alignment = sample_alignment();
+        let vtt = alignment.to_webvtt();
+        assert!(vtt.starts_with("WEBVTT\n\n1\n00:00:00.100 --> 00:00:00.600\n你好,\n"));
+        let srt = alignment.to_srt();
+        assert!(srt.starts_with("1\n00:00:00,100 --> 00:00:00,600\n你好,\n"));
+    }
+}